@@ -0,0 +1,174 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use libp2p::{Multiaddr, PeerId};
+use log::warn;
+
+use crate::constants::*;
+
+// PeerRecord is what the node remembers of a peer of its network
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerRecord {
+    // the addresses the peer listens on, as it told us
+    pub addrs : Vec<Multiaddr>,
+    // last time we heard from it, seconds since UNIX epoch
+    pub last_seen : u64,
+    // connections that got through the identify exchange
+    pub successes : u32,
+    // dials that failed
+    pub failures : u32,
+}
+
+// AddrBook keeps the peers met so far, it is saved in the data directory
+// so a restarted node reconnects to them rather than only to its bootstrap
+// nodes
+pub struct AddrBook {
+    peers : HashMap<PeerId, PeerRecord>,
+    max_peers : usize,
+    expiry : u64,
+}
+
+// the saved form of a record : peer id, addresses, last seen, successes, failures
+type SavedPeer = (String, Vec<String>, u64, u32, u32);
+
+impl AddrBook {
+    pub fn new() -> AddrBook {
+        AddrBook::with_limits(ADDRBOOK_MAX_PEERS, ADDRBOOK_EXPIRY_SECS)
+    }
+
+    pub fn with_limits(max_peers : usize, expiry : u64) -> AddrBook {
+        AddrBook { peers : HashMap::new(), max_peers, expiry }
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn get(&self, peer_id : &PeerId) -> Option<&PeerRecord> {
+        self.peers.get(peer_id)
+    }
+
+    // Seen records that the peer answered at now and listens on addrs
+    pub fn seen(&mut self, peer_id : &PeerId, addrs : Vec<Multiaddr>, now : u64) -> &mut PeerRecord {
+        let record = self.peers.entry(*peer_id).or_insert(PeerRecord { addrs : Vec::new(), last_seen : 0, successes : 0, failures : 0 });
+        if !addrs.is_empty() {
+            record.addrs = addrs;
+        }
+        record.last_seen = now;
+        record
+    }
+
+    // Connected counts a new connection to the peer
+    pub fn connected(&mut self, peer_id : &PeerId, addrs : Vec<Multiaddr>, now : u64) {
+        self.seen(peer_id, addrs, now).successes += 1;
+    }
+
+    // Remove forgets an incompatible peer
+    pub fn remove(&mut self, peer_id : &PeerId) {
+        self.peers.remove(peer_id);
+    }
+
+    // Failed counts a failed dial, only the known peers are tracked
+    pub fn failed(&mut self, peer_id : &PeerId) {
+        if let Some(record) = self.peers.get_mut(peer_id) {
+            record.failures += 1;
+        }
+    }
+
+    // Best returns up to n peers to dial, the ones that failed more than
+    // they succeeded come last, the most recently seen first otherwise
+    pub fn best(&self, n : usize) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        let mut records : Vec<(&PeerId, &PeerRecord)> = self.peers.iter().collect();
+        records.sort_by_key(|(peer_id, r)| (r.failures > r.successes, Reverse(r.last_seen), peer_id.to_bytes()));
+        records.into_iter().take(n).map(|(peer_id, r)| (*peer_id, r.addrs.clone())).collect()
+    }
+
+    // Prune forgets the peers not seen for the expiry time, then the worst
+    // ones past max_peers
+    pub fn prune(&mut self, now : u64) {
+        let expiry = self.expiry;
+        self.peers.retain(|_, r| now.saturating_sub(r.last_seen) < expiry);
+        if self.peers.len() > self.max_peers {
+            let keep : Vec<PeerId> = self.best(self.max_peers).into_iter().map(|(peer_id, _)| peer_id).collect();
+            self.peers.retain(|peer_id, _| keep.contains(peer_id));
+        }
+    }
+
+    // Save writes the book to path and returns how many peers it holds
+    pub fn save(&self, path : &str) -> Result<usize, Box<dyn std::error::Error>> {
+        let saved : Vec<SavedPeer> = self.peers.iter().map(|(peer_id, r)| (
+            peer_id.to_string(),
+            r.addrs.iter().map(|a| a.to_string()).collect(),
+            r.last_seen,
+            r.successes,
+            r.failures,
+        )).collect();
+        // write then rename so a crash never leaves a truncated file behind
+        let tmp_path = format!("{}.new", path);
+        std::fs::write(&tmp_path, bincode::serialize(&saved)?)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(saved.len())
+    }
+
+    // Load reads back the peers saved at path, the expired ones are dropped
+    pub fn load(&mut self, path : &str, now : u64) -> Result<usize, Box<dyn std::error::Error>> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let saved : Vec<SavedPeer> = bincode::deserialize(&data)?;
+        for (peer_id, addrs, last_seen, successes, failures) in saved {
+            let peer_id : PeerId = match peer_id.parse() {
+                Ok(peer_id) => peer_id,
+                Err(e) => {
+                    warn!("address book drops peer {}: {}", peer_id, e);
+                    continue;
+                }
+            };
+            let addrs = addrs.iter().filter_map(|a| a.parse().ok()).collect();
+            self.peers.insert(peer_id, PeerRecord { addrs, last_seen, successes, failures });
+        }
+        self.prune(now);
+        Ok(self.peers.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_addrbook() {
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let addr : Multiaddr = "/ip4/127.0.0.1/tcp/18444".parse().unwrap();
+        let mut book = AddrBook::with_limits(2, 100);
+        book.connected(&a, vec![addr.clone()], 10);
+        book.connected(&b, Vec::new(), 20);
+        book.failed(&b);
+        book.failed(&b);
+        book.connected(&c, Vec::new(), 15);
+        book.failed(&PeerId::random());
+        assert_eq!(book.len(), 3);
+        // b failed more than it succeeded, it comes last despite being the latest
+        let best : Vec<PeerId> = book.best(3).into_iter().map(|(peer_id, _)| peer_id).collect();
+        assert_eq!(best, vec![c, a, b]);
+
+        let path = std::env::temp_dir().join(format!("addrbook-{}.dat", a)).to_string_lossy().to_string();
+        book.save(&path).unwrap();
+        let mut loaded = AddrBook::with_limits(2, 100);
+        // a is expired at 111
+        assert_eq!(loaded.load(&path, 111).unwrap(), 2);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.get(&c), book.get(&c));
+        assert!(loaded.get(&a).is_none());
+
+        // none is expired at 50, b is the worst of the three
+        let mut loaded = AddrBook::with_limits(2, 100);
+        book.save(&path).unwrap();
+        assert_eq!(loaded.load(&path, 50).unwrap(), 2);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.get(&a).unwrap().addrs, vec![addr]);
+        assert!(loaded.get(&b).is_none());
+    }
+}
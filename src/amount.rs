@@ -0,0 +1,109 @@
+use std::fmt;
+use std::str::FromStr;
+use failure::format_err;
+
+// number of smallest units in one coin
+pub const COIN : u64 = 100_000_000;
+// no single amount nor any sum of amounts may go above the total supply
+pub const MAX_MONEY : u64 = 21_000_000 * COIN;
+
+// Amount is a quantity of smallest units, it can not be negative and
+// every arithmetic on it is checked against overflow and MAX_MONEY
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO : Amount = Amount(0);
+
+    pub fn from_units(units : u64) -> Amount {
+        Amount(units)
+    }
+
+    pub fn from_coins(coins : u64) -> Option<Amount> {
+        coins.checked_mul(COIN).map(Amount).filter(|a| a.is_valid())
+    }
+
+    pub fn as_units(&self) -> u64 {
+        self.0
+    }
+
+    // IsValid checks the amount is within the money range
+    pub fn is_valid(&self) -> bool {
+        self.0 <= MAX_MONEY
+    }
+
+    pub fn checked_add(self, other : Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount).filter(|a| a.is_valid())
+    }
+
+    pub fn checked_sub(self, other : Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    // CheckedSum adds up amounts, None if the total overflows or leaves the money range
+    pub fn checked_sum<I : IntoIterator<Item = Amount>>(amounts : I) -> Option<Amount> {
+        let mut total = Amount::ZERO;
+        for a in amounts {
+            if !a.is_valid() {
+                return None;
+            }
+            total = total.checked_add(a)?;
+        }
+        Some(total)
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:08}", self.0 / COIN, self.0 % COIN)
+    }
+}
+
+// parse an amount expressed in coins, with up to 8 decimals ("1.5", "0.00000001")
+impl FromStr for Amount {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s : &str) -> Result<Amount, Self::Err> {
+        let (int_part, frac_part) = match s.split_once('.') {
+            Some((i, f)) => (i, f),
+            None => (s, ""),
+        };
+        let is_digits = |p : &str| p.chars().all(|c| c.is_ascii_digit());
+        if int_part.is_empty() || frac_part.len() > 8 || !is_digits(int_part) || !is_digits(frac_part) {
+            return Err(format_err!("invalid amount: {}", s).into());
+        }
+        let coins : u64 = int_part.parse()?;
+        let units : u64 = format!("{:0<8}", frac_part).parse()?;
+        Amount::from_coins(coins)
+            .and_then(|a| a.checked_add(Amount(units)))
+            .ok_or_else(|| format_err!("amount out of range: {}", s).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        let a : Amount = "1.5".parse().unwrap();
+        assert_eq!(a, Amount::from_units(150_000_000));
+        assert_eq!(a.to_string(), "1.50000000");
+        assert_eq!("0.00000001".parse::<Amount>().unwrap(), Amount::from_units(1));
+        assert!("-1".parse::<Amount>().is_err());
+        assert!("1.000000001".parse::<Amount>().is_err());
+        assert!("21000001".parse::<Amount>().is_err());
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        let max = Amount::from_units(MAX_MONEY);
+        assert!(max.checked_add(Amount::from_units(1)).is_none());
+        assert!(Amount::ZERO.checked_sub(Amount::from_units(1)).is_none());
+        assert!(Amount::checked_sum(vec![Amount::from_units(u64::MAX), Amount::from_units(1)]).is_none());
+        assert_eq!(
+            Amount::checked_sum(vec![Amount::from_units(2), Amount::from_units(3)]),
+            Some(Amount::from_units(5))
+        );
+    }
+}
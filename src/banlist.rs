@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+
+use libp2p::PeerId;
+use log::warn;
+
+use crate::constants::*;
+
+// Misbehavior is a protocol violation of a peer, each one adds its score to
+// the misbehavior score of the peer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Misbehavior {
+    // a block failing the consensus checks
+    InvalidBlock,
+    // a transaction with a bad signature or over the weight limit
+    InvalidTx,
+    // a response that does not answer the request, or no request at all
+    UnrequestedData,
+    // a block we did not ask for whose parent we do not have
+    UnrequestedOrphan,
+    // an inventory or a block locator longer than allowed
+    OversizedMessage,
+    // an undecodable or nonsensical message
+    MalformedMessage,
+}
+
+impl Misbehavior {
+    pub fn score(&self) -> u32 {
+        match self {
+            Misbehavior::InvalidBlock => BAN_SCORE_THRESHOLD,
+            Misbehavior::InvalidTx => 10,
+            Misbehavior::UnrequestedData => 20,
+            Misbehavior::UnrequestedOrphan => 10,
+            Misbehavior::OversizedMessage => 20,
+            Misbehavior::MalformedMessage => 10,
+        }
+    }
+}
+
+// BanList keeps the misbehavior score of the connected peers and the peers
+// banned until some time, the bans are saved in the data directory
+pub struct BanList {
+    scores : HashMap<PeerId, u32>,
+    // peer -> end of the ban, seconds since UNIX epoch
+    banned : HashMap<PeerId, u64>,
+}
+
+// the saved form of a ban : peer id, end of the ban
+type SavedBan = (String, u64);
+
+impl BanList {
+    pub fn new() -> BanList {
+        BanList { scores : HashMap::new(), banned : HashMap::new() }
+    }
+
+    pub fn score(&self, peer_id : &PeerId) -> u32 {
+        self.scores.get(peer_id).copied().unwrap_or(0)
+    }
+
+    // Misbehaving adds the score of what to the peer, it returns true when
+    // the peer reached BAN_SCORE_THRESHOLD and is banned for DEFAULT_BAN_SECS
+    pub fn misbehaving(&mut self, peer_id : &PeerId, what : Misbehavior, now : u64) -> bool {
+        let score = self.scores.entry(*peer_id).or_insert(0);
+        *score += what.score();
+        if *score < BAN_SCORE_THRESHOLD {
+            return false;
+        }
+        self.scores.remove(peer_id);
+        self.ban(peer_id, now + DEFAULT_BAN_SECS);
+        true
+    }
+
+    // Forget drops the score of a disconnected peer, a ban stays
+    pub fn forget(&mut self, peer_id : &PeerId) {
+        self.scores.remove(peer_id);
+    }
+
+    pub fn ban(&mut self, peer_id : &PeerId, until : u64) {
+        self.banned.insert(*peer_id, until);
+    }
+
+    pub fn unban(&mut self, peer_id : &PeerId) -> bool {
+        self.banned.remove(peer_id).is_some()
+    }
+
+    // Clear lifts every ban and returns the peers that were banned
+    pub fn clear(&mut self) -> Vec<PeerId> {
+        self.banned.drain().map(|(peer_id, _)| peer_id).collect()
+    }
+
+    pub fn is_banned(&self, peer_id : &PeerId, now : u64) -> bool {
+        self.banned.get(peer_id).is_some_and(|until| *until > now)
+    }
+
+    // Expire lifts the bans over at now and returns their peers
+    pub fn expire(&mut self, now : u64) -> Vec<PeerId> {
+        let expired : Vec<PeerId> = self.banned.iter().filter(|(_, until)| **until <= now).map(|(peer_id, _)| *peer_id).collect();
+        for peer_id in &expired {
+            self.banned.remove(peer_id);
+        }
+        expired
+    }
+
+    // Banned lists the bans, the one ending first first
+    pub fn banned(&self) -> Vec<(PeerId, u64)> {
+        let mut banned : Vec<(PeerId, u64)> = self.banned.iter().map(|(peer_id, until)| (*peer_id, *until)).collect();
+        banned.sort_by_key(|(peer_id, until)| (*until, peer_id.to_bytes()));
+        banned
+    }
+
+    // Save writes the bans to path and returns how many there are
+    pub fn save(&self, path : &str) -> Result<usize, Box<dyn std::error::Error>> {
+        let saved : Vec<SavedBan> = self.banned.iter().map(|(peer_id, until)| (peer_id.to_string(), *until)).collect();
+        // write then rename so a crash never leaves a truncated file behind
+        let tmp_path = format!("{}.new", path);
+        std::fs::write(&tmp_path, bincode::serialize(&saved)?)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(saved.len())
+    }
+
+    // Load reads back the bans saved at path, the ones over at now are dropped
+    pub fn load(&mut self, path : &str, now : u64) -> Result<usize, Box<dyn std::error::Error>> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let saved : Vec<SavedBan> = bincode::deserialize(&data)?;
+        for (peer_id, until) in saved {
+            match peer_id.parse() {
+                Ok(peer_id) if until > now => self.ban(&peer_id, until),
+                Ok(_) => {}
+                Err(e) => warn!("ban list drops peer {}: {}", peer_id, e),
+            }
+        }
+        Ok(self.banned.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_banlist() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut bans = BanList::new();
+        for _ in 0..4 {
+            assert!(!bans.misbehaving(&a, Misbehavior::UnrequestedData, 0));
+        }
+        assert_eq!(bans.score(&a), 80);
+        assert!(bans.misbehaving(&a, Misbehavior::OversizedMessage, 0));
+        assert_eq!(bans.score(&a), 0);
+        assert!(bans.is_banned(&a, DEFAULT_BAN_SECS - 1));
+        assert!(!bans.is_banned(&a, DEFAULT_BAN_SECS));
+
+        bans.misbehaving(&b, Misbehavior::InvalidTx, 0);
+        bans.forget(&b);
+        assert_eq!(bans.score(&b), 0);
+        assert!(bans.misbehaving(&b, Misbehavior::InvalidBlock, 10));
+        assert_eq!(bans.banned(), vec![(a, DEFAULT_BAN_SECS), (b, DEFAULT_BAN_SECS + 10)]);
+
+        let path = std::env::temp_dir().join(format!("banlist-{}.dat", a)).to_string_lossy().to_string();
+        bans.save(&path).unwrap();
+        let mut loaded = BanList::new();
+        assert_eq!(loaded.load(&path, DEFAULT_BAN_SECS).unwrap(), 1);
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.is_banned(&b, DEFAULT_BAN_SECS));
+
+        assert_eq!(bans.expire(DEFAULT_BAN_SECS), vec![a]);
+        assert!(bans.unban(&b));
+        assert!(bans.clear().is_empty());
+    }
+}
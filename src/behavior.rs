@@ -1,6 +1,7 @@
-use libp2p::kad::RoutingUpdate;
-use libp2p::{identify, Multiaddr, PeerId};
+use libp2p::kad::{NoKnownPeers, QueryId, RoutingUpdate};
+use libp2p::{allow_block_list, identify, Multiaddr, PeerId};
 use libp2p::swarm::NetworkBehaviour;
+use libp2p::swarm::behaviour::toggle::Toggle;
 use libp2p::kad::{
     Behaviour as KademliaBehavior,
     Event as KademliaEvent,
@@ -12,9 +13,25 @@ use libp2p::identify::{
     Event as IdentifyEvent,
 };
 
+use libp2p::gossipsub::{
+    Behaviour as GossipsubBehavior,
+    Event as GossipsubEvent,
+    IdentTopic,
+    MessageAcceptance,
+    MessageId,
+    PublishError,
+    SubscriptionError,
+};
+
+use libp2p::mdns::{
+    tokio::Behaviour as MdnsBehavior,
+    Event as MdnsEvent,
+};
+
 use libp2p::request_response::{Event as RequestResponseEvent, OutboundRequestId, ResponseChannel as RequestResponseChannel};
 use libp2p::request_response::cbor::Behaviour as RequestResponseBehavior;
 
+use crate::chainparams::params;
 use crate::message::Message;
 
 #[derive(NetworkBehaviour)]
@@ -22,12 +39,34 @@ use crate::message::Message;
 pub(crate) struct Behavior {
     identify: IdentifyBehavior,
     kad: KademliaBehavior<KademliaInMemory>,
-    rr: RequestResponseBehavior<Message, Message>
+    rr: RequestResponseBehavior<Message, Message>,
+    gossipsub: GossipsubBehavior,
+    // disabled with startnode --nomdns
+    mdns: Toggle<MdnsBehavior>,
+    // the banned peers, their connections are refused
+    blocked: allow_block_list::Behaviour<allow_block_list::BlockedPeers>
 }
 
 impl Behavior {
-    pub fn new(kad: KademliaBehavior<KademliaInMemory>, identify : IdentifyBehavior,rr : RequestResponseBehavior<Message,Message>) -> Self{
-        Self{ kad,identify,rr}
+    pub fn new(kad: KademliaBehavior<KademliaInMemory>, identify : IdentifyBehavior,rr : RequestResponseBehavior<Message,Message>,gossipsub : GossipsubBehavior,mdns : Option<MdnsBehavior>) -> Self{
+        Self{ kad,identify,rr,gossipsub,mdns: Toggle::from(mdns),blocked: Default::default()}
+    }
+
+    // Subscribe joins the transaction and block topics of the network
+    pub fn subscribe(&mut self) -> Result<(), SubscriptionError> {
+        self.gossipsub.subscribe(&IdentTopic::new(params().tx_topic))?;
+        self.gossipsub.subscribe(&IdentTopic::new(params().block_topic))?;
+        Ok(())
+    }
+
+    pub fn publish(&mut self, topic: &str, data: Vec<u8>) -> Result<MessageId, PublishError> {
+        self.gossipsub.publish(IdentTopic::new(topic), data)
+    }
+
+    // ReportValidation tells gossipsub whether to forward a received message,
+    // it holds them until then
+    pub fn report_validation(&mut self, message_id: &MessageId, source: &PeerId, acceptance: MessageAcceptance) {
+        self.gossipsub.report_message_validation_result(message_id, source, acceptance);
     }
 
     pub fn register_add_kad(&mut self,peer_id:&PeerId, addr : Multiaddr) -> RoutingUpdate {
@@ -42,6 +81,38 @@ impl Behavior {
         self.rr.send_request(peer_id, message)
     }
 
+    // SendResponse answers an inbound request, it gives the message back
+    // when the connection is gone
+    pub fn send_response(&mut self, channel: RequestResponseChannel<Message>, message: Message) -> Result<(), Message> {
+        self.rr.send_response(channel, message)
+    }
+
+    // Bootstrap looks up our own peer id, which refreshes the routing table
+    pub fn bootstrap(&mut self) -> Result<QueryId, NoKnownPeers> {
+        self.kad.bootstrap()
+    }
+
+    // FindRandomPeers looks up a random peer id, the peers met on the way
+    // come back in the query result
+    pub fn find_random_peers(&mut self) -> QueryId {
+        self.kad.get_closest_peers(PeerId::random())
+    }
+
+    pub fn routing_table_peers(&mut self) -> Vec<PeerId> {
+        self.kad.kbuckets()
+            .flat_map(|bucket| bucket.iter().map(|entry| *entry.node.key.preimage()).collect::<Vec<_>>())
+            .collect()
+    }
+
+    // BlockPeer closes the connections of a banned peer and refuses new ones
+    pub fn block_peer(&mut self, peer_id: PeerId) -> bool {
+        self.blocked.block_peer(peer_id)
+    }
+
+    pub fn unblock_peer(&mut self, peer_id: PeerId) -> bool {
+        self.blocked.unblock_peer(peer_id)
+    }
+
     pub fn set_server_mode(&mut self){
         self.kad.set_mode(Some(libp2p::kad::Mode::Server));
     }
@@ -50,7 +121,9 @@ impl Behavior {
 pub(crate) enum Event {
     Identify(IdentifyEvent),
     Kad(KademliaEvent),
-    RequestResponse(RequestResponseEvent<Message,Message>)
+    RequestResponse(RequestResponseEvent<Message,Message>),
+    Gossipsub(GossipsubEvent),
+    Mdns(MdnsEvent)
 }
 
 impl From<IdentifyEvent> for Event {
@@ -69,4 +142,23 @@ impl From<RequestResponseEvent<Message, Message>> for Event {
     fn from(value: RequestResponseEvent<Message, Message>) -> Self{
         Self::RequestResponse(value)
     }
-}
\ No newline at end of file
+}
+
+impl From<GossipsubEvent> for Event {
+    fn from(value: GossipsubEvent) -> Self{
+        Self::Gossipsub(value)
+    }
+}
+
+impl From<MdnsEvent> for Event {
+    fn from(value: MdnsEvent) -> Self{
+        Self::Mdns(value)
+    }
+}
+
+// the block list emits no events
+impl From<std::convert::Infallible> for Event {
+    fn from(value: std::convert::Infallible) -> Self{
+        match value {}
+    }
+}
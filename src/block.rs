@@ -6,9 +6,12 @@ use std::time::SystemTime;
 use merkle_cbt::merkle_tree::Merge ;
 use merkle_cbt::merkle_tree::CBMT ;
 use log::info;
+use failure::format_err;
 use crate::transaction::Transaction;
+use crate::chainparams::params;
+use crate::constants::{MAX_BLOCK_WEIGHT, MAX_FUTURE_BLOCK_TIME, WITNESS_SCALE_FACTOR};
+use crate::validation::{reject, RejectKind};
 
-const TARGET_HEXT: usize = 4; 
 #[derive(Debug,Clone,serde::Serialize,serde::Deserialize)]
 pub struct Block {
     timestamp : u128 ,// time when the block was created 
@@ -17,7 +20,7 @@ pub struct Block {
     prev_block_hash : String ,
     hash : String,
     height : i32 , 
-    nonce : i32 ,
+    nonce : u64 ,
 }
 
 
@@ -27,63 +30,115 @@ impl Block {
         self.height
     }
     
+    pub fn get_timestamp(&self) -> u128 {
+        self.timestamp
+    }
+
+    pub fn get_nonce(&self) -> u64 {
+        self.nonce
+    }
+
     pub fn get_transaction(&self) -> &Vec<Transaction>{
         &self.transactions
     }
     
-    pub fn new_block(transactions: Vec<Transaction>, prev_block_hash : String, height : i32) -> Result<Block,Box<dyn std::error::Error>> {
+    // NewTemplate builds a block that still has to go through the proof of work
+    pub fn new_template(transactions: Vec<Transaction>, prev_block_hash : String, height : i32) -> Result<Block,Box<dyn std::error::Error>> {
         let timestamp = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)?
         .as_millis();
-        let mut block = Block {
+        Ok(Block {
             timestamp ,
             transactions,
             prev_block_hash,
             hash : String::new(),
             height,
             nonce : 0,
-        };
-        block.run_proof_of_work().unwrap();
-        Ok(block)
-    }
-    
-    pub fn new_genesis_block(coinbase : Transaction) -> Block {
-        Block::new_block(vec![coinbase], String::new(),0).unwrap()
+        })
     }
     
-    fn run_proof_of_work(&mut self) -> Result<(),Box<dyn std::error::Error>>{
-        while !self.validate()?{
-            self.nonce += 1 ;
-        }
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        // The input method of the Sha256 hasher (and similar functions) 
-        // expects a slice (&[u8]) rather than a vector (Vec<u8>
-        hasher.input(&data[..]);
-        self.hash = hasher.result_str();
-        Ok(())
+    // Genesis is the first block of the selected network, rebuilt from the
+    // chain params so that every node holds the very same block
+    pub fn genesis() -> Result<Block,Box<dyn std::error::Error>> {
+        let mut block = Block {
+            timestamp : params().genesis_timestamp,
+            transactions : vec![Transaction::new_genesis_coinbase(params().genesis_coinbase_data)?],
+            prev_block_hash : String::new(),
+            hash : String::new(),
+            height : 0,
+            nonce : 0,
+        };
+        block.set_nonce(params().genesis_nonce)?;
+        Ok(block)
     }
-    
-    fn prepare_hash_data(&self) -> Result<Vec<u8>,Box<dyn std::error::Error>> {
+
+    // HeaderPrefix is the serialized header without its trailing nonce : it is
+    // computed once per template, each attempt only appends the nonce bytes
+    pub fn header_prefix(&self) -> Result<Vec<u8>,Box<dyn std::error::Error>> {
         let content  = (
             self.prev_block_hash.clone(),
             self.get_root_hash_merkle_tree_transactions()?,
             self.timestamp,
-            TARGET_HEXT,
-            self.nonce
+            params().target_hex,
         );
         let bytes  = bincode::serialize(&content)?;
         Ok(bytes)
     }
 
-    fn validate(&self) -> Result<bool,Box<dyn std::error::Error>> {
-        let data = self.prepare_hash_data()?;
+    // HashHeader hashes a header prefix followed by a nonce, the result is
+    // the one of hashing the bincode serialization of the full header tuple
+    pub fn hash_header(prefix : &[u8], nonce : u64) -> String {
         let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        let mut vec1 : Vec<u8> = vec![];
-        vec1.resize(TARGET_HEXT, '0' as u8);
-        println!("{:?}",vec1);
-        Ok(&hasher.result_str()[0..TARGET_HEXT] == String::from_utf8(vec1)?)
+        // The input method of the Sha256 hasher (and similar functions) 
+        // expects a slice (&[u8]) rather than a vector (Vec<u8>
+        hasher.input(prefix);
+        hasher.input(&nonce.to_le_bytes());
+        hasher.result_str()
+    }
+
+    // MeetsTarget checks the hash starts with the target number of zero hex digits
+    pub fn meets_target(hash : &str) -> bool {
+        let target = params().target_hex;
+        hash.len() >= target && hash[..target].bytes().all(|c| c == b'0')
+    }
+
+    // SetTimestamp changes the time of a template before the proof of work
+    pub fn set_timestamp(&mut self, timestamp : u128) {
+        self.timestamp = timestamp;
+        self.hash = String::new();
+    }
+
+    // CheckFutureTime refuses a block stamped more than MAX_FUTURE_BLOCK_TIME
+    // ahead of `adjusted_time`, the network adjusted time in seconds
+    pub fn check_future_time(&self, adjusted_time : u64) -> Result<(),Box<dyn std::error::Error>> {
+        let limit = (adjusted_time + MAX_FUTURE_BLOCK_TIME) as u128 * 1000;
+        if self.timestamp > limit {
+            return Err(reject(RejectKind::TimeTooNew, format!("block {}: timestamp {} is too far in the future",self.hash,self.timestamp)));
+        }
+        Ok(())
+    }
+
+    // SetNonce stores a found nonce and the resulting block hash
+    pub fn set_nonce(&mut self, nonce : u64) -> Result<(),Box<dyn std::error::Error>> {
+        self.nonce = nonce;
+        self.hash = Block::hash_header(&self.header_prefix()?, nonce);
+        Ok(())
+    }
+
+    // SetExtraNonce rolls the extra-nonce of the coinbase, which gives the
+    // template a new merkle root and so a fresh nonce space
+    pub fn set_extra_nonce(&mut self, extra_nonce : u64) -> Result<(),Box<dyn std::error::Error>> {
+        match self.transactions.iter_mut().find(|tx| tx.is_coinbase()) {
+            Some(coinbase) => coinbase.set_extra_nonce(extra_nonce),
+            None => Err(format_err!("block at height {} has no coinbase", self.height).into()),
+        }
+    }
+
+    // CheckProofOfWork recomputes the header hash, it must be the stored
+    // hash and meet the target
+    pub fn check_proof_of_work(&self) -> Result<bool,Box<dyn std::error::Error>> {
+        let hash = Block::hash_header(&self.header_prefix()?, self.nonce);
+        Ok(hash == self.hash && Block::meets_target(&hash))
     }
 
     pub fn get_hash(&self) -> String {
@@ -94,10 +149,35 @@ impl Block {
         self.prev_block_hash.clone()
     }
 
+    // GetSize returns the canonical serialized size of the block
+    pub fn get_size(&self) -> Result<usize,Box<dyn std::error::Error>> {
+        Ok(bincode::serialized_size(self)? as usize)
+    }
+
+    // GetWeight applies the witness discount of each transaction to the whole block
+    pub fn get_weight(&self) -> Result<usize,Box<dyn std::error::Error>> {
+        let size = self.get_size()?;
+        let witness_size : usize = self.transactions.iter().map(|tx| tx.witness_size()).sum();
+        Ok((size - witness_size) * (WITNESS_SCALE_FACTOR - 1) + size)
+    }
+
+    // CheckWeight rejects blocks heavier than MAX_BLOCK_WEIGHT
+    pub fn check_weight(&self) -> Result<(),Box<dyn std::error::Error>> {
+        let weight = self.get_weight()?;
+        if weight > MAX_BLOCK_WEIGHT {
+            return Err(reject(RejectKind::Weight, format!("block {} weight {} exceeds the limit {}",self.hash,weight,MAX_BLOCK_WEIGHT)));
+        }
+        Ok(())
+    }
+
+    // the leaves hash each transaction with its id, so that the header commits
+    // to the ids the block is indexed and spent by
     fn get_root_hash_merkle_tree_transactions(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>>{
         let mut transactions = Vec::new();
         for tx in &self.transactions {
-             transactions.push(tx.hash()?.as_bytes().to_owned());
+            let mut hasher = Sha256::new();
+            hasher.input(&bincode::serialize(tx)?);
+            transactions.push(hasher.result_str().as_bytes().to_owned());
         }
         let tree = CBMT::<Vec<u8>, MergeYX>::build_merkle_tree(&*transactions);
         Ok(tree.root()) 
@@ -1,17 +1,44 @@
-use std::{collections::HashMap, hash::Hash};
-use bincode::{deserialize, serialize};
+use std::{collections::{BTreeMap, HashMap, HashSet}, hash::Hash};
+use bincode::deserialize;
 use failure::format_err;
-use log::{info,debug};
-use crate::{block::{self, *}, transaction::Transaction, tx::{TXOutput, TXOutputs}};
+use log::{info,debug,warn};
+use crate::{block::{self, *}, transaction::Transaction, tx::{OutPoint, TXOutput, TXOutputs}};
+use crate::constants::{BLOCK_HEADER_WEIGHT_RESERVE, BLOCK_SUBSIDY, MAX_BLOCK_WEIGHT, MAX_ORPHAN_BLOCKS, MAX_ORPHAN_BYTES, MEDIAN_TIME_SPAN};
+use crate::amount::Amount;
+use crate::chainparams::params;
+use crate::validation::{reject, within, RejectKind};
 const TARGET_HEXT: usize = 4; 
-const GENESIS_COINBASE_DATA: &str =
-    "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
+// sled trees of the block database next to the blocks themselves
+const ORPHANS_TREE: &str = "orphans";
+// the orphans by parent, keyed by parent hash then hash, and by arrival,
+// keyed by a sled generated id then hash
+const ORPHAN_PARENTS_TREE: &str = "orphan_parents";
+const ORPHAN_ORDER_TREE: &str = "orphan_order";
+const INVALID_TREE: &str = "invalid";
+// the stored blocks by parent, keyed by parent hash then hash, and by
+// height, keyed by the big endian height then hash
+const CHILDREN_TREE: &str = "children";
+const HEIGHTS_TREE: &str = "heights";
+// the unspent outputs as of the block named under "tip" in the chainstate
+// tree, keyed by transaction id, and per block of the active chain the
+// outputs it spent, which put them back when the block is disconnected
+const UTXO_TREE: &str = "utxos";
+const UNDO_TREE: &str = "undo";
+const CHAINSTATE_TREE: &str = "chainstate";
 
 #[derive(Debug,Clone)]
 pub struct Blockchain {
     pub current_hash : String,
     pub db : sled::Db 
 }
+// TipChange lists the blocks that left and joined the active chain when
+// the tip moved, both from the lowest height up
+#[derive(Debug,Default)]
+pub struct TipChange {
+    pub disconnected : Vec<Block>,
+    pub connected : Vec<Block>,
+}
+
 pub struct BlockchainIter<'a>{
     current_hash : String,
     bc : &'a Blockchain
@@ -19,63 +46,115 @@ pub struct BlockchainIter<'a>{
 
 impl Blockchain {
     
-    /// NewBlockchain creates a new Blockchain db
+    /// NewBlockchain opens the Blockchain db, an empty one is initialised
+    /// with the genesis block and the rest comes from the peers
     pub fn new() -> Result<Blockchain,Box<dyn std::error::Error>> {
         info!("open blockchain !!");
-        let db = sled::open("data/blocks")?;
-        let hash = db
-            .get("LAST")?
-            .expect("Must create a new block database fisrt");
-        info!("Found block database");
-        let lasthash = String::from_utf8(hash.to_vec())?;
-        Ok(Blockchain{
-            current_hash : lasthash.clone(),
+        Blockchain::from_db(sled::open(params().data_path("blocks"))?)
+    }
+
+    // FromDb opens the chain stored in db, an empty db starts with the
+    // genesis block of the network
+    fn from_db(db : sled::Db) -> Result<Blockchain,Box<dyn std::error::Error>> {
+        let genesis = Block::genesis()?;
+        if genesis.get_hash() != params().genesis_hash || !genesis.check_proof_of_work()? {
+            return Err(format_err!(
+                "genesis block {} does not match the {} genesis hash {}",
+                genesis.get_hash(),params().name,params().genesis_hash
+            ).into());
+        }
+        let lasthash = match db.get("LAST")? {
+            Some(hash) => {
+                if !db.contains_key(genesis.get_hash())? {
+                    // written by a version with other consensus rules, it can not be migrated
+                    return Err(format_err!(
+                        "the block database does not hold the {} genesis block, remove the {} directory to sync the chain again",
+                        params().name,params().data_dir
+                    ).into());
+                }
+                info!("Found block database");
+                String::from_utf8(hash.to_vec())?
+            }
+            None => {
+                info!("initialise the block database with the {} genesis block",params().name);
+                store_block(&db, &genesis)?;
+                db.insert("LAST",genesis.get_hash().as_bytes())?;
+                //Synchronously flushes all dirty IO buffers and calls fsync.
+                db.flush()?;
+                genesis.get_hash()
+            }
+        };
+        let bc = Blockchain{
+            current_hash : lasthash,
             db
-        })
+        };
+        // written by a version without the block indexes
+        if bc.db.open_tree(HEIGHTS_TREE)?.is_empty() {
+            info!("index the blocks of the {} chain",params().name);
+            bc.index_blocks()?;
+        }
+        // a database of an older version, or a crash in the middle of a tip change
+        if bc.utxo_tip()? != bc.current_hash {
+            info!("rebuild the UTXO set of the {} chain",params().name);
+            bc.rebuild_utxos()?;
+        }
+        Ok(bc)
     }
     
-    /// CreateBlockchain creates a new blockchain DB
-    pub fn create_blockchain(address : String) -> Result<Blockchain,Box<dyn std::error::Error>>{
+    /// CreateBlockchain drops the block database and starts over from the genesis block
+    pub fn create_blockchain() -> Result<Blockchain,Box<dyn std::error::Error>>{
         info!("Creating new blockchain");
-        if let Err(e) = std::fs::remove_dir_all("data/blocks"){
+        if let Err(e) = std::fs::remove_dir_all(params().data_path("blocks")){
             info!("no blockchain db exist to be deleted")
         };
-        let db = sled::open("data/blocks")?;
-        let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA))?;
-        let genesis : Block = Block::new_genesis_block(cbtx);
-        db.insert(genesis.get_hash(), bincode::serialize(&genesis)?)?;
-        db.insert("LAST",genesis.get_hash().as_bytes())?;
-        let bc  = Blockchain {
-            current_hash : genesis.get_hash(),
-            db,
-        };
-        //Synchronously flushes all dirty IO buffers and calls fsync.
-        bc.db.flush()?;
-        Ok(bc)
+        Blockchain::new()
     }
 
-    /// MineBlock mines a new block with the provided transactions
-    pub fn mine_block(&mut self, transactions : Vec<Transaction>) -> Result<Block,Box<dyn std::error::Error>>{
-        info!("mine a new block");
+    /// NewBlockTemplate checks the transactions and builds on the current tip
+    /// a block that is ready for the proof of work
+    pub fn new_block_template(&self, transactions : Vec<Transaction>) -> Result<Block,Box<dyn std::error::Error>>{
+        let mut weight = BLOCK_HEADER_WEIGHT_RESERVE;
+        // a transaction may spend outputs of one placed before it in the block
+        let mut in_block = HashMap::new();
         for tx in &transactions{
-            if !self.verify_transaction(tx)?{
+            if !self.verify_transaction_in(tx, &in_block)?{
                 return Err(format_err!("Error: Invalid transaction").into());
             }
+            weight += tx.weight()?;
+            in_block.insert(tx.id.clone(), tx.clone());
+        }
+        if weight > MAX_BLOCK_WEIGHT {
+            return Err(format_err!("Error: block weight {} exceeds the limit {}",weight,MAX_BLOCK_WEIGHT).into());
         }
 
-        let lasthash = self.db.get("LAST")?.unwrap();
-
-        let newblock = Block::new_block(
-            transactions, 
-            String::from_utf8(lasthash.to_vec())?, 
-            self.get_best_height()?+1,
-        )?;
-        self.db.insert(newblock.get_hash(), serialize(&newblock)?)?;
-        self.db.insert("LAST", newblock.get_hash().as_bytes())?;
-        self.db.flush()?;
-        self.current_hash=newblock.get_hash();
-        Ok(newblock)
+        let lasthash = String::from_utf8(self.db.get("LAST")?.unwrap().to_vec())?;
+        let height = self.get_best_height()?+1;
+        let median_time = self.median_time_past(&lasthash)?;
+        for tx in &transactions{
+            if !tx.is_final(height, (median_time / 1000) as u64){
+                return Err(format_err!("Error: transaction {} is locked until {}",tx.id,tx.lock_time).into());
+            }
+        }
+        let mut block = Block::new_template(transactions, lasthash, height)?;
+        // a clock behind the chain still gives a valid timestamp
+        if block.get_timestamp() <= median_time {
+            block.set_timestamp(median_time + 1);
+        }
+        Ok(block)
+    }
 
+    /// MedianTimePast is the median timestamp of the block and of its
+    /// MEDIAN_TIME_SPAN - 1 ancestors, or of all of them near the genesis
+    pub fn median_time_past(&self, block_hash : &str) -> Result<u128,Box<dyn std::error::Error>> {
+        let mut times = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut hash = block_hash.to_string();
+        while times.len() < MEDIAN_TIME_SPAN && !hash.is_empty() {
+            let block = self.get_block(&hash)?;
+            times.push(block.get_timestamp());
+            hash = block.get_prev_hash();
+        }
+        times.sort();
+        Ok(times[times.len() / 2])
     }
 
     /// GetBestHeight returns the height of the latest block
@@ -162,48 +241,6 @@ impl Blockchain {
         utxos
     }*/
 
-    /// FindUTXO finds and returns all unspent transaction outputs
-    pub fn find_UTXO(&self) -> HashMap<String,TXOutputs>{
-        let mut utxos : HashMap::<String,TXOutputs> = HashMap::new();
-        let mut spend_txos : HashMap::<String,Vec<i32>> = HashMap::new(); 
-        for block in self.iter(){
-            for tx in block.get_transaction(){
-                for index in 0..tx.vout.len(){
-                    if let Some(ids) = spend_txos.get(&tx.id){
-                        if ids.contains(&(index as i32)){
-                            continue
-                        }
-                    }
-                    match utxos.get_mut(&tx.id){
-                        Some(v)=>{
-                            v.outputs.push(tx.vout[index].clone())
-                        }
-                        None => {
-                            utxos.insert(
-                                tx.id.clone(),
-                                TXOutputs{
-                                    outputs : vec![tx.vout[index].clone()]
-                                }
-                            );
-                        }
-                    }
-                }
-                if !tx.is_coinbase(){
-                    for i in &tx.vin{
-                        match spend_txos.get_mut(&i.txid){
-                            Some(v)=>{
-                                v.push(i.vout)
-                            }
-                            None => {
-                                spend_txos.insert(i.txid.clone(),vec![i.vout]);
-                            }
-                        }
-                    }
-                }
-            }
-        }
-        utxos
-    }
     /*     
     pub fn find_spendable_outputs(&self, address:&[u8],amount: i32)->(i32,HashMap<String, Vec<i32>>){
         let mut unspent_outputs : HashMap<String, Vec<i32>> = HashMap::new();
@@ -229,16 +266,51 @@ impl Blockchain {
     }
     */
     pub fn find_transaction(&self, id: &str) -> Result<Transaction,Box<dyn std::error::Error>>{
+        Ok(self.find_transaction_block(id)?.0)
+    }
+
+    /// FindTransactionBlock returns a confirmed transaction with the hash of
+    /// the active chain block holding it
+    pub fn find_transaction_block(&self, id: &str) -> Result<(Transaction,String),Box<dyn std::error::Error>>{
         for b in self.iter(){
             for tx in b.get_transaction(){
                 if tx.id == id {
-                    return Ok(tx.clone())
+                    return Ok((tx.clone(),b.get_hash()))
                 }
             }
         }
         Err(format_err!("Transaction is not found").into())
     }
+
+    /// GetBlockHash returns the hash of the active chain block at `height`
+    pub fn get_block_hash(&self, height: i32) -> Result<String,Box<dyn std::error::Error>>{
+        for b in self.iter(){
+            if b.get_height() == height {
+                return Ok(b.get_hash());
+            }
+            if b.get_height() < height {
+                break;
+            }
+        }
+        Err(format_err!("block height {} out of range",height).into())
+    }
     
+    /// AddressHistory returns the active chain transactions paying to or
+    /// spending from `pub_key_hash`, newest first, with the hash and height
+    /// of their block
+    pub fn address_history(&self, pub_key_hash: &[u8]) -> Vec<(Transaction,String,i32)>{
+        let mut history = Vec::new();
+        for b in self.iter(){
+            for tx in b.get_transaction(){
+                let spends = !tx.is_coinbase() && tx.vin.iter().any(|vin| vin.can_unlock_output_with(pub_key_hash));
+                if spends || tx.vout.iter().any(|out| out.can_be_unlock_with(pub_key_hash)) {
+                    history.push((tx.clone(),b.get_hash(),b.get_height()));
+                }
+            }
+        }
+        history
+    }
+
     /// SignTransaction signs inputs of a Transaction
     pub fn sign_transaction(&self, tx : &mut Transaction, private_key : &[u8]) -> Result<(),Box<dyn std::error::Error>> {
         let prev_TXs = self.get_prev_TXs(tx)?;
@@ -248,11 +320,39 @@ impl Blockchain {
 
     /// VerifyTransaction verifies transaction input signatures
     pub fn verify_transaction(&self,tx : &Transaction) -> Result<bool,Box<dyn std::error::Error>>{
+        self.verify_transaction_in(tx, &HashMap::new())
+    }
+
+    /// VerifyTransactionIn verifies tx when some of its inputs come from the
+    /// not yet confirmed transactions in `pending`, the others spend the
+    /// UTXO set
+    pub fn verify_transaction_in(&self,tx : &Transaction, pending : &HashMap<String,Transaction>) -> Result<bool,Box<dyn std::error::Error>>{
         if tx.is_coinbase(){
-            return Ok(true);
+            return Ok(tx.value_out().is_ok());
         }
-        let prev_TXs = self.get_prev_TXs(tx)?;
-        tx.verify(prev_TXs)
+        let utxos = self.utxo_tree()?;
+        let mut spent = Vec::new();
+        for vin in &tx.vin{
+            let out = match pending.get(&vin.txid){
+                Some(prev_tx) => usize::try_from(vin.vout).ok().and_then(|i| prev_tx.vout.get(i)).cloned(),
+                None => match utxos.get(&vin.txid)? {
+                    Some(data) => deserialize::<TXOutputs>(&data)?.outputs.remove(&vin.vout),
+                    None => None,
+                },
+            };
+            match out {
+                Some(out) => spent.push(out),
+                None => {
+                    warn!("transaction {}: input {} is missing or spent",tx.id,vin.outpoint());
+                    return Ok(false);
+                }
+            }
+        }
+        if let Err(e) = tx.check_spent_amounts(&spent){
+            warn!("{}",e);
+            return Ok(false);
+        }
+        tx.verify_spent(&spent)
     }
   
     /// GetBlockHashes returns a list of hashes of all the blocks in the chain
@@ -263,6 +363,42 @@ impl Blockchain {
         }
         list
     }
+
+    /// BlockLocator lists active chain hashes from the tip back to the
+    /// genesis block : the last ten, then twice further apart each time,
+    /// so that a peer finds the last block we have in common
+    pub fn block_locator(&self) -> Vec<String> {
+        let hashes = self.get_block_hashs();
+        let mut locator = Vec::new();
+        let (mut i, mut step) = (0, 1);
+        while i < hashes.len() {
+            locator.push(hashes[i].clone());
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            i += step;
+        }
+        if locator.last() != hashes.last() {
+            locator.extend(hashes.last().cloned());
+        }
+        locator
+    }
+
+    /// BlocksAfter returns up to `max` active chain hashes following the
+    /// first locator hash on the active chain, lowest first, the ones after
+    /// the genesis block when the locator has none
+    pub fn blocks_after(&self, locator: &[String], max: usize) -> Vec<String> {
+        let mut hashes = self.get_block_hashs();
+        hashes.reverse();
+        let heights : HashMap<&String,usize> = hashes.iter().enumerate().map(|(height, hash)| (hash, height)).collect();
+        let start = locator.iter().find_map(|hash| heights.get(hash)).map_or(1, |height| height + 1);
+        hashes.into_iter().skip(start).take(max).collect()
+    }
+
+    /// HasBlock tells whether the block is in the block tree
+    pub fn has_block(&self, block_hash: &str) -> Result<bool,Box<dyn std::error::Error>> {
+        Ok(self.db.contains_key(block_hash)?)
+    }
     /*pub fn add_block(&mut self, transactions : Vec<Transaction>) -> Result<Block,Box<dyn std::error::Error>> {
         /*
             The .into() method converts the string into 
@@ -276,23 +412,480 @@ impl Blockchain {
         Ok(new_block)
     }*/
 
-    /// AddBlock saves the block into the blockchain
-    pub fn add_block(&mut self, block: Block) -> Result<(),Box<dyn std::error::Error>> {
-        let data = bincode::serialize(&block)?;
-        if let Some(_) = self.db.get(block.get_hash())? {
-            return Ok(());
+    /// AddBlock saves the block into the blockchain. A block whose parent is
+    /// still unknown waits among the orphans until the parent arrives, so the
+    /// block tree only holds blocks that connect to the genesis block. The
+    /// tip moves to the highest valid block.
+    pub fn add_block(&mut self, block: Block) -> Result<TipChange,Box<dyn std::error::Error>> {
+        let orphans = self.db.open_tree(ORPHANS_TREE)?;
+        if self.db.contains_key(block.get_hash())? || orphans.contains_key(block.get_hash())? {
+            return Ok(TipChange::default());
+        }
+        self.check_block(&block)?;
+        if !self.db.contains_key(block.get_prev_hash())? {
+            debug!("block {} waits for its parent {}",block.get_hash(),block.get_prev_hash());
+            self.add_orphan(&block)?;
+            self.trim_orphans(MAX_ORPHAN_BLOCKS, MAX_ORPHAN_BYTES)?;
+            return Ok(TipChange::default());
+        }
+
+        self.check_block_context(&block)?;
+
+        // the block joins the tree together with the orphans it completes
+        let invalid = self.db.open_tree(INVALID_TREE)?;
+        let mut best : Option<Block> = None;
+        let mut queue = vec![block];
+        while let Some(block) = queue.pop() {
+            store_block(&self.db, &block)?;
+            if invalid.contains_key(block.get_prev_hash())? {
+                warn!("block {} builds on an invalid block",block.get_hash());
+                invalid.insert(block.get_hash(), vec![])?;
+            } else if best.as_ref().is_none_or(|b| block.get_height() > b.get_height()) {
+                best = Some(block.clone());
+            }
+            for orphan in self.take_orphans(&block.get_hash())? {
+                match self.check_block_context(&orphan) {
+                    Ok(()) => queue.push(orphan),
+                    Err(e) => warn!("drop orphan block: {}",e),
+                }
+            }
+        }
+        match best {
+            Some(best) if best.get_height() > self.get_best_height()? => self.set_tip(&best.get_hash()),
+            _ => {
+                self.db.flush()?;
+                Ok(TipChange::default())
+            }
+        }
+    }
+
+    /// IsOrphan tells whether the block waits for its parent in the orphan pool
+    pub fn is_orphan(&self, block_hash: &str) -> Result<bool,Box<dyn std::error::Error>> {
+        Ok(self.db.open_tree(ORPHANS_TREE)?.contains_key(block_hash)?)
+    }
+
+    // AddOrphan puts a block whose parent is unknown in the orphan pool
+    fn add_orphan(&self, block: &Block) -> Result<(),Box<dyn std::error::Error>> {
+        let hash = block.get_hash();
+        self.db.open_tree(ORPHANS_TREE)?.insert(&hash, bincode::serialize(block)?)?;
+        self.db.open_tree(ORPHAN_PARENTS_TREE)?.insert(format!("{}{}",block.get_prev_hash(),hash), vec![])?;
+        let mut order_key = self.db.generate_id()?.to_be_bytes().to_vec();
+        order_key.extend_from_slice(hash.as_bytes());
+        self.db.open_tree(ORPHAN_ORDER_TREE)?.insert(order_key, vec![])?;
+        Ok(())
+    }
+
+    // RemoveOrphan takes a block out of the orphan pool
+    fn remove_orphan(&self, block_hash: &str) -> Result<Option<Block>,Box<dyn std::error::Error>> {
+        let data = match self.db.open_tree(ORPHANS_TREE)?.remove(block_hash)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let block : Block = deserialize(&data)?;
+        self.db.open_tree(ORPHAN_PARENTS_TREE)?.remove(format!("{}{}",block.get_prev_hash(),block_hash))?;
+        let order = self.db.open_tree(ORPHAN_ORDER_TREE)?;
+        for key in order.iter().keys() {
+            let key = key?;
+            if &key[8..] == block_hash.as_bytes() {
+                order.remove(key)?;
+                break;
+            }
+        }
+        Ok(Some(block))
+    }
+
+    // TakeOrphans removes the orphans waiting for `parent_hash` from the pool
+    // and returns them
+    fn take_orphans(&self, parent_hash: &str) -> Result<Vec<Block>,Box<dyn std::error::Error>> {
+        let mut children = Vec::new();
+        for key in self.db.open_tree(ORPHAN_PARENTS_TREE)?.scan_prefix(parent_hash).keys() {
+            let key = key?;
+            children.push(String::from_utf8(key[parent_hash.len()..].to_vec())?);
+        }
+        let mut orphans = Vec::new();
+        for hash in children {
+            orphans.extend(self.remove_orphan(&hash)?);
+        }
+        Ok(orphans)
+    }
+
+    // TrimOrphans drops the oldest orphans until the pool holds at most
+    // max_blocks blocks of max_bytes together
+    fn trim_orphans(&self, max_blocks: usize, max_bytes: usize) -> Result<(),Box<dyn std::error::Error>> {
+        let orphans = self.db.open_tree(ORPHANS_TREE)?;
+        let order = self.db.open_tree(ORPHAN_ORDER_TREE)?;
+        loop {
+            let mut bytes = 0;
+            for data in orphans.iter().values() {
+                bytes += data?.len();
+            }
+            if orphans.len() <= max_blocks && bytes <= max_bytes {
+                return Ok(());
+            }
+            let oldest = match order.first()? {
+                Some((key, _)) => String::from_utf8(key[8..].to_vec())?,
+                None => return Ok(()),
+            };
+            debug!("orphan pool full, drop block {}",oldest);
+            self.remove_orphan(&oldest)?;
+        }
+    }
+
+    /// InvalidateBlock marks a block and all its descendants invalid, when
+    /// the active chain holds it the tip goes back to the best valid block
+    pub fn invalidate_block(&mut self, block_hash: &str) -> Result<TipChange,Box<dyn std::error::Error>> {
+        if !self.db.contains_key(block_hash)? {
+            return Err(format_err!("block {} not found",block_hash).into());
+        }
+        if block_hash == params().genesis_hash {
+            return Err(format_err!("the genesis block can not be invalidated").into());
+        }
+        let invalid = self.db.open_tree(INVALID_TREE)?;
+        for hash in self.descendants(block_hash)? {
+            invalid.insert(hash.as_bytes(), vec![])?;
+        }
+        info!("block {} marked invalid",block_hash);
+        self.activate_best_chain()
+    }
+
+    /// ReconsiderBlock removes the invalid mark of a block, of its ancestors
+    /// and of its descendants, then moves to the best chain again
+    pub fn reconsider_block(&mut self, block_hash: &str) -> Result<TipChange,Box<dyn std::error::Error>> {
+        let block = self.get_block(block_hash)?;
+        let invalid = self.db.open_tree(INVALID_TREE)?;
+        for hash in self.descendants(block_hash)? {
+            invalid.remove(hash.as_bytes())?;
+        }
+        let mut prev_hash = block.get_prev_hash();
+        while !prev_hash.is_empty() {
+            invalid.remove(prev_hash.as_bytes())?;
+            prev_hash = self.get_block(&prev_hash)?.get_prev_hash();
+        }
+        info!("block {} reconsidered",block_hash);
+        self.activate_best_chain()
+    }
+
+    // ActivateBestChain moves the tip to the highest valid block, the
+    // current tip wins between blocks of the same height
+    fn activate_best_chain(&mut self) -> Result<TipChange,Box<dyn std::error::Error>> {
+        let invalid = self.db.open_tree(INVALID_TREE)?;
+        let mut best : Option<(Vec<u8>,String)> = None;
+        // from the highest block down, until the height of the best valid one is done
+        for key in self.db.open_tree(HEIGHTS_TREE)?.iter().keys().rev() {
+            let key = key?;
+            let (height, hash) = key.split_at(4);
+            if best.as_ref().is_some_and(|(h, _)| h.as_slice() != height) {
+                break;
+            }
+            let hash = String::from_utf8(hash.to_vec())?;
+            if invalid.contains_key(&hash)? {
+                continue;
+            }
+            if best.is_none() || hash == self.current_hash {
+                best = Some((height.to_vec(), hash));
+            }
+        }
+        match best {
+            Some((_, best)) if best != self.current_hash => self.set_tip(&best),
+            _ => Ok(TipChange::default()),
+        }
+    }
+
+    // SetTip makes `block_hash` the tip and returns the blocks between the
+    // old tip and the new one, on both sides of their common ancestor
+    fn set_tip(&mut self, block_hash: &str) -> Result<TipChange,Box<dyn std::error::Error>> {
+        let mut change = TipChange::default();
+        let mut old = self.get_block(&self.current_hash)?;
+        let mut new = self.get_block(block_hash)?;
+        while old.get_hash() != new.get_hash() {
+            if old.get_height() >= new.get_height() {
+                let prev_hash = old.get_prev_hash();
+                change.disconnected.push(old);
+                old = self.get_block(&prev_hash)?;
+            } else {
+                let prev_hash = new.get_prev_hash();
+                change.connected.push(new);
+                new = self.get_block(&prev_hash)?;
+            }
         }
-        self.db.insert(block.get_hash(), data)?;
+        change.disconnected.reverse();
+        change.connected.reverse();
 
-        let lastheight = self.get_best_height()?;
-        if block.get_height() > lastheight {
-            self.db.insert("LAST", block.get_hash().as_bytes())?;
-            self.current_hash = block.get_hash();
-            self.db.flush()?;
+        // the UTXO set is marked out of date until the last block is applied
+        self.set_utxo_tip("")?;
+        for block in change.disconnected.iter().rev() {
+            self.disconnect_block(block)?;
+        }
+        for block in &change.connected {
+            self.connect_block(block)?;
+        }
+        self.set_utxo_tip(block_hash)?;
+        self.db.insert("LAST", block_hash.as_bytes())?;
+        self.current_hash = block_hash.to_string();
+        self.db.flush()?;
+        Ok(change)
+    }
+
+    /// UtxoTree is the UTXO set of the active chain : transaction id to its
+    /// unspent outputs
+    pub fn utxo_tree(&self) -> Result<sled::Tree,Box<dyn std::error::Error>> {
+        Ok(self.db.open_tree(UTXO_TREE)?)
+    }
+
+    /// RebuildUtxos computes the UTXO set again from the blocks of the active chain
+    pub fn rebuild_utxos(&self) -> Result<(),Box<dyn std::error::Error>> {
+        self.set_utxo_tip("")?;
+        self.utxo_tree()?.clear()?;
+        self.db.open_tree(UNDO_TREE)?.clear()?;
+        let mut hashes = self.get_block_hashs();
+        hashes.reverse();
+        for hash in hashes {
+            self.connect_block(&self.get_block(&hash)?)?;
+        }
+        self.set_utxo_tip(&self.current_hash)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn utxo_tip(&self) -> Result<String,Box<dyn std::error::Error>> {
+        match self.db.open_tree(CHAINSTATE_TREE)?.get("tip")? {
+            Some(hash) => Ok(String::from_utf8(hash.to_vec())?),
+            None => Ok(String::new()),
+        }
+    }
+
+    fn set_utxo_tip(&self, block_hash: &str) -> Result<(),Box<dyn std::error::Error>> {
+        self.db.open_tree(CHAINSTATE_TREE)?.insert("tip", block_hash.as_bytes())?;
+        Ok(())
+    }
+
+    // ConnectBlock spends the outputs the block spends in the UTXO set, adds
+    // the ones it creates and keeps the spent ones as its undo data
+    fn connect_block(&self, block: &Block) -> Result<(),Box<dyn std::error::Error>> {
+        let utxos = self.utxo_tree()?;
+        let mut undo : Vec<(OutPoint,TXOutput)> = Vec::new();
+        for tx in block.get_transaction(){
+            if !tx.is_coinbase(){
+                for vin in &tx.vin{
+                    let mut outs : TXOutputs = match utxos.get(&vin.txid)? {
+                        Some(data) => deserialize(&data)?,
+                        None => TXOutputs { outputs : BTreeMap::new() },
+                    };
+                    let out = outs.outputs.remove(&vin.vout)
+                        .ok_or_else(|| format_err!("block {} spends the missing output {}",block.get_hash(),vin.outpoint()))?;
+                    if outs.outputs.is_empty(){
+                        utxos.remove(&vin.txid)?;
+                    }else{
+                        utxos.insert(vin.txid.as_bytes(), bincode::serialize(&outs)?)?;
+                    }
+                    undo.push((vin.outpoint(),out));
+                }
+            }
+            let outs = TXOutputs { outputs : tx.vout.iter().cloned().enumerate().map(|(i,out)| (i as i32,out)).collect() };
+            utxos.insert(tx.id.as_bytes(), bincode::serialize(&outs)?)?;
+        }
+        self.db.open_tree(UNDO_TREE)?.insert(block.get_hash(), bincode::serialize(&undo)?)?;
+        Ok(())
+    }
+
+    // DisconnectBlock removes the outputs the block created from the UTXO
+    // set and puts back the ones it spent, last transaction first
+    fn disconnect_block(&self, block: &Block) -> Result<(),Box<dyn std::error::Error>> {
+        let utxos = self.utxo_tree()?;
+        let undo_tree = self.db.open_tree(UNDO_TREE)?;
+        let mut undo : Vec<(OutPoint,TXOutput)> = match undo_tree.get(block.get_hash())? {
+            Some(data) => deserialize(&data)?,
+            None => return Err(format_err!("no undo data for block {}",block.get_hash()).into()),
+        };
+        for tx in block.get_transaction().iter().rev(){
+            utxos.remove(&tx.id)?;
+            if tx.is_coinbase(){
+                continue;
+            }
+            for _ in &tx.vin{
+                let (outpoint, out) = undo.pop()
+                    .ok_or_else(|| format_err!("undo data of block {} is too short",block.get_hash()))?;
+                let mut outs : TXOutputs = match utxos.get(&outpoint.txid)? {
+                    Some(data) => deserialize(&data)?,
+                    None => TXOutputs { outputs : BTreeMap::new() },
+                };
+                outs.outputs.insert(outpoint.vout, out);
+                utxos.insert(outpoint.txid.as_bytes(), bincode::serialize(&outs)?)?;
+            }
         }
+        undo_tree.remove(block.get_hash())?;
+        Ok(())
+    }
+
+    // Descendants returns the block and every block of the tree built on it
+    fn descendants(&self, block_hash: &str) -> Result<Vec<String>,Box<dyn std::error::Error>> {
+        let children = self.db.open_tree(CHILDREN_TREE)?;
+        let mut found = vec![block_hash.to_string()];
+        let mut i = 0;
+        while i < found.len() {
+            for key in children.scan_prefix(&found[i]).keys() {
+                let key = key?;
+                found.push(String::from_utf8(key[found[i].len()..].to_vec())?);
+            }
+            i += 1;
+        }
+        Ok(found)
+    }
+
+    // IndexBlocks fills the children and heights trees from the stored blocks
+    fn index_blocks(&self) -> Result<(),Box<dyn std::error::Error>> {
+        for entry in self.db.iter() {
+            let (key, data) = entry?;
+            if key.as_ref() == b"LAST" {
+                continue;
+            }
+            store_block(&self.db, &deserialize(&data)?)?;
+        }
+        self.db.flush()?;
         Ok(())
     }
      
+    /// CheckBlock runs the context free consensus checks on a block
+    pub fn check_block(&self, block: &Block) -> Result<(),Box<dyn std::error::Error>> {
+        if !block.check_proof_of_work()? {
+            return Err(reject(RejectKind::InvalidPow, format!("block {}: invalid proof of work",block.get_hash())));
+        }
+        block.check_weight()?;
+        let txs = block.get_transaction();
+        if txs.first().is_none_or(|tx| !tx.is_coinbase()) || txs.iter().skip(1).any(|tx| tx.is_coinbase()) {
+            return Err(reject(RejectKind::Invalid, format!("block {}: the first transaction must be its only coinbase",block.get_hash())));
+        }
+        // every outpoint may be spent once per block
+        let mut spent : HashMap<OutPoint,String> = HashMap::new();
+        for tx in block.get_transaction(){
+            tx.value_out().map_err(|e| within(&format!("block {}",block.get_hash()), e))?;
+            if tx.id != tx.compute_id()?{
+                return Err(reject(RejectKind::Invalid, format!("block {}: transaction {} id does not match its content",block.get_hash(),tx.id)));
+            }
+            if tx.is_coinbase(){
+                continue;
+            }
+            for vin in &tx.vin{
+                if let Some(other) = spent.insert(vin.outpoint(), tx.id.clone()){
+                    return Err(reject(RejectKind::DoubleSpend, format!(
+                        "block {}: input {} is spent by both {} and {}",
+                        block.get_hash(),vin.outpoint(),other,tx.id
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// CheckBlockContext runs the consensus checks that depend on the parent
+    /// of the block : height, timestamp, transaction lock times, amounts,
+    /// signatures and the coinbase reward
+    pub fn check_block_context(&self, block: &Block) -> Result<(),Box<dyn std::error::Error>> {
+        let parent = self.get_block(&block.get_prev_hash())?;
+        if block.get_height() != parent.get_height() + 1 {
+            return Err(reject(RejectKind::Invalid, format!("block {}: height {} does not follow its parent",block.get_hash(),block.get_height())));
+        }
+        let median_time = self.median_time_past(&parent.get_hash())?;
+        if block.get_timestamp() <= median_time {
+            return Err(reject(RejectKind::Time, format!(
+                "block {}: timestamp {} is not after the median time past {}",
+                block.get_hash(),block.get_timestamp(),median_time
+            )));
+        }
+        // the coinbase commits to the height, so that it can not repeat an earlier one
+        if block.get_transaction()[0].coinbase_height() != Some(block.get_height()) {
+            return Err(reject(RejectKind::Invalid, format!("block {}: the coinbase does not start with the height {}",block.get_hash(),block.get_height())));
+        }
+        for tx in block.get_transaction(){
+            if !tx.is_final(block.get_height(), (median_time / 1000) as u64){
+                return Err(reject(RejectKind::NotFinal, format!("block {}: transaction {} is not final",block.get_hash(),tx.id)));
+            }
+        }
+        // the inputs spend unspent outputs of the chain the block builds on
+        // or of the transactions placed before them in the block
+        let mut view = self.utxo_view(&parent.get_hash())?;
+        let mut fees = Amount::ZERO;
+        for tx in block.get_transaction(){
+            // a transaction may not replace one whose outputs are not all spent
+            if view.has_unspent(&tx.id)? {
+                return Err(reject(RejectKind::Invalid, format!(
+                    "block {}: transaction {} already exists with unspent outputs",
+                    block.get_hash(),tx.id
+                )));
+            }
+            if !tx.is_coinbase(){
+                let mut spent = Vec::new();
+                for vin in &tx.vin{
+                    let outpoint = vin.outpoint();
+                    match view.spend(&outpoint)? {
+                        Some(out) => spent.push(out),
+                        None if view.is_spent(&outpoint) => return Err(reject(RejectKind::DoubleSpend, format!(
+                            "block {}: transaction {} spends the already spent output {}",
+                            block.get_hash(),tx.id,outpoint
+                        ))),
+                        None => return Err(reject(RejectKind::MissingInputs, format!(
+                            "block {}: transaction {} spends the missing or spent output {}",
+                            block.get_hash(),tx.id,outpoint
+                        ))),
+                    }
+                }
+                let fee = tx.check_spent_amounts(&spent).map_err(|e| within(&format!("block {}",block.get_hash()), e))?;
+                if !tx.verify_spent(&spent)?{
+                    return Err(reject(RejectKind::InvalidSignature, format!("block {}: transaction {} has an invalid signature",block.get_hash(),tx.id)));
+                }
+                fees = fees.checked_add(fee)
+                    .ok_or_else(|| reject(RejectKind::Invalid, format!("block {}: fees total out of range",block.get_hash())))?;
+            }
+            view.add(tx);
+        }
+        // the coinbase claims at most the subsidy and the fees of the block
+        let reward = Amount::from_units(BLOCK_SUBSIDY).checked_add(fees)
+            .ok_or_else(|| reject(RejectKind::Invalid, format!("block {}: reward out of range",block.get_hash())))?;
+        let value_out = block.get_transaction()[0].value_out()?;
+        if value_out > reward {
+            return Err(reject(RejectKind::Invalid, format!("block {}: coinbase pays {} but the reward is {}",block.get_hash(),value_out,reward)));
+        }
+        Ok(())
+    }
+
+    // UtxoView returns the unspent outputs as of the block, whether it is on
+    // the active chain or on a fork : the UTXO set of the tip with the blocks
+    // from the tip down to the fork point taken back, then the blocks of the
+    // fork applied up to the block
+    fn utxo_view(&self, block_hash: &str) -> Result<UtxoView,Box<dyn std::error::Error>> {
+        let mut view = UtxoView::new(self.utxo_tree()?);
+        let mut old = self.get_block(&self.current_hash)?;
+        let mut new = self.get_block(block_hash)?;
+        let mut branch = Vec::new();
+        while old.get_hash() != new.get_hash() {
+            if old.get_height() >= new.get_height() {
+                view.undo(&old, self.block_undo(&old.get_hash())?);
+                old = self.get_block(&old.get_prev_hash())?;
+            } else {
+                let prev_hash = new.get_prev_hash();
+                branch.push(new);
+                new = self.get_block(&prev_hash)?;
+            }
+        }
+        for block in branch.iter().rev() {
+            for tx in block.get_transaction(){
+                if !tx.is_coinbase(){
+                    for vin in &tx.vin{
+                        view.spend(&vin.outpoint())?;
+                    }
+                }
+                view.add(tx);
+            }
+        }
+        Ok(view)
+    }
+
+    fn block_undo(&self, block_hash: &str) -> Result<Vec<(OutPoint,TXOutput)>,Box<dyn std::error::Error>> {
+        let data = self.db.open_tree(UNDO_TREE)?.get(block_hash)?
+            .ok_or_else(|| format_err!("no undo data for block {}",block_hash))?;
+        Ok(deserialize(&data)?)
+    }
+
     fn get_prev_TXs(&self,tx : &Transaction) -> Result<HashMap<String,Transaction>,Box<dyn std::error::Error>>{
         let mut prev_TXs = HashMap::new();
         for vin in &tx.vin{
@@ -303,7 +896,8 @@ impl Blockchain {
     }
     
     pub fn get_block(&self, block_hash :&str)-> Result<Block,Box<dyn std::error::Error>>{
-        let data = self.db.get(block_hash)?.unwrap();
+        let data = self.db.get(block_hash)?
+            .ok_or_else(|| format_err!("block {} not found",block_hash))?;
         let block = deserialize(&data.to_vec())?;
         Ok(block)
     }
@@ -317,6 +911,100 @@ impl Blockchain {
 
 }
 
+// StoreBlock writes the block and its entries in the children and heights trees
+fn store_block(db : &sled::Db, block : &Block) -> Result<(),Box<dyn std::error::Error>> {
+    let hash = block.get_hash();
+    db.insert(&hash, bincode::serialize(block)?)?;
+    // the genesis block is nobody's child
+    if !block.get_prev_hash().is_empty() {
+        db.open_tree(CHILDREN_TREE)?.insert(format!("{}{}",block.get_prev_hash(),hash), vec![])?;
+    }
+    let mut height_key = (block.get_height() as u32).to_be_bytes().to_vec();
+    height_key.extend_from_slice(hash.as_bytes());
+    db.open_tree(HEIGHTS_TREE)?.insert(height_key, vec![])?;
+    Ok(())
+}
+
+// UtxoView is the UTXO set of the tip seen from another block : the outputs
+// added on top of it, and the outputs of the set hidden because they are
+// spent or were not created yet as of that block
+struct UtxoView {
+    base : sled::Tree,
+    added : HashMap<String,BTreeMap<i32,TXOutput>>,
+    hidden : HashSet<OutPoint>,
+    // the outputs spent through the view
+    spent : HashSet<OutPoint>,
+}
+
+impl UtxoView {
+    fn new(base : sled::Tree) -> UtxoView {
+        UtxoView { base, added : HashMap::new(), hidden : HashSet::new(), spent : HashSet::new() }
+    }
+
+    fn base_outputs(&self, txid : &str) -> Result<BTreeMap<i32,TXOutput>,Box<dyn std::error::Error>> {
+        match self.base.get(txid)? {
+            Some(data) => Ok(deserialize::<TXOutputs>(&data)?.outputs),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    // Spend takes the output out of the view and returns it, None when it is
+    // missing or already spent
+    fn spend(&mut self, outpoint : &OutPoint) -> Result<Option<TXOutput>,Box<dyn std::error::Error>> {
+        let out = match self.added.get_mut(&outpoint.txid).and_then(|outs| outs.remove(&outpoint.vout)) {
+            Some(out) => Some(out),
+            None if self.hidden.contains(outpoint) => None,
+            None => self.base_outputs(&outpoint.txid)?.remove(&outpoint.vout),
+        };
+        if out.is_some() {
+            self.hidden.insert(outpoint.clone());
+            self.spent.insert(outpoint.clone());
+        }
+        Ok(out)
+    }
+
+    // IsSpent tells whether the output was spent through the view
+    fn is_spent(&self, outpoint : &OutPoint) -> bool {
+        self.spent.contains(outpoint)
+    }
+
+    fn has_unspent(&self, txid : &str) -> Result<bool,Box<dyn std::error::Error>> {
+        if self.added.get(txid).is_some_and(|outs| !outs.is_empty()) {
+            return Ok(true);
+        }
+        Ok(self.base_outputs(txid)?.keys().any(|vout| !self.hidden.contains(&OutPoint { txid : txid.to_string(), vout : *vout })))
+    }
+
+    // Add puts the outputs of tx in the view
+    fn add(&mut self, tx : &Transaction) {
+        let outs = self.added.entry(tx.id.clone()).or_default();
+        for (vout, out) in tx.vout.iter().enumerate() {
+            outs.insert(vout as i32, out.clone());
+            self.hidden.remove(&OutPoint { txid : tx.id.clone(), vout : vout as i32 });
+        }
+    }
+
+    // Undo takes back a block of the tip side : its outputs leave the view
+    // and the outputs it spent, listed in undo, come back
+    fn undo(&mut self, block : &Block, mut undo : Vec<(OutPoint,TXOutput)>) {
+        for tx in block.get_transaction().iter().rev() {
+            self.added.remove(&tx.id);
+            for vout in 0..tx.vout.len() as i32 {
+                self.hidden.insert(OutPoint { txid : tx.id.clone(), vout });
+            }
+            if tx.is_coinbase() {
+                continue;
+            }
+            for _ in &tx.vin {
+                if let Some((outpoint, out)) = undo.pop() {
+                    self.hidden.remove(&outpoint);
+                    self.added.entry(outpoint.txid.clone()).or_default().insert(outpoint.vout, out);
+                }
+            }
+        }
+    }
+}
+
 impl <'a> Iterator for BlockchainIter<'a> {
     type Item = Block ;
     fn next(&mut self) -> Option<Self::Item> {
@@ -340,12 +1028,396 @@ impl <'a> Iterator for BlockchainIter<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::miner::{mine, Progress};
+    use crate::constants::{MAX_FUTURE_BLOCK_TIME, SEQUENCE_FINAL};
+    use crate::tx::TXInput;
+    use crate::validation::reject_kind_of;
+    use crate::wallet::hash_pub_key;
+    use crypto::ed25519;
+    use std::sync::atomic::AtomicBool;
+
     #[test]
     fn test_blockchain(){
-        let mut b = Blockchain::new().unwrap();
+        let b = Blockchain::from_db(sled::Config::new().temporary(true).open().unwrap()).unwrap();
+        assert_eq!(b.current_hash, params().genesis_hash);
         //b.add_block("data".to_string()).unwrap();
         //b.add_block("data1".to_string()).unwrap();
         //b.add_block("data2".to_string()).unwrap();
         dbg!(b);
     }
+
+    fn temporary_chain() -> Blockchain {
+        Blockchain::from_db(sled::Config::new().temporary(true).open().unwrap()).unwrap()
+    }
+
+    fn child(parent : &Block, tag : &str) -> Block {
+        child_with(parent, vec![unpaid(parent.get_height() + 1, tag)])
+    }
+
+    fn child_with(parent : &Block, transactions : Vec<Transaction>) -> Block {
+        let mut block = Block::new_template(transactions, parent.get_hash(), parent.get_height() + 1).unwrap();
+        assert!(mine(&mut block, 2, u64::MAX, &AtomicBool::new(false), &Progress::new()).unwrap());
+        block
+    }
+
+    // Key is an ed25519 keypair and the hash its outputs are locked to
+    struct Key {
+        secret_key : Vec<u8>,
+        public_key : Vec<u8>,
+        pub_key_hash : Vec<u8>,
+    }
+
+    fn key(seed : u8) -> Key {
+        let (secret_key, public_key) = ed25519::keypair(&[seed; 32]);
+        let mut pub_key_hash = public_key.to_vec();
+        hash_pub_key(&mut pub_key_hash);
+        Key { secret_key : secret_key.to_vec(), public_key : public_key.to_vec(), pub_key_hash }
+    }
+
+    // unpaid is a coinbase of the block at height that pays nobody
+    fn unpaid(height : i32, tag : &str) -> Transaction {
+        let mut tx = Transaction::new_genesis_coinbase(tag).unwrap();
+        tx.vin[0].pub_key.splice(0..0, height.to_le_bytes());
+        tx.id = tx.hash().unwrap();
+        tx
+    }
+
+    // coinbase pays the block subsidy at height to key
+    fn coinbase(height : i32, tag : &str, key : &Key) -> Transaction {
+        let mut tx = unpaid(height, tag);
+        tx.vout[0].pub_key_hash = key.pub_key_hash.clone();
+        tx.id = tx.hash().unwrap();
+        tx
+    }
+
+    // spend sends value from the first output of prev, owned by key, back to key
+    fn spend(prev : &Transaction, key : &Key, value : u64) -> Transaction {
+        let mut tx = Transaction {
+            id : String::new(),
+            vin : vec![TXInput { txid : prev.id.clone(), vout : 0, signature : Vec::new(), pub_key : key.public_key.clone(), sequence : SEQUENCE_FINAL }],
+            vout : vec![TXOutput { value : Amount::from_units(value), pub_key_hash : key.pub_key_hash.clone() }],
+            lock_time : 0,
+        };
+        tx.id = tx.hash().unwrap();
+        tx.sign(&key.secret_key, HashMap::from([(prev.id.clone(), prev.clone())])).unwrap();
+        tx
+    }
+
+    fn hashes(blocks : &[Block]) -> Vec<String> {
+        blocks.iter().map(|b| b.get_hash()).collect()
+    }
+
+    #[test]
+    fn test_reorg_invalidate_reconsider(){
+        let mut bc = temporary_chain();
+        let genesis = Block::genesis().unwrap();
+        let a1 = child(&genesis, "a1");
+        let a2 = child(&a1, "a2");
+        bc.add_block(a1.clone()).unwrap();
+        let change = bc.add_block(a2.clone()).unwrap();
+        assert_eq!(hashes(&change.connected), vec![a2.get_hash()]);
+        assert_eq!(bc.current_hash, a2.get_hash());
+
+        // a longer branch arriving tip first waits among the orphans
+        let b1 = child(&genesis, "b1");
+        let b2 = child(&b1, "b2");
+        let b3 = child(&b2, "b3");
+        assert!(bc.add_block(b3.clone()).unwrap().connected.is_empty());
+        assert!(bc.add_block(b2.clone()).unwrap().connected.is_empty());
+        assert_eq!(bc.current_hash, a2.get_hash());
+        let change = bc.add_block(b1.clone()).unwrap();
+        assert_eq!(hashes(&change.disconnected), hashes(&[a1.clone(), a2.clone()]));
+        assert_eq!(hashes(&change.connected), hashes(&[b1.clone(), b2.clone(), b3.clone()]));
+        assert_eq!(bc.get_best_height().unwrap(), 3);
+
+        let change = bc.invalidate_block(&b2.get_hash()).unwrap();
+        assert_eq!(hashes(&change.disconnected), hashes(&[b1.clone(), b2.clone(), b3.clone()]));
+        assert_eq!(hashes(&change.connected), hashes(&[a1.clone(), a2.clone()]));
+        assert!(bc.db.open_tree(INVALID_TREE).unwrap().contains_key(b3.get_hash()).unwrap());
+
+        // blocks built on an invalid block are invalid as well
+        let b4 = child(&b3, "b4");
+        assert!(bc.add_block(b4.clone()).unwrap().connected.is_empty());
+        assert_eq!(bc.current_hash, a2.get_hash());
+
+        let change = bc.reconsider_block(&b3.get_hash()).unwrap();
+        assert_eq!(hashes(&change.connected), hashes(&[b1.clone(), b2, b3, b4.clone()]));
+        assert_eq!(bc.current_hash, b4.get_hash());
+        assert!(bc.invalidate_block(&genesis.get_hash()).is_err());
+        assert_eq!(bc.descendants(&genesis.get_hash()).unwrap().len(), 7);
+
+        // the block indexes of an older database are built when it opens
+        for tree in [CHILDREN_TREE, HEIGHTS_TREE] {
+            bc.db.drop_tree(tree).unwrap();
+        }
+        let mut bc = Blockchain::from_db(bc.db.clone()).unwrap();
+        let change = bc.invalidate_block(&b1.get_hash()).unwrap();
+        assert_eq!(change.disconnected.len(), 4);
+        assert_eq!(bc.current_hash, a2.get_hash());
+    }
+
+    #[test]
+    fn test_orphan_pool(){
+        let mut bc = temporary_chain();
+        let mut chain = vec![Block::genesis().unwrap()];
+        for height in 1..=4 {
+            let block = child(&chain[height - 1], &format!("b{}", height));
+            chain.push(block);
+        }
+        // b4, b3 and b2 arrive before b1, the oldest one leaves a full pool
+        for block in chain[2..].iter().rev() {
+            assert!(bc.add_block(block.clone()).unwrap().connected.is_empty());
+        }
+        bc.trim_orphans(2, MAX_ORPHAN_BYTES).unwrap();
+        assert!(!bc.is_orphan(&chain[4].get_hash()).unwrap());
+        assert!(bc.is_orphan(&chain[3].get_hash()).unwrap());
+        bc.trim_orphans(2, bincode::serialized_size(&chain[2]).unwrap() as usize).unwrap();
+        assert!(!bc.is_orphan(&chain[3].get_hash()).unwrap());
+
+        // b1 brings in the orphan waiting for it and the pool is left empty
+        let change = bc.add_block(chain[1].clone()).unwrap();
+        assert_eq!(hashes(&change.connected), hashes(&chain[1..3]));
+        for tree in [ORPHANS_TREE, ORPHAN_PARENTS_TREE, ORPHAN_ORDER_TREE] {
+            assert!(bc.db.open_tree(tree).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_block_time_rules(){
+        let mut bc = temporary_chain();
+        let genesis = Block::genesis().unwrap();
+        let b1 = child(&genesis, "b1");
+        bc.add_block(b1.clone()).unwrap();
+        // the median of two blocks is the later one
+        assert_eq!(bc.median_time_past(&b1.get_hash()).unwrap(), b1.get_timestamp());
+
+        // the timestamp must move past the median of the previous blocks
+        let mut stale = Block::new_template(vec![unpaid(2, "b2")], b1.get_hash(), 2).unwrap();
+        stale.set_timestamp(b1.get_timestamp() - 1);
+        assert!(mine(&mut stale, 2, u64::MAX, &AtomicBool::new(false), &Progress::new()).unwrap());
+        assert!(bc.add_block(stale).is_err());
+
+        // a transaction locked until height 3 can not go in block 2
+        let mut locked = unpaid(2, "locked");
+        locked.vin[0].sequence = 0;
+        locked.lock_time = 3;
+        locked.id = locked.hash().unwrap();
+        assert!(!locked.is_final(2, 0));
+        assert!(locked.is_final(4, 0));
+        let mut block = Block::new_template(vec![locked], b1.get_hash(), 2).unwrap();
+        assert!(mine(&mut block, 2, u64::MAX, &AtomicBool::new(false), &Progress::new()).unwrap());
+        assert!(bc.add_block(block).is_err());
+
+        let b2 = child(&b1, "b2");
+        assert!(b2.check_future_time(unix_time_secs()).is_ok());
+        assert!(b2.check_future_time(unix_time_secs() - MAX_FUTURE_BLOCK_TIME - 60).is_err());
+        bc.add_block(b2).unwrap();
+    }
+
+    #[test]
+    fn test_block_locator(){
+        let mut bc = temporary_chain();
+        let mut chain = vec![Block::genesis().unwrap()];
+        for height in 1..=14 {
+            let block = child(&chain[height - 1], &format!("b{}", height));
+            bc.add_block(block.clone()).unwrap();
+            chain.push(block);
+        }
+        // heights 14 down to 5 one by one, then 3 and the genesis block
+        let locator = bc.block_locator();
+        assert_eq!(locator.len(), 12);
+        assert_eq!(locator[..10], hashes(&chain[5..]).into_iter().rev().collect::<Vec<_>>());
+        assert_eq!(locator[10..], [chain[3].get_hash(), chain[0].get_hash()]);
+
+        // a peer that stopped at height 3 gets the blocks from height 4
+        let peer = [child(&chain[3], "fork").get_hash(), chain[3].get_hash()];
+        assert_eq!(bc.blocks_after(&peer, 5), hashes(&chain[4..9]));
+        assert_eq!(bc.blocks_after(&["unknown".to_string()], 2), hashes(&chain[1..3]));
+        assert!(bc.blocks_after(&locator, 5).is_empty());
+    }
+
+    #[test]
+    fn test_block_transactions_checked(){
+        let mut bc = temporary_chain();
+        let genesis = Block::genesis().unwrap();
+        let alice = key(1);
+        let paid = coinbase(1, "b1", &alice);
+        let b1 = child_with(&genesis, vec![paid.clone()]);
+        bc.add_block(b1.clone()).unwrap();
+        let subsidy = paid.vout[0].value.as_units();
+
+        // the outputs of a transaction can not exceed its inputs
+        let overspend = spend(&paid, &alice, subsidy + 1);
+        assert!(bc.add_block(child_with(&b1, vec![unpaid(2, "b2"), overspend])).is_err());
+
+        // nor can it spend outputs of another key
+        let mut stolen = spend(&paid, &key(2), subsidy);
+        stolen.vin[0].pub_key = key(2).public_key;
+        assert!(bc.add_block(child_with(&b1, vec![unpaid(2, "b2"), stolen])).is_err());
+
+        // a transaction may spend one placed before it in the block
+        let first = spend(&paid, &alice, subsidy);
+        let second = spend(&first, &alice, subsidy);
+        let b2 = child_with(&b1, vec![unpaid(2, "b2"), first, second]);
+        bc.add_block(b2.clone()).unwrap();
+        assert_eq!(bc.current_hash, b2.get_hash());
+    }
+
+    #[test]
+    fn test_coinbase_rules(){
+        let mut bc = temporary_chain();
+        let genesis = Block::genesis().unwrap();
+        let alice = key(1);
+        let paid = coinbase(1, "b1", &alice);
+        let b1 = child_with(&genesis, vec![paid.clone()]);
+        bc.add_block(b1.clone()).unwrap();
+        let subsidy = paid.vout[0].value.as_units();
+
+        // a block holds one coinbase, placed first
+        assert!(bc.add_block(child_with(&b1, vec![])).is_err());
+        let tx = spend(&paid, &alice, subsidy);
+        assert!(bc.add_block(child_with(&b1, vec![tx.clone(), coinbase(2, "b2", &alice)])).is_err());
+        assert!(bc.add_block(child_with(&b1, vec![coinbase(2, "b2", &alice), coinbase(2, "b2'", &alice)])).is_err());
+
+        // the coinbase claims the subsidy plus the fees and no more
+        let tx = spend(&paid, &alice, subsidy - 10);
+        let mut greedy = coinbase(2, "b2", &alice);
+        greedy.vout[0].value = Amount::from_units(subsidy + 11);
+        greedy.id = greedy.hash().unwrap();
+        assert!(bc.add_block(child_with(&b1, vec![greedy, tx.clone()])).is_err());
+        let mut rewarded = coinbase(2, "b2", &alice);
+        rewarded.vout[0].value = Amount::from_units(subsidy + 10);
+        rewarded.id = rewarded.hash().unwrap();
+        let b2 = child_with(&b1, vec![rewarded, tx]);
+        bc.add_block(b2.clone()).unwrap();
+        assert_eq!(bc.current_hash, b2.get_hash());
+    }
+
+    #[test]
+    fn test_double_spend_across_blocks(){
+        let mut bc = temporary_chain();
+        let genesis = Block::genesis().unwrap();
+        let alice = key(1);
+        let paid = coinbase(1, "b1", &alice);
+        let b1 = child_with(&genesis, vec![paid.clone()]);
+        bc.add_block(b1.clone()).unwrap();
+        let subsidy = paid.vout[0].value.as_units();
+        let b2 = child_with(&b1, vec![unpaid(2, "b2"), spend(&paid, &alice, subsidy)]);
+        bc.add_block(b2.clone()).unwrap();
+
+        // the output spent in b2 has left the UTXO set on top of it
+        let again = spend(&paid, &alice, subsidy - 1);
+        let e = bc.add_block(child_with(&b2, vec![unpaid(3, "b3"), again.clone()])).unwrap_err();
+        assert_eq!(reject_kind_of(e.as_ref()), Some(RejectKind::MissingInputs));
+        assert_eq!(bc.current_hash, b2.get_hash());
+
+        // but it is unspent on a fork leaving b2 out, where it is spent once
+        let fork = child_with(&b1, vec![unpaid(2, "c2"), again]);
+        bc.add_block(fork.clone()).unwrap();
+        assert!(bc.db.contains_key(fork.get_hash()).unwrap());
+        let e = bc.add_block(child_with(&fork, vec![unpaid(3, "c3"), spend(&paid, &alice, subsidy - 2)])).unwrap_err();
+        assert_eq!(reject_kind_of(e.as_ref()), Some(RejectKind::DoubleSpend));
+        assert_eq!(bc.current_hash, b2.get_hash());
+
+        // an unknown output can not be spent either
+        let unknown = spend(&coinbase(1, "nowhere", &alice), &alice, subsidy);
+        let e = bc.add_block(child_with(&b2, vec![unpaid(3, "b3"), unknown])).unwrap_err();
+        assert_eq!(reject_kind_of(e.as_ref()), Some(RejectKind::MissingInputs));
+    }
+
+    fn utxo_snapshot(bc : &Blockchain) -> Vec<(sled::IVec,sled::IVec)> {
+        bc.utxo_tree().unwrap().iter().map(|kv| kv.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_utxo_set_follows_the_tip(){
+        let mut bc = temporary_chain();
+        let genesis = Block::genesis().unwrap();
+        let alice = key(1);
+        let paid = coinbase(1, "a1", &alice);
+        let a1 = child_with(&genesis, vec![paid.clone()]);
+        let first = spend(&paid, &alice, 1_000);
+        let a2 = child_with(&a1, vec![unpaid(2, "a2"), first.clone(), spend(&first, &alice, 500)]);
+        bc.add_block(a1.clone()).unwrap();
+        bc.add_block(a2.clone()).unwrap();
+        let on_a2 = utxo_snapshot(&bc);
+        assert!(bc.utxo_tree().unwrap().get(&paid.id).unwrap().is_none());
+
+        // a longer branch takes a2 back, its outputs go and paid comes back
+        let b2 = child(&a1, "b2");
+        let b3 = child(&b2, "b3");
+        bc.add_block(b2.clone()).unwrap();
+        bc.add_block(b3.clone()).unwrap();
+        assert_eq!(bc.current_hash, b3.get_hash());
+        assert!(bc.utxo_tree().unwrap().get(&paid.id).unwrap().is_some());
+        assert!(bc.utxo_tree().unwrap().get(&first.id).unwrap().is_none());
+        let on_b3 = utxo_snapshot(&bc);
+        bc.rebuild_utxos().unwrap();
+        assert_eq!(utxo_snapshot(&bc), on_b3);
+
+        bc.invalidate_block(&b2.get_hash()).unwrap();
+        assert_eq!(bc.current_hash, a2.get_hash());
+        assert_eq!(utxo_snapshot(&bc), on_a2);
+    }
+
+    #[test]
+    fn test_relabelled_transaction(){
+        let mut bc = temporary_chain();
+        let genesis = Block::genesis().unwrap();
+        let alice = key(1);
+        let paid = coinbase(1, "b1", &alice);
+        let b1 = child_with(&genesis, vec![paid.clone()]);
+        bc.add_block(b1.clone()).unwrap();
+        let subsidy = paid.vout[0].value.as_units();
+
+        // a transaction filed under another id would hide its outputs from
+        // whoever looks them up by the id they hash to
+        let mut relabelled = spend(&paid, &alice, subsidy);
+        relabelled.id = paid.id.clone();
+        let e = bc.add_block(child_with(&b1, vec![unpaid(2, "b2"), relabelled])).unwrap_err();
+        assert_eq!(reject_kind_of(e.as_ref()), Some(RejectKind::Invalid));
+        let mut relabelled = coinbase(2, "b2", &alice);
+        relabelled.id = "00".repeat(32);
+        assert!(bc.add_block(child_with(&b1, vec![relabelled])).is_err());
+        assert_eq!(bc.current_hash, b1.get_hash());
+    }
+
+    #[test]
+    fn test_coinbase_height(){
+        let mut bc = temporary_chain();
+        let genesis = Block::genesis().unwrap();
+        let alice = key(1);
+        let paid = coinbase(1, "b1", &alice);
+        let b1 = child_with(&genesis, vec![paid.clone()]);
+        bc.add_block(b1.clone()).unwrap();
+
+        // repeating the coinbase of b1 would overwrite its unspent output
+        let e = bc.add_block(child_with(&b1, vec![paid])).unwrap_err();
+        assert_eq!(reject_kind_of(e.as_ref()), Some(RejectKind::Invalid));
+        assert!(bc.add_block(child_with(&b1, vec![Transaction::new_genesis_coinbase("b2").unwrap()])).is_err());
+        assert_eq!(bc.current_hash, b1.get_hash());
+        bc.add_block(child_with(&b1, vec![coinbase(2, "b1", &alice)])).unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_transaction(){
+        let mut bc = temporary_chain();
+        let genesis = Block::genesis().unwrap();
+        let alice = key(1);
+        let paid = coinbase(1, "b1", &alice);
+        let b1 = child_with(&genesis, vec![paid.clone()]);
+        bc.add_block(b1.clone()).unwrap();
+        let tx = spend(&paid, &alice, paid.vout[0].value.as_units());
+        let b2 = child_with(&b1, vec![unpaid(2, "b2"), tx.clone()]);
+        bc.add_block(b2.clone()).unwrap();
+
+        // a transaction whose outputs are unspent can not be confirmed again
+        let e = bc.add_block(child_with(&b2, vec![unpaid(3, "b3"), tx])).unwrap_err();
+        assert_eq!(reject_kind_of(e.as_ref()), Some(RejectKind::Invalid));
+        assert_eq!(bc.current_hash, b2.get_hash());
+    }
+
+    fn unix_time_secs() -> u64 {
+        std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+    }
 }
@@ -0,0 +1,151 @@
+use std::str::FromStr;
+use std::sync::OnceLock;
+use bitcoincash_addr::{Address, Network as AddressNetwork};
+use failure::format_err;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+impl FromStr for Network {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s : &str) -> Result<Network, Self::Err> {
+        match s {
+            "main" | "mainnet" => Ok(Network::Mainnet),
+            "test" | "testnet" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(format_err!("unknown network {}, expected main, test or regtest", s).into()),
+        }
+    }
+}
+
+// ChainParams holds everything that differs from one network to another.
+// Nodes of different networks use different protocol ids, so they can not
+// even open a stream to each other, and different genesis blocks.
+#[derive(Debug)]
+pub struct ChainParams {
+    pub network : Network,
+    pub name : &'static str,
+    // the genesis block is rebuilt from these and must hash to genesis_hash
+    pub genesis_coinbase_data : &'static str,
+    pub genesis_timestamp : u128,
+    pub genesis_nonce : u64,
+    pub genesis_hash : &'static str,
+    // number of leading zero hex digits of a valid block hash
+    pub target_hex : usize,
+    // libp2p protocol ids of the dht / identify and of the node messages,
+    // and the gossipsub topics transactions and blocks are announced on
+    pub kad_protocol : &'static str,
+    pub message_protocol : &'static str,
+    pub tx_topic : &'static str,
+    pub block_topic : &'static str,
+    pub default_port : u16,
+    pub default_rpc_port : u16,
+    // base58 version bytes of the addresses
+    pub address_network : AddressNetwork,
+    // every database and file of the node lives under this directory
+    pub data_dir : &'static str,
+}
+
+pub static MAINNET : ChainParams = ChainParams {
+    network : Network::Mainnet,
+    name : "main",
+    genesis_coinbase_data : "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks",
+    genesis_timestamp : 1735689600000,
+    genesis_nonce : 93310,
+    genesis_hash : "0000f7b321d2cb3113bb994be7b089478e736afdd508cfdd8bf1d37e6824af41",
+    target_hex : 4,
+    kad_protocol : "/agent/connection/1.0.0",
+    message_protocol : "/agent/message/1.0.0",
+    tx_topic : "/agent/txs/1.0.0",
+    block_topic : "/agent/blocks/1.0.0",
+    default_port : 8000,
+    default_rpc_port : 8332,
+    address_network : AddressNetwork::Main,
+    data_dir : "data",
+};
+
+pub static TESTNET : ChainParams = ChainParams {
+    network : Network::Testnet,
+    name : "test",
+    genesis_coinbase_data : "testnet genesis of the post-quantum learning chain",
+    genesis_timestamp : 1735689600000,
+    genesis_nonce : 1921,
+    genesis_hash : "000d57415dfea9d768ce18c65c36f12439269d874916bcc2f2ea7ccbf8723731",
+    target_hex : 3,
+    kad_protocol : "/agent-testnet/connection/1.0.0",
+    message_protocol : "/agent-testnet/message/1.0.0",
+    tx_topic : "/agent-testnet/txs/1.0.0",
+    block_topic : "/agent-testnet/blocks/1.0.0",
+    default_port : 18000,
+    default_rpc_port : 18332,
+    address_network : AddressNetwork::Test,
+    data_dir : "data/testnet",
+};
+
+// regtest blocks are nearly free to mine, for local tests only
+pub static REGTEST : ChainParams = ChainParams {
+    network : Network::Regtest,
+    name : "regtest",
+    genesis_coinbase_data : "regtest genesis of the post-quantum learning chain",
+    genesis_timestamp : 1735689600000,
+    genesis_nonce : 1,
+    genesis_hash : "043840c6dc7650755b5f8006eb1322a2981e22cb4d2100f58f5f919209f607c7",
+    target_hex : 1,
+    kad_protocol : "/agent-regtest/connection/1.0.0",
+    message_protocol : "/agent-regtest/message/1.0.0",
+    tx_topic : "/agent-regtest/txs/1.0.0",
+    block_topic : "/agent-regtest/blocks/1.0.0",
+    default_port : 18444,
+    default_rpc_port : 18443,
+    // base58 has no regtest version bytes, regtest shares the testnet ones
+    address_network : AddressNetwork::Test,
+    data_dir : "data/regtest",
+};
+
+static SELECTED : OnceLock<&'static ChainParams> = OnceLock::new();
+
+// Select picks the network of the process, it must happen before params()
+// is first used and can not be changed afterwards
+pub fn select(network : Network) -> Result<(),Box<dyn std::error::Error>> {
+    let wanted = match network {
+        Network::Mainnet => &MAINNET,
+        Network::Testnet => &TESTNET,
+        Network::Regtest => &REGTEST,
+    };
+    let selected = SELECTED.get_or_init(|| wanted);
+    if selected.network != network {
+        return Err(format_err!("chain params already set to {}", selected.name).into());
+    }
+    Ok(())
+}
+
+// Params returns the parameters of the selected network, mainnet by default
+pub fn params() -> &'static ChainParams {
+    SELECTED.get_or_init(|| &MAINNET)
+}
+
+impl ChainParams {
+    // DataPath is the location of `name` inside the network data directory
+    pub fn data_path(&self, name : &str) -> String {
+        format!("{}/{}", self.data_dir, name)
+    }
+
+    pub fn rpc_addr(&self) -> String {
+        format!("127.0.0.1:{}", self.default_rpc_port)
+    }
+
+    // DecodeAddress returns the public key hash of an address of this network
+    pub fn decode_address(&self, address : &str) -> Result<Vec<u8>,Box<dyn std::error::Error>> {
+        let decoded = Address::decode(address)
+            .map_err(|e| format_err!("invalid address {}: {:?}", address, e))?;
+        if decoded.network != self.address_network {
+            return Err(format_err!("address {} does not belong to the {} network", address, self.name).into());
+        }
+        Ok(decoded.body)
+    }
+}
@@ -2,14 +2,22 @@ use std::process::exit;
 use bincode::Error;
 use bitcoincash_addr::Address;
 use clap::Command;
+use failure::format_err;
+use crate::amount::Amount;
 use crate::{block::Block, 
     blockchain::*, 
-    transaction::Transaction, 
     utxoset::UTXOSet, 
-    wallet::{Wallet,Wallets}
+    wallet::Wallets
     };
 use crate::server::Server;
-use clap::arg;
+use crate::chainparams::{self, params, Network};
+use crate::miner::{mine, MiningPolicy, Progress};
+use crate::rpc::{self, RpcAuth};
+use crate::constants::RPC_COOKIE_FILE;
+use serde_json::json;
+use libp2p::Multiaddr;
+use std::sync::atomic::AtomicBool;
+use clap::{arg, ArgAction};
 pub struct Cli {
 
 }
@@ -25,40 +33,121 @@ impl Cli {
             .version("0.1")
             .author("Armo")
             .about("a blockchain for learning purpose")
-            .subcommand(Command::new("printchain")).about("print all blocks in the chain")
+            .arg(arg!(--network <NETWORK> "'chain to work on: main, test or regtest'")
+                .global(true)
+                .value_parser(["main", "test", "regtest"])
+                .default_value("main"))
+            .arg(arg!(--rpcconnect <ADDR> "'JSON-RPC address of the node [default: 127.0.0.1:<network rpc port>]'").global(true))
+            .arg(arg!(--rpcuser <USER> "'JSON-RPC user name [default: the cookie of the data directory]'").global(true))
+            .arg(arg!(--rpcpassword <PASSWORD> "'JSON-RPC password'").global(true))
+            .subcommand(Command::new("printchain")).about("print all blocks in the chain of a running node")
             .subcommand(Command::new("createwallet")).about("create a wallet")
             .subcommand(Command::new("listaddresses")).about("list all addresses")
             .subcommand(Command::new("reindex")).about("update unspents transactions index")
             .subcommand(
                 Command::new("getbalance")
-                .about("get balance in the blockchain")
+                .about("get the confirmed balance of an address from a running node")
                 .arg(arg!(<ADDRESS>"'The Address it get balance for'"))
             )
             .subcommand(Command::new("startnode")
             .about("start the node server")
             .arg(arg!(<WALLET_ADDR>"'wallet addresss of the node'"))
-            .arg(arg!(<IP_ADDR>"'ip address of the node [specify only if it is bootsrap node]'"))
+            .arg(arg!([BOOTSTRAP]... "'multiaddrs of the bootstrap nodes to connect to [none: act as a bootstrap node on the default port]'"))
+            .arg(arg!(--rpcbind <ADDR> "'address the JSON-RPC server listens on [default: 127.0.0.1:<network rpc port>]'"))
+            .arg(arg!(--rest "'also serve the read only REST explorer under /rest/ on the JSON-RPC port'"))
+            .arg(arg!(--metrics "'also serve Prometheus metrics under /metrics on the JSON-RPC port'"))
+            .arg(arg!(--nomining "'do not mine, the node only relays [see setgenerate]'"))
+            .arg(arg!(--nomdns "'do not look for peers on the local network'"))
+            .arg(arg!(--"min-txs" <N> "'mine once a template holds this many transactions'").default_value("2"))
+            .arg(arg!(--"min-fees" <FEE> "'or once it pays this much in fees, in coins [0: off]'").default_value("0"))
+            .arg(arg!(--"max-block-interval" <SECS> "'mine even an empty block when the tip gets this old [0: off]'").default_value("0"))
+            .arg(arg!(--payout <ADDR> "'address blocks pay to, repeat to pay several in turn [default: WALLET_ADDR]'").action(ArgAction::Append))
+            ).subcommand(
+                Command::new("setgenerate")
+                .about("turn the miner of a running node on or off")
+                .arg(arg!(<GENERATE>"'true or false'").value_parser(clap::value_parser!(bool)))
             ).subcommand(
                 Command::new("create")
-                .about("Create new blockchain")
-                .arg(arg!(<ADDRESS>"'The address to send genesis block reward to'"))
+                .about("reset the block database to the genesis block of the network")
             ).subcommand(
                 Command::new("send")
-                .about("send in the blockchain")
+                .about("send from a wallet address of a running node")
                 .arg(arg!(<FROM>"'Source wallet address'"))
                 .arg(arg!(<TO>"'Destination wallet address'"))
-                .arg(arg!(<AMOUNT>"'amount to send'"))
-                .arg(arg!(-m --mine " 'the from address mine immediately'")),
+                .arg(arg!(<AMOUNT>"'amount to send, in coins with up to 8 decimals'"))
+                .arg(arg!(-m --mine "'mine a block holding it right away, paying the from address [regtest only]'"))
+                .arg(arg!(--fee <FEE> "'fee paid to the miner, in coins'").default_value("0"))
+                .arg(arg!(--rbf "'let the transaction be replaced later by a higher fee one'"))
+                .arg(arg!(--locktime <N> "'block height, or UNIX time from 500000000 up, before which it can not be mined'").default_value("0")),
+            ).subcommand(
+                Command::new("bumpfee")
+                .about("replace a pending replaceable transaction of a running node by one paying a higher fee")
+                .arg(arg!(<TXID>"'id of the transaction to bump'"))
+                .arg(arg!(--fee <FEE> "'new total fee, in coins [default: current fee + 0.0001]'")),
+            ).subcommand(
+                Command::new("mine")
+                .about("mine blocks for a running node through its block template RPC")
+                .arg(arg!(<ADDRESS>"'address the coinbase pays to'"))
+                .arg(arg!(--blocks <N> "'number of blocks to mine'").default_value("1"))
+            ).subcommand(
+                Command::new("generate")
+                .about("mine blocks right away on a running regtest node")
+                .arg(arg!(<N>"'number of blocks to mine, at most 1000'").value_parser(clap::value_parser!(u64)))
+                .arg(arg!([ADDRESS]"'address the coinbases pay to [default: the node payout address]'"))
+            ).subcommand(
+                Command::new("invalidateblock")
+                .about("mark a block and its descendants invalid on a running node")
+                .arg(arg!(<HASH>"'hash of the block'"))
+            ).subcommand(
+                Command::new("reconsiderblock")
+                .about("undo invalidateblock on a running node")
+                .arg(arg!(<HASH>"'hash of the block'"))
+            )
+            .subcommand(Command::new("getblockcount").about("height of the tip of a running node"))
+            .subcommand(
+                Command::new("getblock")
+                .about("show a block of a running node")
+                .arg(arg!(<HASH>"'hash of the block'"))
+                .arg(arg!(--hex "'print the serialized block'")),
+            ).subcommand(
+                Command::new("getblockhash")
+                .about("hash of the active chain block at a height")
+                .arg(arg!(<HEIGHT>"'height of the block'").value_parser(clap::value_parser!(i64))),
+            ).subcommand(
+                Command::new("getrawtransaction")
+                .about("show a mempool or confirmed transaction of a running node")
+                .arg(arg!(<TXID>"'id of the transaction'"))
+                .arg(arg!(--hex "'print the serialized transaction'")),
+            ).subcommand(
+                Command::new("sendrawtransaction")
+                .about("submit a signed, hex encoded transaction to a running node")
+                .arg(arg!(<HEX>"'serialized transaction'")),
+            )
+            .subcommand(Command::new("getmempoolinfo").about("size and fees of the mempool of a running node"))
+            .subcommand(Command::new("getpeerinfo").about("peers of a running node"))
+            .subcommand(Command::new("listbanned").about("peers banned by a running node"))
+            .subcommand(
+                Command::new("setban")
+                .about("ban a peer of a running node, or lift its ban")
+                .arg(arg!(<PEER_ID>"'libp2p peer id'"))
+                .arg(arg!(<COMMAND>"'add or remove'").value_parser(["add", "remove"]))
+                .arg(arg!(--bantime <SECS> "'how long the ban lasts [default: one day]'").value_parser(clap::value_parser!(u64)))
             )
+            .subcommand(Command::new("clearbanned").about("lift all the bans of a running node"))
+            .subcommand(Command::new("stop").about("stop a running node"))
             .get_matches();
+            // every path, port and consensus rule below depends on the network
+            chainparams::select(matches.get_one::<String>("network").unwrap().parse::<Network>()?)?;
         
             /*******************************************************************************/
             if let Some(ref matches) = matches.subcommand_matches("startnode") {
-                let ip_addr = if let Some(address) = matches.get_one::<String>("IP_ADDR") {
-                    address
-                } else {
-                    ""
-                };
+                // an empty address is accepted as none
+                let mut bootstrap = Vec::new();
+                for address in matches.get_many::<String>("BOOTSTRAP").unwrap_or_default() {
+                    if !address.is_empty() {
+                        bootstrap.push(address.parse::<Multiaddr>()?);
+                    }
+                }
                 let wallet_addr = if let Some(address) = matches.get_one::<String>("WALLET_ADDR") {
                     address
                 } else {
@@ -67,7 +156,22 @@ impl Cli {
                 };
                 let bc = Blockchain::new()?;
                 let utxo_set = UTXOSet { blockchain: bc };
-                let mut server = Server::new(ip_addr, wallet_addr, utxo_set).await?;
+                let mut policy = MiningPolicy::new(wallet_addr);
+                if let Some(payout) = matches.get_many::<String>("payout") {
+                    policy.payout_addresses = payout.cloned().collect();
+                }
+                policy.enabled = !matches.get_flag("nomining") && !policy.payout_addresses.is_empty();
+                policy.min_txs = matches.get_one::<String>("min-txs").unwrap().parse()?;
+                policy.min_fees = matches.get_one::<String>("min-fees").unwrap().parse()?;
+                policy.max_block_interval = matches.get_one::<String>("max-block-interval").unwrap().parse()?;
+                let auth = match (matches.get_one::<String>("rpcuser"), matches.get_one::<String>("rpcpassword")) {
+                    (Some(user), Some(password)) => RpcAuth::new(user, password),
+                    (None, None) => RpcAuth::cookie(&params().data_path(RPC_COOKIE_FILE))?,
+                    _ => return Err(format_err!("--rpcuser and --rpcpassword go together").into()),
+                };
+                let mut server = Server::new(bootstrap, wallet_addr, utxo_set, !matches.get_flag("nomdns")).await?;
+                server.set_mining_policy(policy);
+                server.start_rpc(&rpc_addr(matches, "rpcbind"), auth, matches.get_flag("rest"), matches.get_flag("metrics")).await?;
                 println!("Finish first step ===> start Server :");
                 server.start_server().await;
                 
@@ -85,16 +189,14 @@ impl Cli {
                 cmd_list_address()?;
             }
     
-            if let Some(ref matches) = matches.subcommand_matches("create") {
-                if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                    cmd_create_blockchain(address)?;
-                }
+            if let Some(_) = matches.subcommand_matches("create") {
+                cmd_create_blockchain()?;
             }
 
             if let Some(ref matches) = matches.subcommand_matches("getbalance") {
                 if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                    let balance = cmd_get_balance(address)?;
-                    println!("Balance: {}\n", balance);
+                    let balance = call_node(matches, "getbalance", json!([address])).await?;
+                    println!("Balance: {}\n", balance.as_str().unwrap_or_default());
                 }
             }
 
@@ -111,25 +213,110 @@ impl Cli {
                     println!("from not supply!: usage");
                     exit(1)
                 };
-                let amount: i32 = if let Some(amount) = matches.get_one::<String>("AMOUNT") {
+                let amount: Amount = if let Some(amount) = matches.get_one::<String>("AMOUNT") {
                     amount.parse()?
                 } else {
                     println!("from not supply!: usage");
                     exit(1)
                 };
-                cmd_send(from, to, amount, false).await?;
+                let fee: Amount = matches.get_one::<String>("fee").unwrap().parse()?;
+                let lock_time: u32 = matches.get_one::<String>("locktime").unwrap().parse()?;
+                let txid = call_node(matches, "sendtoaddress", json!([
+                    from, to, amount.to_string(), fee.to_string(), matches.get_flag("rbf"), lock_time
+                ])).await?;
+                println!("txid: {}", txid.as_str().unwrap_or_default());
+                if matches.get_flag("mine") {
+                    let hashes = call_node(matches, "generate", json!([1, from])).await?;
+                    println!("mined in block {}", hashes[0].as_str().unwrap_or_default());
+                }
+            }
 
-                /*if matches.contains_id("mine") {
-                    println!("start mining now ==> ");
-                    cmd_send(from, to, amount, true)?;
-                } else {
-                    println!("start mining later ==> ");
-                    cmd_send(from, to, amount, false)?;
-                }*/
+            if let Some(ref matches) = matches.subcommand_matches("bumpfee") {
+                let txid = matches.get_one::<String>("TXID").unwrap();
+                let mut call_params = vec![json!(txid)];
+                if let Some(fee) = matches.get_one::<String>("fee") {
+                    call_params.push(json!(fee.parse::<Amount>()?.to_string()));
+                }
+                let replacement = call_node(matches, "bumpfee", json!(call_params)).await?;
+                println!("txid: {} replaces {}", replacement["txid"].as_str().unwrap_or_default(), txid);
             }
     
-            if let Some(_) = matches.subcommand_matches("printchain") {
-                cmd_print_chain()?;
+            if let Some(matches) = matches.subcommand_matches("printchain") {
+                cmd_print_chain(matches).await?;
+            }
+
+            if let Some(ref matches) = matches.subcommand_matches("setgenerate") {
+                let generate = *matches.get_one::<bool>("GENERATE").unwrap();
+                call_node(matches, "setgenerate", json!([generate])).await?;
+                println!("mining {}", if generate { "enabled" } else { "disabled" });
+            }
+
+            if let Some(ref matches) = matches.subcommand_matches("mine") {
+                let address = matches.get_one::<String>("ADDRESS").unwrap();
+                let blocks: u32 = matches.get_one::<String>("blocks").unwrap().parse()?;
+                cmd_mine(address, blocks, &rpc_addr(matches, "rpcconnect"), &rpc_auth(matches)?).await?;
+            }
+
+            if let Some(matches) = matches.subcommand_matches("generate") {
+                let blocks = *matches.get_one::<u64>("N").unwrap();
+                let mut call_params = vec![json!(blocks)];
+                if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                    call_params.push(json!(address));
+                }
+                let hashes = call_node(matches, "generate", json!(call_params)).await?;
+                for hash in hashes.as_array().into_iter().flatten() {
+                    println!("{}", hash.as_str().unwrap_or_default());
+                }
+            }
+
+            for method in ["invalidateblock", "reconsiderblock"] {
+                if let Some(matches) = matches.subcommand_matches(method) {
+                    let hash = matches.get_one::<String>("HASH").unwrap();
+                    call_node(matches, method, json!([hash])).await?;
+                    println!("{} {}", method, hash);
+                }
+            }
+
+            for method in ["getblockcount", "getmempoolinfo", "getpeerinfo", "listbanned", "stop"] {
+                if let Some(matches) = matches.subcommand_matches(method) {
+                    print_result(&call_node(matches, method, json!([])).await?)?;
+                }
+            }
+
+            if let Some(matches) = matches.subcommand_matches("setban") {
+                let peer_id = matches.get_one::<String>("PEER_ID").unwrap();
+                let command = matches.get_one::<String>("COMMAND").unwrap();
+                let mut call_params = vec![json!(peer_id), json!(command)];
+                if let Some(bantime) = matches.get_one::<u64>("bantime") {
+                    call_params.push(json!(bantime));
+                }
+                call_node(matches, "setban", json!(call_params)).await?;
+                println!("{} {}", if command == "add" { "banned" } else { "unbanned" }, peer_id);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("clearbanned") {
+                call_node(matches, "clearbanned", json!([])).await?;
+                println!("all bans lifted");
+            }
+
+            if let Some(matches) = matches.subcommand_matches("getblock") {
+                let hash = matches.get_one::<String>("HASH").unwrap();
+                print_result(&call_node(matches, "getblock", json!([hash, !matches.get_flag("hex")])).await?)?;
+            }
+
+            if let Some(matches) = matches.subcommand_matches("getblockhash") {
+                let height = *matches.get_one::<i64>("HEIGHT").unwrap();
+                print_result(&call_node(matches, "getblockhash", json!([height])).await?)?;
+            }
+
+            if let Some(matches) = matches.subcommand_matches("getrawtransaction") {
+                let txid = matches.get_one::<String>("TXID").unwrap();
+                print_result(&call_node(matches, "getrawtransaction", json!([txid, !matches.get_flag("hex")])).await?)?;
+            }
+
+            if let Some(matches) = matches.subcommand_matches("sendrawtransaction") {
+                let data = matches.get_one::<String>("HEX").unwrap();
+                print_result(&call_node(matches, "sendrawtransaction", json!([data])).await?)?;
             }
     
             /*if let Some(_) = matches.subcommand_matches("reindex"){
@@ -216,12 +403,58 @@ impl Cli {
 /***********************************************************************/
 /******************************************************************/
 /************************************************************************/
-async fn cmd_send(from: &str, to: &str, amount: i32, mine_now: bool) -> Result<(),Box<dyn std::error::Error>> {
-    let bc = Blockchain::new()?;
-    let mut utxo_set = UTXOSet { blockchain: bc };
-    let tx = Transaction::new_UTXO(from, to, amount, &utxo_set)?;
-    Server::send_transaction(from,&tx, utxo_set).await?;
-    println!("success!");
+// RpcAddr is the JSON-RPC address given by `arg` or the one of the network
+fn rpc_addr(matches: &clap::ArgMatches, arg: &str) -> String {
+    matches.get_one::<String>(arg).cloned().unwrap_or_else(|| params().rpc_addr())
+}
+
+// RpcAuth returns the credentials given with --rpcuser and --rpcpassword,
+// or the cookie the running node wrote to the data directory
+fn rpc_auth(matches: &clap::ArgMatches) -> Result<RpcAuth,Box<dyn std::error::Error>> {
+    match (matches.get_one::<String>("rpcuser"), matches.get_one::<String>("rpcpassword")) {
+        (Some(user), Some(password)) => Ok(RpcAuth::new(user, password)),
+        (None, None) => RpcAuth::read_cookie(&params().data_path(RPC_COOKIE_FILE)),
+        _ => Err(format_err!("--rpcuser and --rpcpassword go together").into()),
+    }
+}
+
+// CallNode sends one JSON-RPC request to the node given by the global options
+async fn call_node(matches: &clap::ArgMatches, method: &str, params: serde_json::Value) -> Result<serde_json::Value,Box<dyn std::error::Error>> {
+    rpc::call(&rpc_addr(matches, "rpcconnect"), &rpc_auth(matches)?, method, params).await
+}
+
+// PrintResult prints strings as they are and anything else as pretty JSON
+fn print_result(value: &serde_json::Value) -> Result<(),Box<dyn std::error::Error>> {
+    match value.as_str() {
+        Some(s) => println!("{}", s),
+        None => println!("{}", serde_json::to_string_pretty(value)?),
+    }
+    Ok(())
+}
+
+// CmdMine acts as an external miner : it asks the node for a template,
+// searches the proof of work locally and submits the solved block
+async fn cmd_mine(address: &str, blocks: u32, rpc_addr: &str, auth: &RpcAuth) -> Result<(),Box<dyn std::error::Error>> {
+    let threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    for _ in 0..blocks {
+        let template = rpc::call(rpc_addr, auth, "getblocktemplate", json!([address])).await?;
+        let data = template["block"].as_str()
+            .ok_or_else(|| format_err!("template without block data"))?;
+        let mut block: Block = bincode::deserialize(&hex::decode(data)?)?;
+        println!(
+            "mining block at height {} with {} transactions",
+            block.get_height(), template["transactions"].as_array().map_or(0, |t| t.len())
+        );
+        let block = tokio::task::spawn_blocking(move || {
+            let progress = Progress::new();
+            mine(&mut block, threads, u64::MAX, &AtomicBool::new(false), &progress)
+                .map(|_| (block, progress.hashrate()))
+                .map_err(|e| e.to_string())
+        }).await?;
+        let (block, hashrate) = block.map_err(|e| format_err!("{}", e))?;
+        let hash = rpc::call(rpc_addr, auth, "submitblock", json!([hex::encode(bincode::serialize(&block)?)])).await?;
+        println!("block {} accepted ({:.0} H/s)", hash.as_str().unwrap_or_default(), hashrate);
+    }
     Ok(())
 }
 
@@ -239,9 +472,8 @@ fn cmd_reindex() -> Result<i32,Box<dyn std::error::Error>> {
     utxo_set.count_transactions()
 }
 
-fn cmd_create_blockchain(address: &str) -> Result<(),Box<dyn std::error::Error>> {
-    let address = String::from(address);
-    let bc = Blockchain::create_blockchain(address)?;
+fn cmd_create_blockchain() -> Result<(),Box<dyn std::error::Error>> {
+    let bc = Blockchain::create_blockchain()?;
 
     let utxo_set = UTXOSet { blockchain: bc };
     utxo_set.reindex()?;
@@ -249,23 +481,13 @@ fn cmd_create_blockchain(address: &str) -> Result<(),Box<dyn std::error::Error>>
     Ok(())
 }
 
-fn cmd_get_balance(address: &str) -> Result<i32,Box<dyn std::error::Error>> {
-    let pub_key_hash = Address::decode(address).unwrap().body;
-    let bc = Blockchain::new()?;
-    let utxo_set = UTXOSet { blockchain: bc };
-    let utxos = utxo_set.find_UTXO(&pub_key_hash)?;
-
-    let mut balance = 0;
-    for out in utxos.outputs {
-        balance += out.value;
-    }
-    Ok(balance)
-}
-
-fn cmd_print_chain() -> Result<(),Box<dyn std::error::Error>> {
-    let bc = Blockchain::new()?;
-    for b in bc.iter() {
-        println!("{:#?}", b);
+// CmdPrintChain walks the active chain of a running node from its tip
+async fn cmd_print_chain(matches: &clap::ArgMatches) -> Result<(),Box<dyn std::error::Error>> {
+    let mut hash = call_node(matches, "getbestblockhash", json!([])).await?;
+    while let Some(block_hash) = hash.as_str().filter(|h| !h.is_empty()) {
+        let block = call_node(matches, "getblock", json!([block_hash])).await?;
+        print_result(&block)?;
+        hash = block["previousblockhash"].clone();
     }
     Ok(())
 }
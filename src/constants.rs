@@ -1,3 +1,101 @@
-const MAX_BLOCK_SIZE : usize = 2^20; 
-const TARGET_HEXT: usize = 4; 
+// block budget measured on the canonical (bincode) serialization,
+// excluding witness data (input signatures and public keys)
+pub const MAX_BLOCK_SIZE : usize = 1 << 20;
+// non witness bytes weigh WITNESS_SCALE_FACTOR, witness bytes weigh 1 :
+// post-quantum signatures are several KB, counting them at full price
+// would leave room for only a few hundred transactions per block
+pub const WITNESS_SCALE_FACTOR : usize = 4;
+pub const MAX_BLOCK_WEIGHT : usize = MAX_BLOCK_SIZE * WITNESS_SCALE_FACTOR;
+// weight kept aside for the block header fields when building a template
+pub const BLOCK_HEADER_WEIGHT_RESERVE : usize = 4_000;
+// reward paid by the coinbase of every block, in smallest units
+pub const BLOCK_SUBSIDY : u64 = 100 * crate::amount::COIN;
+// blocks whose parent is unknown wait in the orphan pool, at most
+// MAX_ORPHAN_BLOCKS of them weighing MAX_ORPHAN_BYTES together, the oldest
+// are dropped first
+pub const MAX_ORPHAN_BLOCKS : usize = 100;
+pub const MAX_ORPHAN_BYTES : usize = 8 * MAX_BLOCK_WEIGHT;
+// mempool limits : total weight of pending transactions, number of
+// entries and how long an entry may wait before being dropped
+pub const MEMPOOL_MAX_WEIGHT : usize = 100 * MAX_BLOCK_WEIGHT;
+pub const MEMPOOL_MAX_COUNT : usize = 50_000;
+pub const MEMPOOL_EXPIRY_SECS : u64 = 14 * 24 * 60 * 60;
+// input sequence numbers : final inputs opt out of replace-by-fee
+pub const SEQUENCE_FINAL : u32 = 0xffffffff;
+pub const MAX_REPLACEABLE_SEQUENCE : u32 = 0xfffffffd;
+// lock times below this are block heights, the others UNIX times in seconds
+pub const LOCKTIME_THRESHOLD : u32 = 500_000_000;
+// a block timestamp must exceed the median of the previous MEDIAN_TIME_SPAN
+// blocks and be at most MAX_FUTURE_BLOCK_TIME seconds ahead of the network
+// adjusted time, whose offset from the local clock is capped to
+// MAX_TIME_ADJUSTMENT seconds
+pub const MEDIAN_TIME_SPAN : usize = 11;
+pub const MAX_FUTURE_BLOCK_TIME : u64 = 2 * 60 * 60;
+pub const MAX_TIME_ADJUSTMENT : i64 = 70 * 60;
+// fee increase used by bumpfee when no new fee is given, in smallest units
+pub const DEFAULT_BUMP_FEE : u64 = 10_000;
+// pending transactions are saved to this file of the data directory on
+// shutdown and every MEMPOOL_SAVE_INTERVAL_SECS
+pub const MEMPOOL_FILE : &str = "mempool.dat";
+pub const MEMPOOL_SAVE_INTERVAL_SECS : u64 = 10 * 60;
+// a node started without --rpcuser writes random JSON-RPC credentials to
+// this file of the data directory, the local clients read them from there
+pub const RPC_COOKIE_FILE : &str = ".cookie";
+// the generate RPC mines its blocks one after the other on the miner
+// threads, one call mines at most this many blocks
+pub const MAX_GENERATE_BLOCKS : u64 = 1000;
+// node events waiting for a slow subscriber, past this it skips ahead and
+// is told how many it missed, idle streams get a comment every
+// EVENTS_KEEPALIVE_SECS so dead clients are noticed
+pub const EVENTS_CAPACITY : usize = 1024;
+pub const EVENTS_KEEPALIVE_SECS : u64 = 15;
+// a request to a peer that fails is sent again up to MAX_REQUEST_RETRIES
+// times, waiting REQUEST_RETRY_DELAY_SECS more before each new attempt
+pub const MAX_REQUEST_RETRIES : u32 = 3;
+pub const REQUEST_RETRY_DELAY_SECS : u64 = 2;
+// a gossip message carries at most one block
+pub const MAX_GOSSIP_MESSAGE_SIZE : usize = MAX_BLOCK_WEIGHT;
+// the node keeps TARGET_OUTBOUND_PEERS connections it dialed itself : it
+// checks every PEER_CHECK_INTERVAL_SECS and dials more peers when it lacks
+// some, right when one drops unless it did so less than RECONNECT_DELAY_SECS
+// ago, and refreshes its kademlia routing table every BOOTSTRAP_INTERVAL_SECS
+pub const TARGET_OUTBOUND_PEERS : usize = 8;
+pub const PEER_CHECK_INTERVAL_SECS : u64 = 30;
+pub const RECONNECT_DELAY_SECS : u64 = 5;
+pub const BOOTSTRAP_INTERVAL_SECS : u64 = 5 * 60;
+// the libp2p keypair of the node is kept in this file of the data directory
+// so its peer id survives restarts, the peers it met in ADDRBOOK_FILE : at
+// most ADDRBOOK_MAX_PEERS of them, forgotten when not seen for
+// ADDRBOOK_EXPIRY_SECS
+pub const NODE_KEY_FILE : &str = "node_key";
+pub const ADDRBOOK_FILE : &str = "peers.dat";
+pub const ADDRBOOK_MAX_PEERS : usize = 1000;
+pub const ADDRBOOK_EXPIRY_SECS : u64 = 30 * 24 * 60 * 60;
+// a peer whose misbehavior score reaches BAN_SCORE_THRESHOLD is
+// disconnected and banned for DEFAULT_BAN_SECS, the bans are kept in
+// BANLIST_FILE of the data directory
+pub const BAN_SCORE_THRESHOLD : u32 = 100;
+pub const DEFAULT_BAN_SECS : u64 = 24 * 60 * 60;
+pub const BANLIST_FILE : &str = "banlist.dat";
+// an inventory lists at most this many blocks or transactions, about what
+// fits in a request-response message
+pub const MAX_INV_ITEMS : usize = 100_000;
+// a node syncing asks for the blocks after the hashes of its block
+// locator, of at most MAX_LOCATOR_HASHES, and gets them MAX_BLOCKS_PER_INV
+// at a time
+pub const MAX_LOCATOR_HASHES : usize = 101;
+pub const MAX_BLOCKS_PER_INV : usize = 500;
+// version of the node messages, peers older than MIN_PEER_PROTOCOL_VERSION
+// are disconnected, as are those not done with the version handshake after
+// HANDSHAKE_TIMEOUT_SECS
+pub const PROTOCOL_VERSION : i32 = 3;
+pub const MIN_PEER_PROTOCOL_VERSION : i32 = 3;
+pub const HANDSHAKE_TIMEOUT_SECS : u64 = 30;
+// services a node announces in its version message : it serves the whole
+// chain, answers light clients over the REST explorer, or only keeps the
+// recent blocks
+pub const NODE_NETWORK : u64 = 1;
+pub const NODE_LIGHT_SERVER : u64 = 1 << 1;
+pub const NODE_PRUNED : u64 = 1 << 2;
+const TARGET_HEXT: usize = 4;
 const COINBASE_MATURITY_THRESHOLD: usize = 100 ;
\ No newline at end of file
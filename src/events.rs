@@ -0,0 +1,104 @@
+use std::collections::HashSet;
+use serde_json::{json, Value};
+
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::transaction::Transaction;
+use crate::wallet::hash_pub_key;
+
+// NodeEvent is pushed to the subscribers of /rest/events when the node
+// connects or disconnects a block, or a transaction enters or leaves the
+// mempool
+#[derive(Clone, Debug)]
+pub struct NodeEvent {
+    // blockconnected, blockdisconnected, txaccepted or txremoved
+    pub kind : &'static str,
+    pub data : Value,
+    // public key hashes paid or spending in the event, the address
+    // filters of the subscribers are matched against them
+    pub pub_key_hashes : HashSet<Vec<u8>>,
+}
+
+impl NodeEvent {
+    pub fn block_connected(block : &Block) -> NodeEvent {
+        NodeEvent::block("blockconnected", block)
+    }
+
+    // a block of the old branch during a reorg, or an invalidated one
+    pub fn block_disconnected(block : &Block) -> NodeEvent {
+        NodeEvent::block("blockdisconnected", block)
+    }
+
+    fn block(kind : &'static str, block : &Block) -> NodeEvent {
+        let txids : Vec<&String> = block.get_transaction().iter().map(|tx| &tx.id).collect();
+        NodeEvent {
+            kind,
+            data : json!({
+                "type" : kind,
+                "hash" : block.get_hash(),
+                "height" : block.get_height(),
+                "previousblockhash" : block.get_prev_hash(),
+                "time" : block.get_timestamp(),
+                "txids" : txids,
+            }),
+            pub_key_hashes : block.get_transaction().iter().flat_map(tx_pub_key_hashes).collect(),
+        }
+    }
+
+    pub fn tx_accepted(tx : &Transaction, fee : Amount) -> NodeEvent {
+        NodeEvent {
+            kind : "txaccepted",
+            data : json!({ "type" : "txaccepted", "txid" : tx.id, "fee" : fee.to_string() }),
+            pub_key_hashes : tx_pub_key_hashes(tx).collect(),
+        }
+    }
+
+    // `reason` is evicted, conflict or expired, the transactions confirmed
+    // by a block leave with its blockconnected event instead
+    pub fn tx_removed(tx : &Transaction, reason : &str) -> NodeEvent {
+        NodeEvent {
+            kind : "txremoved",
+            data : json!({ "type" : "txremoved", "txid" : tx.id, "reason" : reason }),
+            pub_key_hashes : tx_pub_key_hashes(tx).collect(),
+        }
+    }
+
+    // Matches tells whether a subscriber watching `filter` wants the event,
+    // an empty filter gets everything
+    pub fn matches(&self, filter : &HashSet<Vec<u8>>) -> bool {
+        filter.is_empty() || !self.pub_key_hashes.is_disjoint(filter)
+    }
+
+    // ToSse formats the event for a text/event-stream
+    pub fn to_sse(&self) -> String {
+        format!("event: {}\ndata: {}\n\n", self.kind, self.data)
+    }
+}
+
+// the public key hashes a transaction pays to or spends from
+fn tx_pub_key_hashes(tx : &Transaction) -> impl Iterator<Item = Vec<u8>> + '_ {
+    let spending = tx.vin.iter().filter(|_| !tx.is_coinbase()).map(|vin| {
+        let mut pub_key_hash = vin.pub_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        pub_key_hash
+    });
+    spending.chain(tx.vout.iter().map(|out| out.pub_key_hash.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let tx = Transaction::new_genesis_coinbase("events").unwrap();
+        let paid = tx.vout[0].pub_key_hash.clone();
+        let event = NodeEvent::tx_accepted(&tx, Amount::ZERO);
+        assert!(event.matches(&HashSet::new()));
+        assert!(event.matches(&HashSet::from([paid.clone(), vec![1; 20]])));
+        assert!(!event.matches(&HashSet::from([vec![1; 20]])));
+        // a coinbase input carries no key
+        assert_eq!(event.pub_key_hashes, HashSet::from([paid]));
+        assert!(event.to_sse().starts_with("event: txaccepted\ndata: {"));
+    }
+}
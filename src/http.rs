@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use failure::format_err;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+// requests larger than this are refused, a hex encoded block fits well below
+const MAX_BODY_SIZE : usize = 32 << 20;
+const MAX_HEADER_LINES : usize = 100;
+// the longest request or header line, and the request line with all the
+// headers together
+const MAX_LINE_SIZE : usize = 8 << 10;
+const MAX_HEADER_SIZE : usize = 64 << 10;
+// a client that does not send its whole request within this is dropped
+const READ_TIMEOUT_SECS : u64 = 30;
+
+// HttpRequest is the part of an HTTP/1.1 request the local servers look at
+pub struct HttpRequest {
+    pub method : String,
+    pub path : String,
+    // header names are lower cased
+    pub headers : HashMap<String, String>,
+    pub body : Vec<u8>,
+}
+
+// ReadRequest reads one request from the stream, bodies are only read
+// through Content-Length (no chunked encoding)
+pub async fn read_request(stream : &mut TcpStream) -> Result<HttpRequest,Box<dyn std::error::Error>> {
+    match tokio::time::timeout(Duration::from_secs(READ_TIMEOUT_SECS), read_request_parts(stream)).await {
+        Ok(request) => request,
+        Err(_) => Err(format_err!("no complete request within {}s", READ_TIMEOUT_SECS).into()),
+    }
+}
+
+async fn read_request_parts(stream : &mut TcpStream) -> Result<HttpRequest,Box<dyn std::error::Error>> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    let mut left = MAX_HEADER_SIZE;
+    read_line(&mut reader, &mut line, &mut left).await?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(m), Some(p)) => (m.to_string(), p.to_string()),
+        _ => return Err(format_err!("malformed request line: {:?}", line).into()),
+    };
+
+    let mut headers = HashMap::new();
+    loop {
+        if read_line(&mut reader, &mut line, &mut left).await? == 0 {
+            return Err(format_err!("connection closed inside the headers").into());
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADER_LINES {
+            return Err(format_err!("too many headers").into());
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let length : usize = match headers.get("content-length") {
+        Some(l) => l.parse()?,
+        None => 0,
+    };
+    if length > MAX_BODY_SIZE {
+        return Err(format_err!("request body of {} bytes is too large", length).into());
+    }
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body).await?;
+    Ok(HttpRequest { method, path, headers, body })
+}
+
+// ReadLine replaces `line` with the next line of the request, which may
+// be MAX_LINE_SIZE long and use up to `left` bytes of the headers
+async fn read_line(reader : &mut BufReader<&mut TcpStream>, line : &mut String, left : &mut usize) -> Result<usize,Box<dyn std::error::Error>> {
+    line.clear();
+    let limit = MAX_LINE_SIZE.min(*left);
+    let read = reader.take(limit as u64).read_line(line).await?;
+    if read == limit && !line.ends_with('\n') {
+        return Err(match limit {
+            MAX_LINE_SIZE => format_err!("request or header line longer than {} bytes", MAX_LINE_SIZE),
+            _ => format_err!("request headers longer than {} bytes", MAX_HEADER_SIZE),
+        }.into());
+    }
+    *left -= read;
+    Ok(read)
+}
+
+// WriteResponse sends a complete response and closes the exchange,
+// every connection carries a single request
+pub async fn write_response(stream : &mut TcpStream, status : u16, content_type : &str, body : &[u8]) -> Result<(),Box<dyn std::error::Error>> {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status, reason(status), content_type, body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+// WriteStreamHead starts a response whose body runs until the connection
+// closes, like a text/event-stream
+pub async fn write_stream_head(stream : &mut TcpStream, content_type : &str) -> Result<(),Box<dyn std::error::Error>> {
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        content_type
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+// QueryParams splits the query string of a path into its name=value
+// pairs, values are not percent decoded
+pub fn query_params(path : &str) -> Vec<(&str, &str)> {
+    match path.split_once('?') {
+        Some((_, query)) => query.split('&').filter(|p| !p.is_empty())
+            .map(|p| p.split_once('=').unwrap_or((p, ""))).collect(),
+        None => Vec::new(),
+    }
+}
+
+fn reason(status : u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
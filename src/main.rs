@@ -10,6 +10,18 @@ mod utxoset;
 mod server ;
 mod behavior;
 mod constants;
+mod chainparams;
+mod amount;
+mod mempool;
+mod miner;
+mod http;
+mod rpc;
+mod rest;
+mod events;
+mod metrics;
+mod addrbook;
+mod banlist;
+mod validation;
 use env_logger::{Env, Builder};
 /********************
  * wallets owners rely on merkle trees to veirfy transactions 
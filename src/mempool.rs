@@ -0,0 +1,563 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+use log::{debug, info, warn};
+
+use crate::amount::Amount;
+use crate::constants::*;
+use crate::transaction::Transaction;
+use crate::tx::OutPoint;
+use crate::utxoset::UTXOSet;
+use crate::validation::{reject, RejectKind};
+
+// MempoolEntry is a validated pending transaction together with
+// what the mempool needs to order, chain and evict it
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub tx : Transaction,
+    pub fee : Amount,
+    pub weight : usize,
+    // admission time, seconds since UNIX epoch
+    pub time : u64,
+    // pending transactions this one spends from
+    pub parents : HashSet<String>,
+    // pending transactions spending from this one
+    pub children : HashSet<String>,
+}
+
+impl MempoolEntry {
+    fn cmp_fee_rate(&self, other : &MempoolEntry) -> Ordering {
+        cmp_fee_rate(self.fee, self.weight, other.fee, other.weight)
+    }
+}
+
+// compare fee per weight unit without rounding : a/wa ? b/wb <=> a*wb ? b*wa
+fn cmp_fee_rate(fee_a : Amount, weight_a : usize, fee_b : Amount, weight_b : usize) -> Ordering {
+    let lhs = fee_a.as_units() as u128 * weight_b as u128;
+    let rhs = fee_b.as_units() as u128 * weight_a as u128;
+    lhs.cmp(&rhs)
+}
+
+pub struct Mempool {
+    entries : HashMap<String, MempoolEntry>,
+    // outpoint -> id of the pending transaction spending it
+    spends : HashMap<OutPoint, String>,
+    total_weight : usize,
+    max_weight : usize,
+    max_count : usize,
+    expiry : u64,
+}
+
+impl Mempool {
+    pub fn new() -> Mempool {
+        Mempool::with_limits(MEMPOOL_MAX_WEIGHT, MEMPOOL_MAX_COUNT, MEMPOOL_EXPIRY_SECS)
+    }
+
+    pub fn with_limits(max_weight : usize, max_count : usize, expiry : u64) -> Mempool {
+        Mempool {
+            entries : HashMap::new(),
+            spends : HashMap::new(),
+            total_weight : 0,
+            max_weight,
+            max_count,
+            expiry,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // TotalWeight is the weight of all the pending transactions
+    pub fn total_weight(&self) -> usize {
+        self.total_weight
+    }
+
+    // TotalFees is what the pending transactions pay in fees altogether
+    pub fn total_fees(&self) -> Amount {
+        Amount::checked_sum(self.entries.values().map(|e| e.fee)).unwrap_or(Amount::ZERO)
+    }
+
+    // Entries lists the pending transactions in no particular order
+    pub fn entries(&self) -> impl Iterator<Item = &MempoolEntry> {
+        self.entries.values()
+    }
+
+    pub fn get_entry(&self, txid : &str) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    pub fn contains(&self, txid : &str) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    pub fn get(&self, txid : &str) -> Option<&Transaction> {
+        self.entries.get(txid).map(|e| &e.tx)
+    }
+
+    /// Add validates tx against the UTXO set and the pending transactions, admits it
+    /// and returns the transactions it replaced or that were evicted to keep the
+    /// mempool within its limits
+    pub fn add(&mut self, tx : Transaction, utxo : &UTXOSet, now : u64) -> Result<Vec<Transaction>, Box<dyn std::error::Error>> {
+        if tx.is_coinbase() {
+            return Err(reject(RejectKind::Invalid, "coinbase transactions are not relayed".to_string()));
+        }
+        if self.entries.contains_key(&tx.id) {
+            return Err(reject(RejectKind::Duplicate, format!("transaction {} already in mempool", tx.id)));
+        }
+        if tx.id != tx.compute_id()? {
+            return Err(reject(RejectKind::Invalid, format!("transaction {} id does not match its content", tx.id)));
+        }
+        // a transaction that cannot fit next to the coinbase will never be mined
+        let weight = tx.weight()?;
+        if weight > MAX_BLOCK_WEIGHT - BLOCK_HEADER_WEIGHT_RESERVE {
+            return Err(reject(RejectKind::Weight, format!("weight {} is too large", weight)));
+        }
+
+        let mut spent = Vec::new();
+        let mut outpoints = HashSet::new();
+        // pending transactions spending the same outputs, tx can only get in by replacing them
+        let mut conflicts = HashSet::new();
+        for vin in &tx.vin {
+            let outpoint = vin.outpoint();
+            if !outpoints.insert(outpoint.clone()) {
+                return Err(reject(RejectKind::DoubleSpend, format!("input {} spent twice", outpoint)));
+            }
+            if let Some(other) = self.spends.get(&outpoint) {
+                conflicts.insert(other.clone());
+            }
+            // chained spend of a pending transaction, or a confirmed unspent output
+            let out = match self.entries.get(&vin.txid) {
+                Some(parent) => usize::try_from(vin.vout).ok().and_then(|i| parent.tx.vout.get(i)).cloned(),
+                None => utxo.get_output(&outpoint)?,
+            };
+            match out {
+                Some(out) => spent.push(out),
+                None => return Err(reject(RejectKind::MissingInputs, format!("input {} is missing or already spent", outpoint))),
+            }
+        }
+        // a time-locked transaction waits outside until it may go in the next block
+        let next_height = utxo.blockchain.get_best_height()? + 1;
+        let tip_time = utxo.blockchain.median_time_past(&utxo.blockchain.current_hash)?;
+        if !tx.is_final(next_height, (tip_time / 1000) as u64) {
+            return Err(reject(RejectKind::NotFinal, format!("transaction {} is locked until {}", tx.id, tx.lock_time)));
+        }
+        let fee = tx.check_spent_amounts(&spent)?;
+        if !tx.verify_spent(&spent)? {
+            return Err(reject(RejectKind::InvalidSignature, format!("transaction {} has an invalid signature", tx.id)));
+        }
+
+        self.admit(tx, fee, weight, now, &conflicts)
+    }
+
+    // Admit inserts a validated tx in place of the pending transactions it
+    // conflicts with, then trims the mempool. When tx does not survive the
+    // trim the mempool is put back as it was, conflicts included
+    fn admit(&mut self, tx : Transaction, fee : Amount, weight : usize, now : u64, conflicts : &HashSet<String>) -> Result<Vec<Transaction>, Box<dyn std::error::Error>> {
+        let mut evicted = Vec::new();
+        if !conflicts.is_empty() {
+            self.check_replacement(&tx, fee, weight, conflicts)?;
+            for id in conflicts {
+                evicted.extend(self.take(id));
+            }
+        }
+
+        let txid = tx.id.clone();
+        self.insert_entry(tx, fee, weight, now);
+        evicted.extend(self.trim());
+        if evicted.iter().any(|e| e.tx.id == txid) {
+            self.restore(evicted.into_iter().filter(|e| e.tx.id != txid).collect());
+            return Err(reject(RejectKind::Fee, format!("mempool full, fee rate of {} too low", txid)));
+        }
+        for id in conflicts {
+            info!("mempool replaces {} by {}", id, txid);
+        }
+        info!("mempool accepted {} fee: {} weight: {}", txid, fee, weight);
+        Ok(evicted.into_iter().map(|e| e.tx).collect())
+    }
+
+    // CheckReplacement applies the replace-by-fee rules : every conflicting transaction
+    // opted in to replacement, tx pays a strictly higher fee rate than each of them and
+    // a strictly higher absolute fee than all the transactions it evicts together
+    fn check_replacement(&self, tx : &Transaction, fee : Amount, weight : usize, conflicts : &HashSet<String>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut evicted = HashSet::new();
+        for id in conflicts {
+            let entry = &self.entries[id];
+            if !entry.tx.signals_replaceable() {
+                return Err(reject(RejectKind::DoubleSpend, format!("conflicts with {} which is not replaceable", id)));
+            }
+            if cmp_fee_rate(fee, weight, entry.fee, entry.weight) != Ordering::Greater {
+                return Err(reject(RejectKind::Fee, format!("fee rate does not beat the one of {}", id)));
+            }
+            evicted.extend(self.descendants(id));
+        }
+        if let Some(vin) = tx.vin.iter().find(|vin| evicted.contains(&vin.txid)) {
+            return Err(reject(RejectKind::DoubleSpend, format!("spends {} which it would replace", vin.txid)));
+        }
+        let evicted_fee = Amount::checked_sum(evicted.iter().map(|id| self.entries[id].fee))
+            .ok_or_else(|| reject(RejectKind::Invalid, "fees of the replaced transactions out of range".to_string()))?;
+        if fee <= evicted_fee {
+            return Err(reject(RejectKind::Fee, format!("fee {} does not beat the {} paid by the {} replaced transactions", fee, evicted_fee, evicted.len())));
+        }
+        Ok(())
+    }
+
+    fn insert_entry(&mut self, tx : Transaction, fee : Amount, weight : usize, time : u64) {
+        let parents : HashSet<String> = tx.vin.iter()
+            .filter(|vin| self.entries.contains_key(&vin.txid))
+            .map(|vin| vin.txid.clone())
+            .collect();
+        for parent in &parents {
+            if let Some(p) = self.entries.get_mut(parent) {
+                p.children.insert(tx.id.clone());
+            }
+        }
+        for vin in &tx.vin {
+            self.spends.insert(vin.outpoint(), tx.id.clone());
+        }
+        self.total_weight += weight;
+        self.entries.insert(tx.id.clone(), MempoolEntry {
+            tx,
+            fee,
+            weight,
+            time,
+            parents,
+            children : HashSet::new(),
+        });
+    }
+
+    // remove a single entry, its children stay and simply lose a pending parent
+    fn remove_entry(&mut self, txid : &str) -> Option<MempoolEntry> {
+        let entry = self.entries.remove(txid)?;
+        for parent in &entry.parents {
+            if let Some(p) = self.entries.get_mut(parent) {
+                p.children.remove(txid);
+            }
+        }
+        for child in &entry.children {
+            if let Some(c) = self.entries.get_mut(child) {
+                c.parents.remove(txid);
+            }
+        }
+        for vin in &entry.tx.vin {
+            self.spends.remove(&vin.outpoint());
+        }
+        self.total_weight -= entry.weight;
+        Some(entry)
+    }
+
+    // Restore puts back entries taken out of the mempool, parents before children
+    fn restore(&mut self, mut entries : Vec<MempoolEntry>) {
+        while !entries.is_empty() {
+            let waiting_ids : HashSet<String> = entries.iter().map(|e| e.tx.id.clone()).collect();
+            let (ready, waiting) : (Vec<MempoolEntry>, Vec<MempoolEntry>) = entries.into_iter()
+                .partition(|e| !e.tx.vin.iter().any(|vin| waiting_ids.contains(&vin.txid)));
+            for e in ready {
+                self.insert_entry(e.tx, e.fee, e.weight, e.time);
+            }
+            entries = waiting;
+        }
+    }
+
+    // txid followed by every pending transaction that depends on it
+    fn descendants(&self, txid : &str) -> Vec<String> {
+        let mut found = vec![txid.to_string()];
+        let mut seen = HashSet::new();
+        let mut i = 0;
+        while i < found.len() {
+            if let Some(e) = self.entries.get(&found[i]) {
+                for child in &e.children {
+                    if seen.insert(child.clone()) {
+                        found.push(child.clone());
+                    }
+                }
+            }
+            i += 1;
+        }
+        found
+    }
+
+    // take out txid and every pending transaction that depends on it
+    fn take(&mut self, txid : &str) -> Vec<MempoolEntry> {
+        self.descendants(txid).iter().filter_map(|id| self.remove_entry(id)).collect()
+    }
+
+    /// Remove drops txid and every pending transaction that depends on it
+    pub fn remove(&mut self, txid : &str) -> Vec<Transaction> {
+        self.take(txid).into_iter().map(|e| e.tx).collect()
+    }
+
+    /// RemoveConfirmed drops the transactions included in a connected block and
+    /// returns the pending ones that conflicted with it, along with their descendants
+    pub fn remove_confirmed(&mut self, txs : &[Transaction]) -> Vec<Transaction> {
+        let mut conflicts = Vec::new();
+        for tx in txs {
+            self.remove_entry(&tx.id);
+            if tx.is_coinbase() {
+                continue;
+            }
+            for vin in &tx.vin {
+                if let Some(spender) = self.spends.get(&vin.outpoint()).cloned() {
+                    debug!("mempool transaction {} conflicts with confirmed {}", spender, tx.id);
+                    conflicts.extend(self.remove(&spender));
+                }
+            }
+        }
+        conflicts
+    }
+
+    /// Expire drops entries that waited longer than the expiry delay
+    pub fn expire(&mut self, now : u64) -> Vec<Transaction> {
+        let stale : Vec<String> = self.entries.values()
+            .filter(|e| now.saturating_sub(e.time) >= self.expiry)
+            .map(|e| e.tx.id.clone())
+            .collect();
+        stale.iter().flat_map(|id| self.remove(id)).collect()
+    }
+
+    // evict the lowest fee rate entries until the mempool fits its limits
+    fn trim(&mut self) -> Vec<MempoolEntry> {
+        let mut evicted = Vec::new();
+        while self.entries.len() > self.max_count || self.total_weight > self.max_weight {
+            let worst = match self.entries.values().min_by(|a, b| a.cmp_fee_rate(b)) {
+                Some(e) => e.tx.id.clone(),
+                None => break,
+            };
+            evicted.extend(self.take(&worst));
+        }
+        evicted
+    }
+
+    /// Save writes the pending transactions with their admission time to path,
+    /// parents before children so that Load can admit them back in order
+    pub fn save(&self, path : &str) -> Result<usize, Box<dyn std::error::Error>> {
+        let mut saved : Vec<(Transaction, u64)> = Vec::new();
+        let mut done = HashSet::new();
+        let mut entries : Vec<&MempoolEntry> = self.entries.values().collect();
+        entries.sort_by_key(|e| e.time);
+        loop {
+            let before = done.len();
+            for e in &entries {
+                if !done.contains(&e.tx.id) && e.parents.iter().all(|p| done.contains(p)) {
+                    done.insert(e.tx.id.clone());
+                    saved.push((e.tx.clone(), e.time));
+                }
+            }
+            if done.len() == before {
+                break;
+            }
+        }
+        // write then rename so a crash never leaves a truncated file behind
+        let tmp_path = format!("{}.new", path);
+        std::fs::write(&tmp_path, bincode::serialize(&saved)?)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(saved.len())
+    }
+
+    /// Load admits back the transactions saved at path, each one is validated
+    /// again against the current UTXO set and dropped if it became invalid
+    pub fn load(&mut self, path : &str, utxo : &UTXOSet, now : u64) -> Result<usize, Box<dyn std::error::Error>> {
+        let mut loaded = 0;
+        for (tx, time) in read_saved(path)? {
+            let txid = tx.id.clone();
+            if now.saturating_sub(time) >= self.expiry {
+                info!("mempool drops saved transaction {}: expired", txid);
+                continue;
+            }
+            match self.add(tx, utxo, time) {
+                Ok(_) => loaded += 1,
+                Err(e) => warn!("mempool drops saved transaction {}: {}", txid, e),
+            }
+        }
+        Ok(loaded)
+    }
+
+    /// SelectForBlock picks transactions by decreasing fee rate within max_weight,
+    /// a transaction is only picked once all its pending parents are, so the
+    /// returned list is in a valid order for a block. Also returns the total fees.
+    pub fn select_for_block(&self, max_weight : usize) -> (Vec<Transaction>, Amount) {
+        let mut candidates : Vec<&MempoolEntry> = self.entries.values().collect();
+        candidates.sort_by(|a, b| b.cmp_fee_rate(a).then(a.time.cmp(&b.time)).then(a.tx.id.cmp(&b.tx.id)));
+
+        let mut selected = HashSet::new();
+        let mut txs = Vec::new();
+        let mut weight = 0;
+        let mut fees = Amount::ZERO;
+        loop {
+            let next = candidates.iter().find(|e| {
+                !selected.contains(&e.tx.id)
+                    && weight + e.weight <= max_weight
+                    && e.parents.iter().all(|p| selected.contains(p))
+            });
+            let entry = match next {
+                Some(e) => e,
+                None => break,
+            };
+            fees = match fees.checked_add(entry.fee) {
+                Some(f) => f,
+                None => break,
+            };
+            selected.insert(entry.tx.id.clone());
+            weight += entry.weight;
+            txs.push(entry.tx.clone());
+        }
+        (txs, fees)
+    }
+}
+
+// ReadSaved returns the transactions written by Save with their admission
+// time, in the saved order, or nothing when there is no file at path
+fn read_saved(path : &str) -> Result<Vec<(Transaction, u64)>, Box<dyn std::error::Error>> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(bincode::deserialize(&data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tx::{TXInput, TXOutput};
+
+    fn new_tx(id : &str, inputs : Vec<(&str, i32)>) -> Transaction {
+        Transaction {
+            id : id.to_string(),
+            vin : inputs.into_iter().map(|(txid, vout)| TXInput {
+                txid : txid.to_string(),
+                vout,
+                signature : Vec::new(),
+                pub_key : Vec::new(),
+                sequence : MAX_REPLACEABLE_SEQUENCE,
+            }).collect(),
+            vout : vec![TXOutput {
+                value : Amount::from_units(1_000),
+                pub_key_hash : Vec::new(),
+            }],
+            lock_time : 0,
+        }
+    }
+
+    fn insert(pool : &mut Mempool, tx : Transaction, fee : u64) {
+        let weight = tx.weight().unwrap();
+        pool.insert_entry(tx, Amount::from_units(fee), weight, 0);
+    }
+
+    #[test]
+    fn test_select_orders_by_fee_rate_after_parents() {
+        let mut pool = Mempool::new();
+        insert(&mut pool, new_tx("parent", vec![("confirmed", 0)]), 1);
+        insert(&mut pool, new_tx("child", vec![("parent", 0)]), 1_000);
+        insert(&mut pool, new_tx("other", vec![("confirmed", 1)]), 100);
+
+        let (txs, fees) = pool.select_for_block(MAX_BLOCK_WEIGHT);
+        let ids : Vec<&str> = txs.iter().map(|t| t.id.as_str()).collect();
+        assert_eq!(ids, vec!["other", "parent", "child"]);
+        assert_eq!(fees, Amount::from_units(1_101));
+    }
+
+    #[test]
+    fn test_remove_confirmed_evicts_conflicts_and_descendants() {
+        let mut pool = Mempool::new();
+        insert(&mut pool, new_tx("a", vec![("confirmed", 0)]), 10);
+        insert(&mut pool, new_tx("b", vec![("a", 0)]), 10);
+        insert(&mut pool, new_tx("c", vec![("confirmed", 1)]), 10);
+
+        let block_tx = new_tx("d", vec![("confirmed", 0)]);
+        let conflicts = pool.remove_confirmed(&[block_tx]);
+        assert_eq!(conflicts.len(), 2);
+        assert_eq!(pool.len(), 1);
+        assert!(pool.contains("c"));
+        assert!(!pool.spends.contains_key(&OutPoint { txid : "confirmed".to_string(), vout : 0 }));
+    }
+
+    #[test]
+    fn test_replacement_rules() {
+        let mut pool = Mempool::new();
+        insert(&mut pool, new_tx("original", vec![("confirmed", 0)]), 100);
+        insert(&mut pool, new_tx("child", vec![("original", 0)]), 100);
+        let conflicts = HashSet::from(["original".to_string()]);
+        let replacement = new_tx("replacement", vec![("confirmed", 0)]);
+        let weight = replacement.weight().unwrap();
+
+        // must pay more than the original and its child together
+        assert!(pool.check_replacement(&replacement, Amount::from_units(150), weight, &conflicts).is_err());
+        assert!(pool.check_replacement(&replacement, Amount::from_units(201), weight, &conflicts).is_ok());
+
+        let mut final_tx = new_tx("final", vec![("confirmed", 1)]);
+        final_tx.vin[0].sequence = SEQUENCE_FINAL;
+        insert(&mut pool, final_tx, 1);
+        let conflicts = HashSet::from(["final".to_string()]);
+        let replacement = new_tx("replacement", vec![("confirmed", 1)]);
+        assert!(pool.check_replacement(&replacement, Amount::from_units(1_000), weight, &conflicts).is_err());
+    }
+
+    #[test]
+    fn test_trim_evicts_lowest_fee_rate() {
+        let mut pool = Mempool::with_limits(MEMPOOL_MAX_WEIGHT, 2, MEMPOOL_EXPIRY_SECS);
+        insert(&mut pool, new_tx("low", vec![("confirmed", 0)]), 1);
+        insert(&mut pool, new_tx("high", vec![("confirmed", 1)]), 100);
+        insert(&mut pool, new_tx("mid", vec![("confirmed", 2)]), 50);
+
+        let evicted = pool.trim();
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].tx.id, "low");
+        assert_eq!(pool.expire(MEMPOOL_EXPIRY_SECS).len(), 2);
+    }
+
+    #[test]
+    fn test_replacement_trimmed_from_full_pool() {
+        let original = new_tx("original", vec![("confirmed", 0)]);
+        let child = new_tx("child", vec![("original", 0)]);
+        let high = new_tx("high", vec![("confirmed", 1)]);
+        let replaced = original.weight().unwrap() + child.weight().unwrap();
+        let full = replaced + high.weight().unwrap();
+        let mut pool = Mempool::with_limits(full, MEMPOOL_MAX_COUNT, MEMPOOL_EXPIRY_SECS);
+        insert(&mut pool, original, 100);
+        insert(&mut pool, child, 100);
+        insert(&mut pool, high, 1_000_000);
+
+        // the replacement is heavier than what it evicts and has the lowest
+        // fee rate : it is trimmed and the transactions it replaced come back
+        let conflicts = HashSet::from(["original".to_string()]);
+        let mut replacement = new_tx("replacement", vec![("confirmed", 0)]);
+        while replacement.weight().unwrap() <= replaced {
+            replacement.vout.push(replacement.vout[0].clone());
+        }
+        let weight = replacement.weight().unwrap();
+        assert!(pool.admit(replacement.clone(), Amount::from_units(1_000), weight, 0, &conflicts).is_err());
+        assert_eq!(pool.len(), 3);
+        assert_eq!(pool.total_weight(), full);
+        assert!(pool.get_entry("original").unwrap().children.contains("child"));
+        assert_eq!(pool.spends[&OutPoint { txid : "confirmed".to_string(), vout : 0 }], "original");
+
+        // paying enough it pushes out the high fee transaction instead
+        let evicted = pool.admit(replacement, Amount::from_units(10_000_000), weight, 0, &conflicts).unwrap();
+        let mut ids : Vec<String> = evicted.into_iter().map(|t| t.id).collect();
+        ids.sort();
+        assert_eq!(ids, vec!["child", "high", "original"]);
+        assert!(pool.contains("replacement"));
+    }
+
+    #[test]
+    fn test_save_and_read_back() {
+        let mut pool = Mempool::new();
+        let parent = new_tx("parent", vec![("confirmed", 0)]);
+        let child = new_tx("child", vec![("parent", 0)]);
+        let other = new_tx("other", vec![("confirmed", 1)]);
+        // restored entries keep their admission time, a child may be older than its parent
+        for (tx, time) in [(parent, 20), (child, 10), (other, 15)] {
+            let weight = tx.weight().unwrap();
+            pool.insert_entry(tx, Amount::from_units(10), weight, time);
+        }
+
+        let path = std::env::temp_dir().join(format!("mempool-{}.dat", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert_eq!(pool.save(path).unwrap(), 3);
+        let saved : Vec<(String, u64)> = read_saved(path).unwrap().into_iter().map(|(tx, time)| (tx.id, time)).collect();
+        assert_eq!(saved, vec![("other".to_string(), 15), ("parent".to_string(), 20), ("child".to_string(), 10)]);
+        std::fs::remove_file(path).unwrap();
+        assert!(read_saved(path).unwrap().is_empty());
+    }
+}
@@ -1,16 +1,26 @@
 use serde::{Deserialize, Serialize};
 use crate::block::*;
 use crate::transaction::*;
+use crate::constants::*;
 
+// Message is both what peers request and what they answer on the response
+// channel : Version is answered by a Version, GetBlock by an Inv of the
+// block hashes following its locator, GetData by a Block, a Tx or NotFound,
+// and the relayed Tx, Block and Inv by an Ack or a Reject
 #[derive(Serialize,Deserialize, Debug,Clone)]
 pub enum Message {
     //Addr(Vec<String>), 
     Version(Versionmsg),
     Tx(Txmsg),
     GetData(GetDatamsg),
-    GetBlock,
+    GetBlock(GetBlocksmsg),
     Inv(Invmsg),
-    Block(Blockmsg)
+    Block(Blockmsg),
+    Reject(Rejectmsg),
+    // the request was processed, nothing comes back
+    Ack,
+    // the object asked by a GetData is unknown to the peer
+    NotFound(GetDatamsg)
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -23,19 +33,86 @@ pub struct GetDatamsg{
     pub id : String,
 }
 
+// asks for the active chain blocks after the first locator hash the peer
+// knows, the locator goes from the tip of the sender back to its genesis
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GetBlocksmsg{
+    pub locator : Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Invmsg{
     pub kind : String,
     pub items : Vec<String>,
 }
 
+// answers the peer that relayed an object we refused
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rejectmsg{
+    pub kind : String,
+    pub id : String,
+    pub reason : String,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Txmsg{
     pub transaction : Transaction
 }
 
+// the first message on a connection, the dialer sends it and the other
+// side answers with its own : peers only talk once both were accepted
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Versionmsg{
     pub version : i32,
-    pub best_height : i32
+    // genesis block hash of the chain the sender follows
+    pub network : String,
+    // NODE_* bits of what the sender offers
+    pub services : u64,
+    pub user_agent : String,
+    pub best_height : i32,
+    // clock of the sender, UNIX time in seconds
+    pub timestamp : u64,
+    // random for each run of a node, receiving our own one means we
+    // connected to ourselves
+    pub nonce : u64,
+}
+
+impl Versionmsg {
+    // Check tells why a peer sending this version can not be talked to, if
+    // it can not
+    pub fn check(&self, genesis_hash : &str, local_nonce : u64) -> Result<(), String> {
+        if self.version < MIN_PEER_PROTOCOL_VERSION {
+            return Err(format!("protocol version {} is older than {}", self.version, MIN_PEER_PROTOCOL_VERSION));
+        }
+        if self.network != genesis_hash {
+            return Err(format!("it follows the chain of genesis {}", self.network));
+        }
+        if self.nonce == local_nonce {
+            return Err("connected to ourselves".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_check() {
+        let version = Versionmsg {
+            version : PROTOCOL_VERSION,
+            network : "00ab".to_string(),
+            services : NODE_NETWORK,
+            user_agent : "/test/".to_string(),
+            best_height : 0,
+            timestamp : 0,
+            nonce : 7,
+        };
+        assert!(version.check("00ab", 8).is_ok());
+        assert!(version.check("00cd", 8).unwrap_err().contains("genesis 00ab"));
+        assert_eq!(version.check("00ab", 7).unwrap_err(), "connected to ourselves");
+        let old = Versionmsg { version : MIN_PEER_PROTOCOL_VERSION - 1, ..version };
+        assert!(old.check("00ab", 8).is_err());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::validation::reject_kind_of;
+
+// upper bounds of the signature verification latency buckets, in seconds
+const VERIFY_BUCKETS : [f64; 8] = [0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01];
+
+// Metrics are the counters the node keeps for the Prometheus /metrics
+// page, the gauges such as the chain height are read when it is scraped
+pub struct Metrics {
+    blocks_received : AtomicU64,
+    txs_received : AtomicU64,
+    // (block or tx, reason) -> count
+    rejected : Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    hashes : AtomicU64,
+    // signature scheme -> latency histogram
+    verify : Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+struct Histogram {
+    // one count per bucket, not cumulative
+    buckets : [u64; VERIFY_BUCKETS.len()],
+    count : u64,
+    sum : f64,
+}
+
+pub static METRICS : Metrics = Metrics {
+    blocks_received : AtomicU64::new(0),
+    txs_received : AtomicU64::new(0),
+    rejected : Mutex::new(BTreeMap::new()),
+    hashes : AtomicU64::new(0),
+    verify : Mutex::new(BTreeMap::new()),
+};
+
+impl Metrics {
+    pub fn block_received(&self) {
+        self.blocks_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn tx_received(&self) {
+        self.txs_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Rejected counts a block or transaction refused for `error`, by the
+    // kind of the rejection
+    pub fn rejected(&self, kind : &'static str, error : &(dyn std::error::Error + 'static)) {
+        let reason = reject_kind_of(error).map_or("other", |k| k.label());
+        *self.rejected.lock().unwrap().entry((kind, reason)).or_insert(0) += 1;
+    }
+
+    pub fn hashes(&self, hashes : u64) {
+        self.hashes.fetch_add(hashes, Ordering::Relaxed);
+    }
+
+    // SignatureVerified records how long checking one signature took
+    pub fn signature_verified(&self, scheme : &'static str, elapsed : Duration) {
+        let secs = elapsed.as_secs_f64();
+        let mut verify = self.verify.lock().unwrap();
+        let histogram = verify.entry(scheme).or_insert(Histogram { buckets : [0; VERIFY_BUCKETS.len()], count : 0, sum : 0.0 });
+        if let Some(i) = VERIFY_BUCKETS.iter().position(|bound| secs <= *bound) {
+            histogram.buckets[i] += 1;
+        }
+        histogram.count += 1;
+        histogram.sum += secs;
+    }
+
+    // Render appends the counters in the Prometheus text format
+    pub fn render(&self, out : &mut String) {
+        counter(out, "node_blocks_received_total", "blocks received from peers", self.blocks_received.load(Ordering::Relaxed));
+        counter(out, "node_transactions_received_total", "transactions received from peers", self.txs_received.load(Ordering::Relaxed));
+        header(out, "node_rejected_total", "counter", "blocks and transactions refused, by reason");
+        for ((kind, reason), count) in self.rejected.lock().unwrap().iter() {
+            sample(out, "node_rejected_total", &[("kind", kind), ("reason", reason)], *count as f64);
+        }
+        counter(out, "node_mining_hashes_total", "proof of work hashes tried, its rate is the hashrate", self.hashes.load(Ordering::Relaxed));
+        header(out, "node_signature_verify_seconds", "histogram", "time spent verifying one input signature");
+        for (scheme, histogram) in self.verify.lock().unwrap().iter() {
+            let mut cumulative = 0;
+            for (bound, count) in VERIFY_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                sample(out, "node_signature_verify_seconds_bucket", &[("scheme", scheme), ("le", &bound.to_string())], cumulative as f64);
+            }
+            sample(out, "node_signature_verify_seconds_bucket", &[("scheme", scheme), ("le", "+Inf")], histogram.count as f64);
+            sample(out, "node_signature_verify_seconds_sum", &[("scheme", scheme)], histogram.sum);
+            sample(out, "node_signature_verify_seconds_count", &[("scheme", scheme)], histogram.count as f64);
+        }
+    }
+}
+
+// Header starts a metric family with its HELP and TYPE lines
+pub fn header(out : &mut String, name : &str, kind : &str, help : &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// Sample writes one value of a metric family
+pub fn sample(out : &mut String, name : &str, labels : &[(&str, &str)], value : f64) {
+    let _ = write!(out, "{}", name);
+    if !labels.is_empty() {
+        let labels : Vec<String> = labels.iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+            .collect();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+pub fn gauge(out : &mut String, name : &str, help : &str, value : f64) {
+    header(out, name, "gauge", help);
+    sample(out, name, &[], value);
+}
+
+fn counter(out : &mut String, name : &str, help : &str, value : u64) {
+    header(out, name, "counter", help);
+    sample(out, name, &[], value as f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::{reject, RejectKind};
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics {
+            blocks_received : AtomicU64::new(3),
+            txs_received : AtomicU64::new(0),
+            rejected : Mutex::new(BTreeMap::new()),
+            hashes : AtomicU64::new(0),
+            verify : Mutex::new(BTreeMap::new()),
+        };
+        metrics.rejected("tx", reject(RejectKind::DoubleSpend, "input x:0 spent twice".to_string()).as_ref());
+        metrics.rejected("block", Box::<dyn std::error::Error>::from("something else").as_ref());
+        metrics.signature_verified("ed25519", Duration::from_micros(80));
+        metrics.signature_verified("ed25519", Duration::from_secs(1));
+        let mut out = String::new();
+        metrics.render(&mut out);
+        assert!(out.contains("# TYPE node_blocks_received_total counter\nnode_blocks_received_total 3\n"));
+        assert!(out.contains("node_rejected_total{kind=\"tx\",reason=\"double_spend\"} 1\n"));
+        assert!(out.contains("node_rejected_total{kind=\"block\",reason=\"other\"} 1\n"));
+        assert!(out.contains("node_signature_verify_seconds_bucket{scheme=\"ed25519\",le=\"0.00005\"} 0\n"));
+        assert!(out.contains("node_signature_verify_seconds_bucket{scheme=\"ed25519\",le=\"0.0001\"} 1\n"));
+        assert!(out.contains("node_signature_verify_seconds_bucket{scheme=\"ed25519\",le=\"+Inf\"} 2\n"));
+        assert!(out.contains("node_signature_verify_seconds_count{scheme=\"ed25519\"} 2\n"));
+    }
+}
@@ -0,0 +1,324 @@
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::metrics::METRICS;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+use tokio::sync::mpsc::UnboundedSender;
+use log::{debug, info, error};
+
+// nonces a worker tries between two looks at the stop flags
+const CHECK_INTERVAL : u64 = 4096;
+// seconds between two hashrate reports of a running job
+const REPORT_INTERVAL_SECS : u64 = 10;
+
+// Miner runs the proof of work of one block template at a time on its own
+// threads, so the network event loop keeps answering peers while mining.
+// Solved blocks are handed back to the server through `found`.
+pub struct Miner {
+    found : UnboundedSender<Block>,
+    threads : usize,
+    job : Option<(Arc<AtomicBool>, JoinHandle<()>)>,
+}
+
+impl Miner {
+    pub fn new(found : UnboundedSender<Block>) -> Miner {
+        let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        Miner { found, threads, job : None }
+    }
+
+    // Start drops the running job, if any, and starts mining `template`
+    pub fn start(&mut self, template : Block) {
+        self.cancel();
+        info!(
+            "start mining block at height {} on top of {} with {} threads",
+            template.get_height(), template.get_prev_hash(), self.threads
+        );
+        let cancel = Arc::new(AtomicBool::new(false));
+        let flag = cancel.clone();
+        let found = self.found.clone();
+        let threads = self.threads;
+        let handle = thread::spawn(move || {
+            let mut block = template;
+            let progress = Progress::new();
+            match mine(&mut block, threads, u64::MAX, &flag, &progress) {
+                Ok(true) => {
+                    info!(
+                        "found block {} after {} hashes in {:.1}s ({:.0} H/s)",
+                        block.get_hash(), progress.hashes(), progress.started.elapsed().as_secs_f64(), progress.hashrate()
+                    );
+                    // the receiver is gone only when the server is shutting down
+                    let _ = found.send(block);
+                }
+                Ok(false) => debug!("mining of block at height {} cancelled", block.get_height()),
+                Err(e) => error!("mining of block at height {} failed: {}", block.get_height(), e),
+            }
+        });
+        self.job = Some((cancel, handle));
+    }
+
+    // Cancel asks the running job to stop, it does not wait for the threads
+    pub fn cancel(&mut self) {
+        if let Some((cancel, _)) = self.job.take() {
+            cancel.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // IsMining tells whether a job is still searching a nonce
+    pub fn is_mining(&self) -> bool {
+        match &self.job {
+            Some((_, handle)) => !handle.is_finished(),
+            None => false,
+        }
+    }
+}
+
+impl Drop for Miner {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+// MiningPolicy decides when the node mines a template and who its coinbase pays
+#[derive(Debug, Clone)]
+pub struct MiningPolicy {
+    pub enabled : bool,
+    // a template is worth mining once it holds this many transactions ...
+    pub min_txs : usize,
+    // ... or pays at least this much in fees, zero disables the fee trigger
+    pub min_fees : Amount,
+    // once the tip is this many seconds old whatever is pending is mined,
+    // even an empty block, zero disables it
+    pub max_block_interval : u64,
+    // blocks pay these addresses in turn
+    pub payout_addresses : Vec<String>,
+    next_payout : usize,
+}
+
+impl MiningPolicy {
+    // New keeps the historical behaviour : mine blocks of at least two
+    // transactions for `address`, if there is one
+    pub fn new(address : &str) -> MiningPolicy {
+        let payout_addresses : Vec<String> = Some(address.to_string()).into_iter().filter(|a| !a.is_empty()).collect();
+        MiningPolicy {
+            enabled : !payout_addresses.is_empty(),
+            min_txs : 2,
+            min_fees : Amount::ZERO,
+            max_block_interval : 0,
+            payout_addresses,
+            next_payout : 0,
+        }
+    }
+
+    // PayoutAddress is the address the next block pays, None if there is none
+    pub fn payout_address(&self) -> Option<&str> {
+        if self.payout_addresses.is_empty() {
+            return None;
+        }
+        Some(&self.payout_addresses[self.next_payout % self.payout_addresses.len()])
+    }
+
+    // RotatePayout moves on to the next payout address after a block was found
+    pub fn rotate_payout(&mut self) {
+        self.next_payout = self.next_payout.wrapping_add(1);
+    }
+
+    // ShouldMine tells whether a template with `txs` transactions besides the
+    // coinbase and `fees` in total is worth mining on a tip `tip_age` seconds old
+    pub fn should_mine(&self, txs : usize, fees : Amount, tip_age : u64) -> bool {
+        if !self.enabled || self.payout_addresses.is_empty() {
+            return false;
+        }
+        if self.interval_elapsed(tip_age) {
+            return true;
+        }
+        txs > 0 && (txs >= self.min_txs || (self.min_fees > Amount::ZERO && fees >= self.min_fees))
+    }
+
+    // IntervalElapsed tells whether the tip is old enough to mine anyway
+    pub fn interval_elapsed(&self, tip_age : u64) -> bool {
+        self.enabled && self.max_block_interval > 0 && tip_age >= self.max_block_interval
+    }
+}
+
+// Progress counts the hashes of one job for the hashrate reports
+pub struct Progress {
+    started : Instant,
+    hashes : AtomicU64,
+    next_report : AtomicU64,
+}
+
+impl Progress {
+    pub fn new() -> Progress {
+        Progress {
+            started : Instant::now(),
+            hashes : AtomicU64::new(0),
+            next_report : AtomicU64::new(REPORT_INTERVAL_SECS),
+        }
+    }
+
+    pub fn hashes(&self) -> u64 {
+        self.hashes.load(Ordering::Relaxed)
+    }
+
+    // Hashrate is the average number of hashes per second since the job started
+    pub fn hashrate(&self) -> f64 {
+        let secs = self.started.elapsed().as_secs_f64();
+        if secs > 0.0 { self.hashes() as f64 / secs } else { 0.0 }
+    }
+
+    fn add(&self, hashes : u64) {
+        self.hashes.fetch_add(hashes, Ordering::Relaxed);
+        METRICS.hashes(hashes);
+        // whichever worker crosses the deadline first writes the report
+        let elapsed = self.started.elapsed().as_secs();
+        let next = self.next_report.load(Ordering::Relaxed);
+        if elapsed >= next && self.next_report
+            .compare_exchange(next, elapsed + REPORT_INTERVAL_SECS, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            info!("mining: {} hashes in {}s, {:.0} H/s", self.hashes(), elapsed, self.hashrate());
+        }
+    }
+}
+
+// Mine searches a nonce for the block with `threads` workers. Every
+// extra-nonce of the coinbase gives `nonce_space` nonces, split in one
+// contiguous range per worker; once they are all tried the extra-nonce
+// is rolled. It returns false when `cancel` is set.
+pub fn mine(block : &mut Block, threads : usize, nonce_space : u64, cancel : &AtomicBool, progress : &Progress) -> Result<bool,Box<dyn std::error::Error>> {
+    let mut extra_nonce = 0u64;
+    loop {
+        let prefix = block.header_prefix()?;
+        if let Some(nonce) = search(&prefix, threads, nonce_space, cancel, progress) {
+            block.set_nonce(nonce)?;
+            return Ok(true);
+        }
+        if cancel.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+        extra_nonce += 1;
+        debug!("nonce space exhausted, roll the extra-nonce to {}", extra_nonce);
+        block.set_extra_nonce(extra_nonce)?;
+    }
+}
+
+// Search runs the workers over [0, nonce_space) and returns the first nonce found
+fn search(prefix : &[u8], threads : usize, nonce_space : u64, cancel : &AtomicBool, progress : &Progress) -> Option<u64> {
+    let threads = threads.max(1) as u64;
+    let chunk = nonce_space / threads;
+    let stop = AtomicBool::new(false);
+    let result = Mutex::new(None);
+    thread::scope(|scope| {
+        for i in 0..threads {
+            let start = i * chunk;
+            // the last worker also takes the remainder of the division
+            let end = if i == threads - 1 { nonce_space } else { start + chunk };
+            let (stop, result) = (&stop, &result);
+            scope.spawn(move || work(prefix, start..end, cancel, stop, result, progress));
+        }
+    });
+    result.into_inner().unwrap()
+}
+
+// Work tries the nonces of `range` until one meets the target or another
+// worker found one or the job is cancelled, the flags are looked at before
+// every batch so a cancelled job never hashes
+fn work(prefix : &[u8], range : Range<u64>, cancel : &AtomicBool, stop : &AtomicBool, result : &Mutex<Option<u64>>, progress : &Progress) {
+    let mut nonce = range.start;
+    while nonce < range.end {
+        if stop.load(Ordering::Relaxed) || cancel.load(Ordering::Relaxed) {
+            return;
+        }
+        let batch_end = nonce.saturating_add(CHECK_INTERVAL).min(range.end);
+        for n in nonce..batch_end {
+            if Block::meets_target(&Block::hash_header(prefix, n)) {
+                progress.add(n - nonce + 1);
+                if !stop.swap(true, Ordering::Relaxed) {
+                    *result.lock().unwrap() = Some(n);
+                }
+                return;
+            }
+        }
+        progress.add(batch_end - nonce);
+        nonce = batch_end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::constants::SEQUENCE_FINAL;
+    use crate::transaction::Transaction;
+    use crate::tx::{TXInput, TXOutput};
+
+    fn template() -> Block {
+        let mut coinbase = Transaction {
+            id : String::new(),
+            vin : vec![TXInput {
+                txid : String::new(),
+                vout : -1,
+                signature : Vec::new(),
+                pub_key : 0u64.to_le_bytes().to_vec(),
+                sequence : SEQUENCE_FINAL,
+            }],
+            vout : vec![TXOutput {
+                value : Amount::from_units(1_000),
+                pub_key_hash : Vec::new(),
+            }],
+            lock_time : 0,
+        };
+        coinbase.id = coinbase.hash().unwrap();
+        Block::new_template(vec![coinbase], String::from("prev"), 1).unwrap()
+    }
+
+    #[test]
+    fn test_mine_rolls_extra_nonce() {
+        let mut block = template();
+        let coinbase_id = block.get_transaction()[0].id.clone();
+        // a tiny nonce space per extra-nonce forces the coinbase to be rolled
+        let progress = Progress::new();
+        assert!(mine(&mut block, 3, 10, &AtomicBool::new(false), &progress).unwrap());
+        assert!(block.check_proof_of_work().unwrap());
+        assert_ne!(block.get_transaction()[0].id, coinbase_id);
+        assert!(progress.hashes() > 10);
+    }
+
+    #[test]
+    fn test_mining_policy() {
+        let mut policy = MiningPolicy::new("a");
+        assert!(!policy.should_mine(1, Amount::ZERO, 0));
+        assert!(policy.should_mine(2, Amount::ZERO, 0));
+
+        policy.min_txs = 10;
+        policy.min_fees = Amount::from_units(500);
+        assert!(!policy.should_mine(1, Amount::from_units(499), 0));
+        assert!(policy.should_mine(1, Amount::from_units(500), 0));
+
+        // an old tip gets an empty block
+        policy.max_block_interval = 60;
+        assert!(!policy.should_mine(0, Amount::ZERO, 59));
+        assert!(policy.should_mine(0, Amount::ZERO, 60));
+
+        policy.enabled = false;
+        assert!(!policy.should_mine(10, Amount::from_units(500), 60));
+        assert!(!MiningPolicy::new("").should_mine(10, Amount::ZERO, 0));
+
+        policy.payout_addresses.push("b".to_string());
+        assert_eq!(policy.payout_address(), Some("a"));
+        policy.rotate_payout();
+        assert_eq!(policy.payout_address(), Some("b"));
+        policy.rotate_payout();
+        assert_eq!(policy.payout_address(), Some("a"));
+    }
+
+    #[test]
+    fn test_mine_cancelled() {
+        let mut block = template();
+        assert!(!mine(&mut block, 2, u64::MAX, &AtomicBool::new(true), &Progress::new()).unwrap());
+        assert!(block.get_hash().is_empty());
+    }
+}
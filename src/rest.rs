@@ -0,0 +1,128 @@
+use std::collections::HashSet;
+use std::time::Duration;
+use crate::chainparams::params;
+use crate::constants::EVENTS_KEEPALIVE_SECS;
+use crate::events::NodeEvent;
+use crate::http::{query_params, write_response, write_stream_head, HttpRequest};
+use crate::rpc::{self, RpcCall, RpcError};
+use log::debug;
+use serde_json::{json, Value};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc};
+
+// The REST explorer answers GET requests under /rest/ on the JSON-RPC port :
+//
+//   /rest/chaininfo                 network, height and tip of the active chain
+//   /rest/tip                       hash, height and time of the tip block
+//   /rest/block/<hash>              a block with its transactions
+//   /rest/block/height/<height>     the active chain block at height
+//   /rest/tx/<txid>                 a mempool or confirmed transaction
+//   /rest/address/<address>/txs     confirmed and pending transactions of an address
+//   /rest/address/<address>/utxos   unspent outputs and balance of an address
+//   /rest/mempool                   the pending transactions
+//   /rest/events[?address=..]       a text/event-stream of the node events,
+//                                   only those touching the addresses if any
+//
+// the data is public so no credentials are asked for, errors come back as
+// {"error": message} with a 4xx/5xx status
+
+// Route maps a REST path to the node call answering it
+pub fn route(path : &str) -> Result<(&'static str, Value), RpcError> {
+    let segments : Vec<&str> = path.trim_start_matches('/').trim_end_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["rest", "chaininfo"] => Ok(("rest.chaininfo", json!([]))),
+        ["rest", "tip"] => Ok(("rest.tip", json!([]))),
+        ["rest", "block", "height", height] => {
+            let height : i32 = height.parse()
+                .map_err(|_| RpcError::new(rpc::INVALID_PARAMS, &format!("invalid height {}", height)))?;
+            Ok(("rest.blockbyheight", json!([height])))
+        }
+        ["rest", "block", hash] => Ok(("rest.block", json!([hash]))),
+        ["rest", "tx", txid] => Ok(("rest.tx", json!([txid]))),
+        ["rest", "address", address, "txs"] => Ok(("rest.addresstxs", json!([address]))),
+        ["rest", "address", address, "utxos"] => Ok(("rest.addressutxos", json!([address]))),
+        ["rest", "mempool"] => Ok(("rest.mempool", json!([]))),
+        _ => Err(RpcError::new(rpc::NOT_FOUND, &format!("no REST endpoint {}", path))),
+    }
+}
+
+// Status is the HTTP status reporting an error of the node
+fn status(error : &RpcError) -> u16 {
+    match error.code {
+        rpc::NOT_FOUND => 404,
+        rpc::INVALID_PARAMS => 400,
+        _ => 500,
+    }
+}
+
+// Handle answers one REST request through the node event loop
+pub async fn handle(stream : &mut TcpStream, request : &HttpRequest, calls : &mpsc::Sender<RpcCall>, events : &broadcast::Sender<NodeEvent>) -> Result<(),Box<dyn std::error::Error>> {
+    if request.method != "GET" {
+        return write_response(stream, 405, "application/json", json!({ "error" : "REST expects GET" }).to_string().as_bytes()).await;
+    }
+    let path = request.path.split('?').next().unwrap_or_default();
+    if path.trim_end_matches('/') == "/rest/events" {
+        return stream_events(stream, &request.path, events.subscribe()).await;
+    }
+    let result = match route(path) {
+        Ok((method, params)) => rpc::forward(method.to_string(), params, calls).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(value) => write_response(stream, 200, "application/json", value.to_string().as_bytes()).await,
+        Err(e) => write_response(stream, status(&e), "application/json", json!({ "error" : e.message }).to_string().as_bytes()).await,
+    }
+}
+
+// StreamEvents pushes the node events to the client until it hangs up
+async fn stream_events(stream : &mut TcpStream, path : &str, mut events : broadcast::Receiver<NodeEvent>) -> Result<(),Box<dyn std::error::Error>> {
+    let mut filter = HashSet::new();
+    for (name, value) in query_params(path) {
+        if name != "address" {
+            continue;
+        }
+        // several addresses may also be given comma separated
+        for address in value.split(',') {
+            match params().decode_address(address).map_err(|e| e.to_string()) {
+                Ok(pub_key_hash) => filter.insert(pub_key_hash),
+                Err(e) => return write_response(stream, 400, "application/json", json!({ "error" : e }).to_string().as_bytes()).await,
+            };
+        }
+    }
+    write_stream_head(stream, "text/event-stream").await?;
+    debug!("event subscriber {:?} watching {} addresses", stream.peer_addr(), filter.len());
+    let mut keepalive = tokio::time::interval(Duration::from_secs(EVENTS_KEEPALIVE_SECS));
+    loop {
+        let message = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) if event.matches(&filter) => event.to_sse(),
+                Ok(_) => continue,
+                // the subscriber fell behind, it has to resync from the REST calls
+                Err(broadcast::error::RecvError::Lagged(missed)) => format!("event: lagged\ndata: {}\n\n", json!({ "type" : "lagged", "missed" : missed })),
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            _ = keepalive.tick() => ": keepalive\n\n".to_string(),
+        };
+        if stream.write_all(message.as_bytes()).await.is_err() {
+            debug!("event subscriber {:?} left", stream.peer_addr());
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route() {
+        assert_eq!(route("/rest/tip").unwrap().0, "rest.tip");
+        assert_eq!(route("/rest/block/00ab/").unwrap(), ("rest.block", json!(["00ab"])));
+        assert_eq!(route("/rest/block/height/7").unwrap(), ("rest.blockbyheight", json!([7])));
+        assert_eq!(route("/rest/address/1abc/utxos").unwrap(), ("rest.addressutxos", json!(["1abc"])));
+        assert_eq!(route("/rest/block/height/x").unwrap_err().code, rpc::INVALID_PARAMS);
+        assert_eq!(route("/rest/address/1abc").unwrap_err().code, rpc::NOT_FOUND);
+        assert_eq!(route("/rest/").unwrap_err().code, rpc::NOT_FOUND);
+    }
+}
@@ -0,0 +1,306 @@
+use crate::amount::Amount;
+use crate::events::NodeEvent;
+use crate::http::{read_request, write_response};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use failure::format_err;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+// JSON-RPC 2.0 error codes, the application ones follow bitcoind
+pub const PARSE_ERROR : i64 = -32700;
+pub const INVALID_REQUEST : i64 = -32600;
+pub const METHOD_NOT_FOUND : i64 = -32601;
+pub const INVALID_PARAMS : i64 = -32602;
+pub const MISC_ERROR : i64 = -1;
+pub const VERIFY_ERROR : i64 = -25;
+pub const NOT_FOUND : i64 = -5;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RpcError {
+    pub code : i64,
+    pub message : String,
+}
+
+impl RpcError {
+    pub fn new(code : i64, message : &str) -> RpcError {
+        RpcError { code, message : message.to_string() }
+    }
+}
+
+// node errors that are not mapped to a specific code end up as MISC_ERROR
+impl From<Box<dyn std::error::Error>> for RpcError {
+    fn from(e : Box<dyn std::error::Error>) -> RpcError {
+        RpcError::new(MISC_ERROR, &e.to_string())
+    }
+}
+
+impl From<bincode::Error> for RpcError {
+    fn from(e : bincode::Error) -> RpcError {
+        RpcError::new(MISC_ERROR, &e.to_string())
+    }
+}
+
+pub type RpcResult = Result<Value, RpcError>;
+
+// user name of the cookie credentials
+const COOKIE_USER : &str = "__cookie__";
+
+// RpcAuth holds the credentials every request must carry in its basic
+// Authorization header : the ones given on the command line, or random
+// ones written to a cookie file only the local user can read
+#[derive(Clone)]
+pub struct RpcAuth {
+    // user:password
+    credentials : String,
+    cookie_file : Option<String>,
+}
+
+impl RpcAuth {
+    pub fn new(user : &str, password : &str) -> RpcAuth {
+        RpcAuth { credentials : format!("{}:{}", user, password), cookie_file : None }
+    }
+
+    // Cookie draws a random password and writes the credentials to `path`,
+    // a file only the owner can read from the moment it exists
+    pub fn cookie(path : &str) -> Result<RpcAuth, Box<dyn std::error::Error>> {
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        let credentials = format!("{}:{}", COOKIE_USER, hex::encode(secret));
+        // left behind by a node that did not shut down cleanly
+        match std::fs::remove_file(path) {
+            Ok(()) => info!("removed the stale JSON-RPC cookie {}", path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        std::io::Write::write_all(&mut options.open(path)?, credentials.as_bytes())?;
+        info!("JSON-RPC cookie written to {}", path);
+        Ok(RpcAuth { credentials, cookie_file : Some(path.to_string()) })
+    }
+
+    // ReadCookie loads the credentials a running node wrote to `path`
+    pub fn read_cookie(path : &str) -> Result<RpcAuth, Box<dyn std::error::Error>> {
+        let credentials = std::fs::read_to_string(path)
+            .map_err(|e| format_err!("could not read the JSON-RPC cookie {}: {}, is the node running?", path, e))?;
+        Ok(RpcAuth { credentials : credentials.trim().to_string(), cookie_file : None })
+    }
+
+    // RemoveCookie deletes the cookie file written by Cookie, if any
+    pub fn remove_cookie(&self) {
+        if let Some(path) = &self.cookie_file {
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("could not remove the JSON-RPC cookie {}: {}", path, e);
+            }
+        }
+    }
+
+    fn header(&self) -> String {
+        format!("Basic {}", BASE64.encode(&self.credentials))
+    }
+
+    // Check compares the Authorization header in constant time
+    fn check(&self, header : Option<&String>) -> bool {
+        let given = match header.and_then(|h| h.strip_prefix("Basic ")).and_then(|h| BASE64.decode(h.trim()).ok()) {
+            Some(given) => given,
+            None => return false,
+        };
+        let expected = self.credentials.as_bytes();
+        given.len() == expected.len() && given.iter().zip(expected).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+}
+
+// RpcCall is a request handed to the node event loop, which owns the
+// chain, the mempool and the swarm, the answer goes back through `reply`
+pub struct RpcCall {
+    pub method : String,
+    pub params : Value,
+    pub reply : oneshot::Sender<RpcResult>,
+}
+
+// Param returns the positional parameter `index`, if it was given
+pub fn param(params : &Value, index : usize) -> Option<&Value> {
+    params.as_array().and_then(|p| p.get(index)).filter(|v| !v.is_null())
+}
+
+// ParamStr returns a required string parameter
+pub fn param_str(params : &Value, index : usize, name : &str) -> Result<String, RpcError> {
+    match param(params, index).and_then(|v| v.as_str()) {
+        Some(s) => Ok(s.to_string()),
+        None => Err(RpcError::new(INVALID_PARAMS, &format!("missing string parameter {}", name))),
+    }
+}
+
+// ParamAmount returns an amount in coins, given as a string or a number
+pub fn param_amount(params : &Value, index : usize, name : &str) -> Result<Amount, RpcError> {
+    let text = match param(params, index) {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Number(n)) => n.to_string(),
+        _ => return Err(RpcError::new(INVALID_PARAMS, &format!("missing amount parameter {}", name))),
+    };
+    text.parse().map_err(|e : Box<dyn std::error::Error>| RpcError::new(INVALID_PARAMS, &format!("{}: {}", name, e)))
+}
+
+// PublicEndpoints are served next to JSON-RPC without credentials, they
+// only read public data
+#[derive(Clone, Default)]
+pub struct PublicEndpoints {
+    // the REST explorer under /rest/, with the stream of the node events
+    pub rest : Option<broadcast::Sender<NodeEvent>>,
+    // the Prometheus /metrics page
+    pub metrics : bool,
+}
+
+// Serve accepts authenticated JSON-RPC requests over HTTP POST and forwards
+// them to the node, along with the requests to the public endpoints
+pub async fn serve(listener : TcpListener, calls : mpsc::Sender<RpcCall>, auth : RpcAuth, public : PublicEndpoints) {
+    info!("JSON-RPC listening on {:?}", listener.local_addr());
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("JSON-RPC accept failed: {}", e);
+                continue;
+            }
+        };
+        let calls = calls.clone();
+        let auth = auth.clone();
+        let public = public.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(&mut stream, calls, &auth, &public).await {
+                debug!("JSON-RPC connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream : &mut TcpStream, calls : mpsc::Sender<RpcCall>, auth : &RpcAuth, public : &PublicEndpoints) -> Result<(),Box<dyn std::error::Error>> {
+    let request = read_request(stream).await?;
+    if let Some(events) = &public.rest {
+        if request.path.starts_with("/rest/") {
+            return crate::rest::handle(stream, &request, &calls, events).await;
+        }
+    }
+    if public.metrics && request.path == "/metrics" {
+        return match forward("node.metrics".to_string(), json!([]), &calls).await {
+            Ok(Value::String(text)) => write_response(stream, 200, "text/plain; version=0.0.4", text.as_bytes()).await,
+            Ok(_) => write_response(stream, 500, "text/plain", b"metrics unavailable").await,
+            Err(e) => write_response(stream, 500, "text/plain", e.message.as_bytes()).await,
+        };
+    }
+    if !auth.check(request.headers.get("authorization")) {
+        warn!("JSON-RPC request with wrong credentials from {:?}", stream.peer_addr());
+        return write_response(stream, 401, "text/plain", b"unauthorized").await;
+    }
+    if request.path != "/" {
+        return write_response(stream, 404, "text/plain", b"not found").await;
+    }
+    if request.method != "POST" {
+        return write_response(stream, 405, "text/plain", b"JSON-RPC expects POST").await;
+    }
+    let (id, result) = match serde_json::from_slice::<Value>(&request.body) {
+        Ok(body) => {
+            let id = body.get("id").cloned().unwrap_or(Value::Null);
+            (id, dispatch(body, &calls).await)
+        }
+        Err(e) => (Value::Null, Err(RpcError::new(PARSE_ERROR, &e.to_string()))),
+    };
+    let response = match result {
+        Ok(value) => json!({ "jsonrpc" : "2.0", "result" : value, "error" : null, "id" : id }),
+        Err(e) => json!({ "jsonrpc" : "2.0", "result" : null, "error" : e, "id" : id }),
+    };
+    write_response(stream, 200, "application/json", response.to_string().as_bytes()).await
+}
+
+async fn dispatch(body : Value, calls : &mpsc::Sender<RpcCall>) -> RpcResult {
+    let method = match body.get("method").and_then(|m| m.as_str()) {
+        Some(m) => m.to_string(),
+        None => return Err(RpcError::new(INVALID_REQUEST, "missing method")),
+    };
+    // dotted names are the calls of the public endpoints
+    if method.contains('.') {
+        return Err(RpcError::new(METHOD_NOT_FOUND, &format!("unknown method {}", method)));
+    }
+    let params = body.get("params").cloned().unwrap_or(json!([]));
+    forward(method, params, calls).await
+}
+
+// Forward hands one call to the node event loop and waits for its answer
+pub async fn forward(method : String, params : Value, calls : &mpsc::Sender<RpcCall>) -> RpcResult {
+    let (reply, answer) = oneshot::channel();
+    if calls.send(RpcCall { method, params, reply }).await.is_err() {
+        return Err(RpcError::new(MISC_ERROR, "node is shutting down"));
+    }
+    answer.await.unwrap_or_else(|_| Err(RpcError::new(MISC_ERROR, "node dropped the request")))
+}
+
+// Call sends one request to a node and returns its result
+pub async fn call(addr : &str, auth : &RpcAuth, method : &str, params : Value) -> Result<Value,Box<dyn std::error::Error>> {
+    let body = json!({ "jsonrpc" : "2.0", "id" : 1, "method" : method, "params" : params }).to_string();
+    let mut stream = TcpStream::connect(addr).await
+        .map_err(|e| format_err!("could not connect to the node at {}: {}", addr, e))?;
+    let request = format!(
+        "POST / HTTP/1.1\r\nHost: {}\r\nAuthorization: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        addr, auth.header(), body.len(), body
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+
+    if response.starts_with(b"HTTP/1.1 401") {
+        return Err(format_err!("the node at {} refused the JSON-RPC credentials", addr).into());
+    }
+    let start = response.windows(4).position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| format_err!("malformed response from {}", addr))? + 4;
+    let mut answer : Value = serde_json::from_slice(&response[start..])?;
+    if !answer["error"].is_null() {
+        let error : RpcError = serde_json::from_value(answer["error"].take())?;
+        return Err(format_err!("{} (code {})", error.message, error.code).into());
+    }
+    Ok(answer["result"].take())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auth_check() {
+        let auth = RpcAuth::new("alice", "secret");
+        assert!(auth.check(Some(&auth.header())));
+        assert!(!auth.check(Some(&RpcAuth::new("alice", "secreT").header())));
+        assert!(!auth.check(Some(&RpcAuth::new("alice", "secret2").header())));
+        assert!(!auth.check(Some(&"Basic !!".to_string())));
+        assert!(!auth.check(None));
+    }
+
+    #[test]
+    fn test_cookie() {
+        let path = std::env::temp_dir().join(format!("rpc-cookie-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "__cookie__:stale").unwrap();
+        let auth = RpcAuth::cookie(path).unwrap();
+        let written = RpcAuth::read_cookie(path).unwrap();
+        assert!(auth.check(Some(&written.header())));
+        assert_ne!(written.credentials, "__cookie__:stale");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        auth.remove_cookie();
+        assert!(!std::path::Path::new(path).exists());
+    }
+}
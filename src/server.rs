@@ -6,6 +6,20 @@ use crate::transaction::*;
 use crate::behavior::Behavior;
 use crate::behavior::Event as AgentEvent;
 use crate::constants::*;
+use crate::mempool::{Mempool, MempoolEntry};
+use crate::events::NodeEvent;
+use crate::addrbook::AddrBook;
+use crate::banlist::{BanList, Misbehavior};
+use crate::validation::reject_kind_of;
+use crate::metrics::{self, METRICS};
+use crypto::{digest::Digest, sha2::Sha256};
+use libp2p::gossipsub::MessageAcceptance;
+use crate::miner::{Miner, MiningPolicy};
+use crate::blockchain::TipChange;
+use crate::rpc::{self, RpcAuth, RpcCall, RpcError, RpcResult};
+use crate::wallet::Wallets;
+use crate::amount::{Amount, COIN};
+use crate::chainparams::{params, Network};
 /************************/
 use bincode::{deserialize, serialize};
 use failure::format_err;
@@ -13,7 +27,8 @@ use std::collections::{HashMap, HashSet};
 use std::io::prelude::*;
 use std::ops::Mul;
 use std::sync::*;
-use std::{thread,time::Duration};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
 /*****************************/
 use libp2p::kad::RoutingUpdate;
 use libp2p::{
@@ -28,7 +43,8 @@ use libp2p::{
     ,yamux,
     kad::{store::MemoryStore, Mode,Behaviour},
     kad,
-    mdns
+    mdns,
+    gossipsub
 };
 use libp2p::identify::{
     Config as IdentifyConfig, 
@@ -39,7 +55,9 @@ use libp2p::request_response::{
     Config as RequestResponseConfig, 
     ProtocolSupport as RequestResponseProtocolSupport,
     Event as RequestResponseEvent,
-    Message as RequestResponseMessage
+    Message as RequestResponseMessage,
+    OutboundFailure,
+    OutboundRequestId
 };
 use libp2p::request_response::cbor::Behaviour as RequestResponseBehavior;
 use tracing_subscriber::EnvFilter;
@@ -48,28 +66,89 @@ use futures::stream::StreamExt;
 use log::{debug, info,warn,error};
 use env_logger::{Env, Builder};
 use std::env::args;
-use get_size::GetSize;
 /*****************************/
 pub struct Server{
-    wallet_address : String,
+    policy : MiningPolicy,
     swarm : Swarm<Behavior>,
-    inner : Arc<Mutex<ServerInner>>
+    inner : Arc<Mutex<ServerInner>>,
+    miner : Miner,
+    mined_blocks : tokio::sync::mpsc::UnboundedReceiver<Block>,
+    // mines the blocks of the generate RPC, apart from the background job
+    generator : Miner,
+    generated_blocks : tokio::sync::mpsc::UnboundedReceiver<Block>,
+    // the generate call in progress, answered after its last block
+    generate : Option<GenerateJob>,
+    rpc_sender : tokio::sync::mpsc::Sender<RpcCall>,
+    rpc_calls : tokio::sync::mpsc::Receiver<RpcCall>,
+    rpc_auth : Option<RpcAuth>,
+    // what the node does, for the /rest/events subscribers
+    events : tokio::sync::broadcast::Sender<NodeEvent>,
+    // set by the stop RPC, the event loop exits after the call
+    stopping : bool,
+    // requests sent to peers and not answered yet
+    pending_requests : HashMap<OutboundRequestId,PendingRequest>,
+    // failed requests waiting to be sent again
+    retry_queue : Vec<PendingRequest>,
+    // dialed again whenever the node has no peer left
+    bootstrap : Vec<Multiaddr>,
+    // connected peers the node dialed itself
+    outbound_peers : HashSet<PeerId>,
+    // when a dropped peer last made the node dial others, a peer refusing
+    // us would else be dialed in a loop
+    last_reconnect : Option<Instant>,
+    // the peers met so far, dialed first when the node needs more
+    addr_book : AddrBook,
+    // misbehavior scores of the peers and the banned ones
+    ban_list : BanList,
+    // connected peers not done with the version handshake, since when
+    handshakes : HashMap<PeerId,Instant>,
+    // NODE_* bits announced in our version message
+    services : u64,
+    // sent in our version message to notice connections to ourselves
+    nonce : u64,
+}
+
+// PendingRequest is a request to a peer, kept until it is answered so it
+// can be sent again if it fails
+struct PendingRequest {
+    peer : PeerId,
+    message : Message,
+    // how many times it failed already
+    attempts : u32,
+    // when to send it again after a failure
+    due : Instant,
+}
+
+// GenerateJob is a generate call whose blocks are mined one after the other
+struct GenerateJob {
+    address : String,
+    // blocks still to mine
+    remaining : u64,
+    // the blocks connected so far
+    hashes : Vec<String>,
+    reply : tokio::sync::oneshot::Sender<RpcResult>,
 }
 
 pub struct ServerInner{
     pub known_peers : HashSet<PeerId>,
     utxo : UTXOSet,
     blocks_in_transit : Vec<String>,
-    mempool : HashMap<String,Transaction>,
+    // last hash of a full block inventory, the blocks after it are asked
+    // for once the ones in transit arrived
+    blocks_continue : Option<String>,
+    mempool : Mempool,
+    // how far the clock of each peer is ahead of ours, in seconds
+    time_offsets : HashMap<PeerId,i64>,
+    // the version message of each peer done with the handshake
+    peer_versions : HashMap<PeerId,Versionmsg>,
 }
 
 //const BOOTSTRAP_NODE: &str = "localhost:3000";
 const CMD_LEN: usize = 12;
-const VERSION: i32 = 1;
 
 impl Server {
-    pub async fn new(ip_addr : &str, wallet_addr : &str, utxo : UTXOSet) -> Result<Server, Box<dyn std::error::Error>>{
-        let local_key: identity::Keypair = libp2p::identity::Keypair::generate_ed25519();
+    pub async fn new(bootstrap : Vec<Multiaddr>, wallet_addr : &str, utxo : UTXOSet, use_mdns : bool) -> Result<Server, Box<dyn std::error::Error>>{
+        let local_key = load_node_key(&params().data_path(NODE_KEY_FILE))?;
         let mut swarm = libp2p::SwarmBuilder::with_existing_identity(local_key.clone())
                     .with_tokio()
                     .with_tcp(
@@ -80,117 +159,125 @@ impl Server {
                     .with_behaviour(|key|{
                         let local_peer_id = PeerId::from(key.public().clone());
                         info!("Local peer ID : {local_peer_id}");
-                        let mut kad_config = kad::Config::new(StreamProtocol::new("/agent/connection/1.0.0"));
+                        let mut kad_config = kad::Config::new(StreamProtocol::new(params().kad_protocol));
+                        // start_server runs the bootstrap on its own timer
+                        kad_config.set_periodic_bootstrap_interval(None);
                         let kad_memory = MemoryStore::new(local_peer_id);
                         let kad_mem_behaviour = kad::Behaviour::with_config(local_peer_id, kad_memory, kad_config);
                         let identity_config = IdentifyConfig::new(
-                            "/agent/connection/1.0.0".to_string(), 
+                            params().kad_protocol.to_string(), 
                             key.clone().public()
                         )
                         .with_push_listen_addr_updates(true)
                         .with_interval(Duration::from_secs(30));
 
                         let rr_config = RequestResponseConfig::default();
-                        let rr_protocol = StreamProtocol::new("/agent/message/1.0.0");
+                        let rr_protocol = StreamProtocol::new(params().message_protocol);
                         let rr_behavior = RequestResponseBehavior::<Message,Message>::new([(rr_protocol,RequestResponseProtocolSupport::Full)],rr_config);
 
+                        // gossip messages are identified by their content, so the
+                        // same transaction relayed by several peers is handled once,
+                        // and only forwarded once handle_gossip validated it
+                        let gossipsub_config = gossipsub::ConfigBuilder::default()
+                            .validation_mode(gossipsub::ValidationMode::Strict)
+                            .validate_messages()
+                            .message_id_fn(|message: &gossipsub::Message| {
+                                let mut hasher = Sha256::new();
+                                hasher.input(&message.data);
+                                gossipsub::MessageId::from(hasher.result_str())
+                            })
+                            .max_transmit_size(MAX_GOSSIP_MESSAGE_SIZE)
+                            .build()?;
+                        let gossipsub = gossipsub::Behaviour::new(
+                            gossipsub::MessageAuthenticity::Signed(key.clone()),
+                            gossipsub_config
+                        )?;
+
+                        // peers of the same LAN announce themselves over multicast DNS
+                        let mdns = if use_mdns {
+                            Some(mdns::tokio::Behaviour::new(mdns::Config::default(), local_peer_id)?)
+                        } else {
+                            None
+                        };
+
                         let identify = IdentifyBehavior::new(identity_config);
-                        Behavior::new(kad_mem_behaviour,identify,rr_behavior)
+                        Ok(Behavior::new(kad_mem_behaviour,identify,rr_behavior,gossipsub,mdns))
                     })?
                     .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(30)))
                     .build();
-        let mut node_set : HashSet<PeerId> = HashSet::new();
-        if ip_addr != ""{
-            swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
-            let remote : Multiaddr = ip_addr.parse()?;
-            //Dial a known or unknown peer.
-            swarm.dial(remote)?;
-            info!("Dialed to: {ip_addr}");
-        }else{
+        swarm.behaviour_mut().subscribe()?;
+        // every node answers kademlia queries, so the lookups of its peers
+        // can go through it
+        swarm.behaviour_mut().set_server_mode();
+        if bootstrap.is_empty() {
             info!("Act as bootstrap node");
-            swarm.listen_on("/ip4/0.0.0.0/tcp/8000".parse()?)?;
+            swarm.listen_on(format!("/ip4/0.0.0.0/tcp/{}", params().default_port).parse()?)?;
+        }else{
+            swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
         }
-        /***********************************************************************************/
-        /***************************connect to at least one node ***************************/
-        println!("===> waiting for new connections !!! ");
-        loop {
-            match swarm.select_next_some().await {
-                SwarmEvent::NewListenAddr { listener_id, address } => info!("NewListenAddr: {listener_id:?} | {address:?}"),
-                SwarmEvent::ConnectionEstablished { 
-                    peer_id, 
-                    connection_id, 
-                    endpoint, 
-                    num_established, 
-                    concurrent_dial_errors, 
-                    established_in 
-                }=> {info!("ConnectionEstablished: {peer_id} | {connection_id} | {endpoint:?} | {num_established} | {concurrent_dial_errors:?} | {established_in:?}");
-                    },
-                SwarmEvent::Dialing { peer_id, connection_id } => info!("Dialing: {peer_id:?} | {connection_id}"),
-         
-                SwarmEvent::Behaviour(AgentEvent::Kad(event)) => match event {
-                    kad::Event::ModeChanged { new_mode } => info!("KadEvent:ModeChanged: {new_mode}"),
-                    kad::Event::RoutablePeer { peer, address } => info!("KadEvent:RoutablePeer: {peer} | {address}"),
-                    kad::Event::PendingRoutablePeer { peer, address } => info!("KadEvent:PendingRoutablePeer: {peer} | {address}"),
-                    kad::Event::InboundRequest { request } => info!("KadEvent:InboundRequest: {request:?}"),
-                    kad::Event::RoutingUpdated { 
-                        peer, 
-                        is_new_peer, 
-                        addresses, 
-                        bucket_range, 
-                        old_peer } => {
-                            info!("KadEvent:RoutingUpdated: {peer} | IsNewPeer? {is_new_peer} | {addresses:?} | {bucket_range:?} | OldPeer: {old_peer:?}");
-                    },
-                    kad::Event::OutboundQueryProgressed { 
-                        id, 
-                        result, 
-                        stats, 
-                        step } => {
-    
-                        info!("KadEvent:OutboundQueryProgressed: ID: {id:?} | Result: {result:?} | Stats: {stats:?} | Step: {step:?}")
-                    },
-                    _ => {println!("problem in :  SwarmEvent::Behaviour(AgentEvent::Kad");}
-                },
-                SwarmEvent::Behaviour(AgentEvent::Identify(event)) => match event {
-                    //Identification information of the local node has been sent 
-                    //to a peer in response to an identification request
-                    IdentifyEvent::Sent {peer_id,connection_id :_ } => info!("IdentifyEvent:Sent: {peer_id}"),
-                    IdentifyEvent::Pushed { connection_id : _, peer_id, info } => info!("IdentifyEvent:Pushed: {peer_id} | {info:?}"),
-                    IdentifyEvent::Received { connection_id, peer_id, info }=> {
-                        info!("IdentifyEvent:Received: {peer_id} | {info:?}");
-                        node_set.insert(peer_id.clone());
-                        for addr in info.listen_addrs.clone(){
-                            let agent_routing = swarm.behaviour_mut().register_add_kad(&peer_id, addr.clone());
-                            match agent_routing {
-                                RoutingUpdate::Failed => error!("IdentifyReceived: Failed to register address to Kademlia"),
-                                RoutingUpdate::Pending => warn!("IdentifyReceived: Register address pending"),
-                                RoutingUpdate::Success => {
-                                    info!("IdentifyReceived: {addr}: Success register address");
-                                } 
-                            }
-                            _ = swarm.behaviour_mut().register_addr_rr(&peer_id, addr.clone());
-                        }
-                    },
-                    _ => {println!("problem in : SwarmEvent::Behaviour(AgentEvent::Identify");}
-                },
-                SwarmEvent::IncomingConnectionError { connection_id, local_addr, send_back_addr, error } => println!("incming connection error"),
-                SwarmEvent::OutgoingConnectionError { connection_id, peer_id, error } => println!("outgoing connection error !!"),
-                
-                _ => {println!("problem in swarmevent !!");}
-            }
-            if node_set.len() > 0 {
-                break;
+        let mut addr_book = AddrBook::new();
+        let addr_book_file = params().data_path(ADDRBOOK_FILE);
+        match addr_book.load(&addr_book_file, unix_time()) {
+            Ok(count) => info!("restored {} peers from {}", count, addr_book_file),
+            Err(e) => warn!("could not restore the address book from {}: {}", addr_book_file, e),
+        }
+        let mut ban_list = BanList::new();
+        let ban_list_file = params().data_path(BANLIST_FILE);
+        match ban_list.load(&ban_list_file, unix_time()) {
+            Ok(count) => info!("restored {} bans from {}", count, ban_list_file),
+            Err(e) => warn!("could not restore the ban list from {}: {}", ban_list_file, e),
+        }
+        for (peer_id, _) in ban_list.banned() {
+            swarm.behaviour_mut().block_peer(peer_id);
+        }
+        for (peer_id, addrs) in addr_book.best(ADDRBOOK_MAX_PEERS) {
+            for addr in addrs {
+                swarm.behaviour_mut().register_add_kad(&peer_id, addr.clone());
+                swarm.behaviour_mut().register_addr_rr(&peer_id, addr);
             }
         }
-        println!("return instance of the created server !!");
         /********************************************************/
+        let mut mempool = Mempool::new();
+        let mempool_file = params().data_path(MEMPOOL_FILE);
+        match mempool.load(&mempool_file, &utxo, unix_time()) {
+            Ok(count) => info!("restored {} transactions from {}", count, mempool_file),
+            Err(e) => warn!("could not restore mempool from {}: {}", mempool_file, e),
+        }
+        let (found, mined_blocks) = tokio::sync::mpsc::unbounded_channel();
+        let (generated, generated_blocks) = tokio::sync::mpsc::unbounded_channel();
+        let (rpc_sender, rpc_calls) = tokio::sync::mpsc::channel(64);
+        let (events, _) = tokio::sync::broadcast::channel(EVENTS_CAPACITY);
         Ok(Server{
-            wallet_address : wallet_addr.to_string(),
+            policy : MiningPolicy::new(wallet_addr),
             swarm,
+            miner : Miner::new(found),
+            mined_blocks,
+            generator : Miner::new(generated),
+            generated_blocks,
+            generate : None,
+            rpc_sender,
+            rpc_calls,
+            rpc_auth : None,
+            events,
+            stopping : false,
+            pending_requests : HashMap::new(),
+            retry_queue : Vec::new(),
+            bootstrap,
+            outbound_peers : HashSet::new(),
+            last_reconnect : None,
+            addr_book,
+            ban_list,
+            handshakes : HashMap::new(),
+            services : NODE_NETWORK,
+            nonce : rand::random(),
             inner: Arc::new(Mutex::new(ServerInner{
-                known_peers : node_set,
+                known_peers : HashSet::new(),
                 utxo,
                 blocks_in_transit : Vec::new(),
-                mempool : HashMap::new(),
+                blocks_continue : None,
+                mempool,
+                time_offsets : HashMap::new(),
+                peer_versions : HashMap::new(),
 
             })),
         })
@@ -199,28 +286,135 @@ impl Server {
     
     /**************************************************************************/
     /****************************inner hepler functions ***********************/
-    fn get_mempool(&self) -> HashMap<String,Transaction>{
-        self.inner.lock().unwrap().mempool.clone()
+    // Notify hands an event to the subscribers, having none is fine
+    fn notify(&self, event: NodeEvent) {
+        let _ = self.events.send(event);
     }
-    
-    fn insert_mempool(&self, tx: Transaction){
-        self.inner.lock().unwrap().mempool.insert(tx.id.clone(),tx);
+
+    fn mempool_len(&self) -> usize {
+        self.inner.lock().unwrap().mempool.len()
+    }
+
+    // InsertMempool validates tx against the UTXO set and the pending transactions
+    fn insert_mempool(&self, tx: Transaction) -> Result<(),Box<dyn std::error::Error>>{
+        let txid = tx.id.clone();
+        let (evicted, fee) = {
+            let mut inner = self.inner.lock().unwrap();
+            let inner = &mut *inner;
+            let evicted = inner.mempool.add(tx, &inner.utxo, unix_time())?;
+            (evicted, inner.mempool.get_entry(&txid).map(|e| (e.tx.clone(), e.fee)))
+        };
+        for tx in evicted {
+            info!("mempool evicted {} to stay within its limits", tx.id);
+            self.notify(NodeEvent::tx_removed(&tx, "evicted"));
+        }
+        if let Some((tx, fee)) = fee {
+            self.notify(NodeEvent::tx_accepted(&tx, fee));
+        }
+        Ok(())
     }
     
     fn get_mempool_tx(&self,addr : &str) -> Option<Transaction>{
-        match self.inner.lock().unwrap().mempool.get(addr){
-            Some(tx) => Some(tx.clone()),
-            None => None
+        self.inner.lock().unwrap().mempool.get(addr).cloned()
+    }
+
+    // only the transactions of a connected block leave the mempool,
+    // together with whatever conflicted with them
+    fn remove_confirmed_mempool(&self, block: &Block){
+        let conflicts = self.inner.lock().unwrap().mempool.remove_confirmed(block.get_transaction());
+        for tx in conflicts {
+            info!("mempool dropped {}: conflicts with block {}", tx.id, block.get_hash());
+            self.notify(NodeEvent::tx_removed(&tx, "conflict"));
         }
     }
-    
-    fn clear_mempool(&self){
-        self.inner.lock().unwrap().mempool.clear()
+
+    fn expire_mempool(&self){
+        let expired = self.inner.lock().unwrap().mempool.expire(unix_time());
+        for tx in expired {
+            info!("mempool expired {}", tx.id);
+            self.notify(NodeEvent::tx_removed(&tx, "expired"));
+        }
+    }
+
+    fn save_mempool(&self){
+        let mempool_file = params().data_path(MEMPOOL_FILE);
+        match self.inner.lock().unwrap().mempool.save(&mempool_file) {
+            Ok(count) => info!("saved {} mempool transactions to {}", count, mempool_file),
+            Err(e) => error!("could not save mempool to {}: {}", mempool_file, e),
+        }
+    }
+
+    // SavePeers writes the address book and the ban list
+    fn save_peers(&mut self){
+        let addr_book_file = params().data_path(ADDRBOOK_FILE);
+        self.addr_book.prune(unix_time());
+        match self.addr_book.save(&addr_book_file) {
+            Ok(count) => info!("saved {} peers to {}", count, addr_book_file),
+            Err(e) => error!("could not save the address book to {}: {}", addr_book_file, e),
+        }
+        let ban_list_file = params().data_path(BANLIST_FILE);
+        match self.ban_list.save(&ban_list_file) {
+            Ok(count) => info!("saved {} bans to {}", count, ban_list_file),
+            Err(e) => error!("could not save the ban list to {}: {}", ban_list_file, e),
+        }
     }
 
     pub async fn start_server(&mut self){
+        let mut save_interval = tokio::time::interval(Duration::from_secs(MEMPOOL_SAVE_INTERVAL_SECS));
+        let mut mining_interval = tokio::time::interval(Duration::from_secs(1));
+        let mut retry_interval = tokio::time::interval(Duration::from_secs(1));
+        // the first ticks are immediate : the bootstrap nodes are dialed right away
+        let mut peer_interval = tokio::time::interval(Duration::from_secs(PEER_CHECK_INTERVAL_SECS));
+        let mut bootstrap_interval = tokio::time::interval(Duration::from_secs(BOOTSTRAP_INTERVAL_SECS));
+        let mut shutdown = Box::pin(tokio::signal::ctrl_c());
         loop {
-            self.handle_events().await.unwrap();
+            tokio::select! {
+                event = self.swarm.select_next_some() => self.handle_events(event).unwrap(),
+                Some(block) = self.mined_blocks.recv() => {
+                    if let Err(e) = self.handle_mined_block(block) {
+                        error!("could not connect mined block: {}", e);
+                    }
+                }
+                Some(block) = self.generated_blocks.recv() => self.handle_generated_block(block),
+                Some(call) = self.rpc_calls.recv() => {
+                    self.handle_rpc(call);
+                    if self.stopping {
+                        self.shutdown();
+                        return;
+                    }
+                }
+                _ = save_interval.tick() => {
+                    self.save_mempool();
+                    self.save_peers();
+                }
+                _ = mining_interval.tick() => self.check_block_interval(),
+                _ = retry_interval.tick() => self.retry_requests(),
+                _ = peer_interval.tick() => {
+                    self.drop_silent_peers();
+                    self.lift_expired_bans();
+                    self.maintain_peers();
+                }
+                _ = bootstrap_interval.tick() => {
+                    if let Err(e) = self.swarm.behaviour_mut().bootstrap() {
+                        debug!("kademlia bootstrap skipped: {e}");
+                    }
+                }
+                _ = &mut shutdown => {
+                    self.shutdown();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn shutdown(&mut self) {
+        info!("shutting down the node");
+        self.miner.cancel();
+        self.generator.cancel();
+        self.save_mempool();
+        self.save_peers();
+        if let Some(auth) = &self.rpc_auth {
+            auth.remove_cookie();
         }
     }
     /*******************************************************/
@@ -237,34 +431,189 @@ impl Server {
         self.inner.lock().unwrap().known_nodes.get(addr).is_some()
     }
     ********************************************************/
+    // AddTimeOffset records how far the clock of a peer is from ours
+    fn add_time_offset(&self, peer_id : &PeerId, peer_time : u64) {
+        let offset = peer_time as i64 - unix_time() as i64;
+        if offset.abs() > MAX_TIME_ADJUSTMENT {
+            warn!("the clock of {} is {}s away from ours, check the local time", peer_id, offset);
+        }
+        self.inner.lock().unwrap().time_offsets.insert(*peer_id, offset);
+    }
+
+    // AdjustedTime is the local time corrected by the median offset of the
+    // peer clocks, a correction beyond MAX_TIME_ADJUSTMENT is ignored
+    fn adjusted_time(&self) -> u64 {
+        let mut offsets : Vec<i64> = self.inner.lock().unwrap().time_offsets.values().cloned().collect();
+        // our own clock takes part in the median
+        offsets.push(0);
+        offsets.sort();
+        let median = offsets[offsets.len() / 2];
+        if median.abs() > MAX_TIME_ADJUSTMENT {
+            return unix_time();
+        }
+        (unix_time() as i64 + median).max(0) as u64
+    }
+
     fn get_known_nodes(&self) -> HashSet<PeerId> {
         self.inner.lock().unwrap().known_peers.clone()
     }
-    fn add_node(&self, peer_id : &PeerId){
-        self.inner.lock().unwrap().known_peers.insert(peer_id.clone());
+    // AddNode returns true if the peer was not known yet
+    fn add_node(&self, peer_id : &PeerId) -> bool {
+        self.inner.lock().unwrap().known_peers.insert(peer_id.clone())
+    }
+
+    fn remove_node(&self, peer_id : &PeerId) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.known_peers.remove(peer_id);
+        inner.time_offsets.remove(peer_id);
+        inner.peer_versions.remove(peer_id);
+    }
+
+    // NodeIsKnown tells whether the peer is done with the version handshake
+    fn node_is_known(&self, peer_id : &PeerId) -> bool {
+        self.inner.lock().unwrap().known_peers.contains(peer_id)
     }
     /********************************************************/
     fn get_block(&self, blokc_hash : &str) -> Result<Block,Box<dyn std::error::Error>>{
         self.inner.lock().unwrap().utxo.blockchain.get_block(blokc_hash)
     }
     
-    fn add_block(&self, block: Block) {
-        self.inner.lock().unwrap().utxo.blockchain.add_block(block).unwrap();
+    fn is_orphan(&self, block_hash : &str) -> Result<bool,Box<dyn std::error::Error>> {
+        self.inner.lock().unwrap().utxo.blockchain.is_orphan(block_hash)
+    }
+
+    fn add_block(&self, block: Block) -> Result<TipChange,Box<dyn std::error::Error>> {
+        block.check_future_time(self.adjusted_time())?;
+        self.inner.lock().unwrap().utxo.blockchain.add_block(block)
+    }
+
+    // ApplyTipChange brings the mempool in line with a new tip, the block
+    // tree already moved the UTXO set : the transactions of disconnected
+    // blocks go back to the mempool unless the new chain confirms or
+    // conflicts with them
+    fn apply_tip_change(&self, change: TipChange) -> Result<(),Box<dyn std::error::Error>> {
+        if change.connected.is_empty() && change.disconnected.is_empty() {
+            return Ok(());
+        }
+        for block in change.disconnected.iter().rev() {
+            info!("disconnected block {} at height {}", block.get_hash(), block.get_height());
+            self.notify(NodeEvent::block_disconnected(block));
+        }
+        for block in &change.connected {
+            info!("connected block {} at height {}", block.get_hash(), block.get_height());
+            self.notify(NodeEvent::block_connected(block));
+        }
+        for block in &change.connected {
+            self.remove_confirmed_mempool(block);
+        }
+        for block in &change.disconnected {
+            for tx in block.get_transaction() {
+                if tx.is_coinbase() {
+                    continue;
+                }
+                if let Err(e) = self.insert_mempool(tx.clone()) {
+                    info!("transaction {} of disconnected block {} not restored: {}", tx.id, block.get_hash(), e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_mining_policy(&mut self, policy: MiningPolicy) {
+        self.policy = policy;
+    }
+
+    // NewBlockTemplate fills a block with the best paying mempool transactions
+    // on top of the current tip, the coinbase pays `address` the subsidy and
+    // the returned fees
+    fn new_block_template(&self, address: &str) -> Result<(Block, Amount),Box<dyn std::error::Error>> {
+        // the fees do not change the coinbase size, its weight is known up front
+        let height = self.get_best_height()? + 1;
+        let cbtx_weight = Transaction::new_coinbase(address.to_string(), height, String::new(), Amount::ZERO)?.weight()?;
+        let inner = self.inner.lock().unwrap();
+        // fill the template by fee rate up to MAX_BLOCK_WEIGHT, what is
+        // left over stays in the mempool for the next block
+        let (mut txs, fees) = inner.mempool
+            .select_for_block(MAX_BLOCK_WEIGHT - BLOCK_HEADER_WEIGHT_RESERVE - cbtx_weight);
+        // the coinbase goes first, as the consensus rules want it
+        txs.insert(0, Transaction::new_coinbase(address.to_string(), height, String::new(), fees)?);
+        Ok((inner.utxo.blockchain.new_block_template(txs)?, fees))
+    }
+
+    // TipAge is the number of seconds since the tip was mined
+    fn tip_age(&self) -> Result<u64,Box<dyn std::error::Error>> {
+        let tip = self.get_block(&self.get_tip_hash())?;
+        Ok(unix_time().saturating_sub((tip.get_timestamp() / 1000) as u64))
+    }
+
+    // UpdateMining restarts the miner on a fresh template, or stops it
+    // when the policy finds nothing worth mining
+    fn update_mining(&mut self) -> Result<(),Box<dyn std::error::Error>> {
+        // a block found by the background job would turn the generated ones stale
+        if self.generate.is_some() {
+            self.miner.cancel();
+            return Ok(());
+        }
+        let address = match self.policy.payout_address() {
+            Some(address) if self.policy.enabled => address.to_string(),
+            _ => {
+                self.miner.cancel();
+                return Ok(());
+            }
+        };
+        let (template, fees) = self.new_block_template(&address)?;
+        // every transaction but the coinbase
+        let txs = template.get_transaction().len() - 1;
+        if !self.policy.should_mine(txs, fees, self.tip_age()?) {
+            self.miner.cancel();
+            return Ok(());
+        }
+        self.miner.start(template);
+        Ok(())
+    }
+
+    // CheckBlockInterval starts mining once the tip gets older than the
+    // maximum block interval of the policy, even with an empty mempool
+    fn check_block_interval(&mut self) {
+        if self.miner.is_mining() || self.policy.max_block_interval == 0 {
+            return;
+        }
+        match self.tip_age() {
+            Ok(age) if self.policy.interval_elapsed(age) => {
+                if let Err(e) = self.update_mining() {
+                    error!("could not start mining: {}", e);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => error!("could not read the tip: {}", e),
+        }
     }
 
-    fn mine_block(&self, txs: Vec<Transaction>) -> Result<Block,Box<dyn std::error::Error>> {
-        self.inner.lock().unwrap().utxo.blockchain.mine_block(txs)
+    // SubmitBlock connects a block solved by the local or an external miner
+    // and announces it, it must extend the current tip
+    fn submit_block(&mut self, block: Block) -> Result<(),Box<dyn std::error::Error>> {
+        if block.get_prev_hash() != self.get_tip_hash() {
+            return Err(format_err!("block {} is stale, it does not extend the tip", block.get_hash()).into());
+        }
+        let change = self.add_block(block.clone())?;
+        self.apply_tip_change(change)?;
+        info!("announce block {}",block.get_hash());
+        self.publish(params().block_topic, &Message::Block(Blockmsg { block }))?;
+        // to mine a new block based on transactions 
+        // sent while node was mining old ones 
+        self.update_mining()
     }
 
     fn request_blocks(&mut self) -> Result<(),Box<dyn std::error::Error>>{
         for node in self.get_known_nodes(){
-            self.send_get_blocks(&node)?
+            let locator = self.block_locator();
+            self.send_get_blocks(&node, locator)?
         }
         Ok(())
     }
     
-    fn get_block_hashs(&self) -> Vec<String>{
-        self.inner.lock().unwrap().utxo.blockchain.get_block_hashs()
+    fn block_locator(&self) -> Vec<String>{
+        self.inner.lock().unwrap().utxo.blockchain.block_locator()
     }
     /******************************************************/
     fn replace_in_transit(&self,hashs : Vec<String>){
@@ -276,18 +625,15 @@ impl Server {
         self.inner.lock().unwrap().blocks_in_transit.clone()
     }
 
+    fn get_tip_hash(&self) -> String {
+        self.inner.lock().unwrap().utxo.blockchain.current_hash.clone()
+    }
+
     fn get_best_height(&self) -> Result<i32,Box<dyn std::error::Error>>{
         self.inner.lock().unwrap().utxo.blockchain.get_best_height()
     }
     /*************************************************************************************/
     /*************************************************************************************/
-    fn send_block(& mut self, peer_id : &PeerId, b: &Block) -> Result<(),Box<dyn std::error::Error>> {
-        info!("send block data to: {} block hash: {}", peer_id, b.get_hash());
-        let data = Message::Block(Blockmsg {
-            block: b.clone(),
-        });
-        self.send_data(peer_id, data)
-    }
     /***********************************************/
     /*fn send_addr(&self, addr: &str) -> Result<(),Box<dyn std::error::Error>> {
         info!("send address info to: {}", addr);
@@ -298,42 +644,22 @@ impl Server {
     /***********************************************/
     fn send_version(&mut self, peer_id: &PeerId) -> Result<(),Box<dyn std::error::Error>> {
         info!("send version info to: {}", peer_id);
-        let data = Message::Version(Versionmsg {
-            best_height: self.get_best_height()?,
-            version: VERSION,
-        });
+        let data = Message::Version(self.version_msg()?);
         self.send_data(peer_id, data)
     }
-    /***********************************************/
-    fn send_inv(& mut self, peer_id: &PeerId, kind: &str, items: Vec<String>) -> Result<(),Box<dyn std::error::Error>> {
-        info!(
-            "send inv message to: {} kind: {} data: {:?}",
-            peer_id, kind, items
-        );
-        let data = Message::Inv(Invmsg {
-            kind: kind.to_string(),
-            items,
-        });
-        self.send_data(peer_id, data)
-    }
-    /*************************************************/
-    pub async fn send_transaction(wallet_addr : &str,tx : &Transaction, utxoset : UTXOSet) -> Result<(),Box<dyn std::error::Error>>{
-        let mut server = Server::new("/ip4/127.0.0.1/tcp/8000",wallet_addr,utxoset).await?;
-        for addr in &server.get_known_nodes(){
-            server.send_tx(addr,tx)?;
-        }
-        server.start_server().await;
-        Ok(())
-    }
-    /************************************************/
-    pub fn send_tx(&mut self, peer_id: &PeerId, tx: &Transaction) -> Result<(),Box<dyn std::error::Error>> {
-        info!("send tx to: {} txid: {}", peer_id, &tx.id);
-        let data = Message::Tx(Txmsg {
-            transaction: tx.clone(),
-        });
-        self.send_data(peer_id, data)
+
+    fn version_msg(&self) -> Result<Versionmsg,Box<dyn std::error::Error>> {
+        Ok(Versionmsg {
+            version: PROTOCOL_VERSION,
+            network: params().genesis_hash.to_string(),
+            services: self.services,
+            user_agent: format!("/{}:{}/", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            best_height: self.get_best_height()?,
+            timestamp: unix_time(),
+            nonce: self.nonce,
+        })
     }
-    /************************************************/
+    /***********************************************/
     fn send_get_data(&mut self, peer_id: &PeerId, kind: &str, id: &str) -> Result<(),Box<dyn std::error::Error>> {
         info!(
             "send get data message to: {} kind: {} id: {}",
@@ -347,9 +673,20 @@ impl Server {
         self.send_data(peer_id, data)
     }
     /************************************************/
-    fn send_get_blocks(& mut self, peer_id: &PeerId) -> Result<(),Box<dyn std::error::Error>> {
+    // Publish announces a transaction or a block to the whole network
+    fn publish(&mut self, topic: &str, message: &Message) -> Result<(),Box<dyn std::error::Error>> {
+        let data = serialize(message)?;
+        match self.swarm.behaviour_mut().publish(topic, data) {
+            Ok(message_id) => debug!("published {message_id} on {topic}"),
+            // with no peer listening yet they get it when they sync
+            Err(e) => warn!("could not publish on {topic}: {e}"),
+        }
+        Ok(())
+    }
+    /************************************************/
+    fn send_get_blocks(& mut self, peer_id: &PeerId, locator: Vec<String>) -> Result<(),Box<dyn std::error::Error>> {
         info!("send get blocks message to: {}", peer_id);
-        let data = Message::GetBlock ;
+        let data = Message::GetBlock(GetBlocksmsg { locator });
         self.send_data(peer_id, data)
     }
     /**********   ===================>   ***********/
@@ -357,16 +694,127 @@ impl Server {
         if peer_id == self.swarm.local_peer_id() {
             return Ok(());
         }
-        let request_id = self.swarm.behaviour_mut().send_message(&peer_id, data);
-        info!("RequestID: {request_id}"); 
-        // continue server running 
-        info!("data send successfully");
+        self.send_request(PendingRequest { peer : *peer_id, message : data, attempts : 0, due : Instant::now() });
         Ok(())
     }
+
+    fn send_request(&mut self, request: PendingRequest) {
+        let request_id = self.swarm.behaviour_mut().send_message(&request.peer, request.message.clone());
+        debug!("RequestID: {request_id} sent to {}", request.peer);
+        self.pending_requests.insert(request_id, request);
+    }
+
+    // RequestFailed queues a failed request to be sent again, unless the
+    // peer can not answer it at all or it failed too often
+    fn request_failed(&mut self, request_id: OutboundRequestId, error: &OutboundFailure) {
+        let mut request = match self.pending_requests.remove(&request_id) {
+            Some(request) => request,
+            None => return,
+        };
+        request.attempts += 1;
+        if matches!(error, OutboundFailure::UnsupportedProtocols) || request.attempts > MAX_REQUEST_RETRIES {
+            warn!("give up request {request_id} to {} after {} attempts: {error}", request.peer, request.attempts);
+            return;
+        }
+        warn!("request {request_id} to {} failed, attempt {}/{}: {error}", request.peer, request.attempts, MAX_REQUEST_RETRIES);
+        request.due = Instant::now() + Duration::from_secs(REQUEST_RETRY_DELAY_SECS * request.attempts as u64);
+        self.retry_queue.push(request);
+    }
+
+    // RetryRequests sends again the failed requests whose delay is over
+    fn retry_requests(&mut self) {
+        let now = Instant::now();
+        let (due, waiting) = std::mem::take(&mut self.retry_queue).into_iter().partition(|r| r.due <= now);
+        self.retry_queue = waiting;
+        for request in due {
+            self.send_request(request);
+        }
+    }
+    // MaintainPeers dials more peers while the node has fewer than
+    // TARGET_OUTBOUND_PEERS outbound connections : the bootstrap nodes when
+    // it has no peer at all, the best peers of its address book then those
+    // of its routing table, and it looks up a random peer id to learn about
+    // new ones
+    fn maintain_peers(&mut self) {
+        if self.outbound_peers.len() >= TARGET_OUTBOUND_PEERS {
+            return;
+        }
+        if self.swarm.connected_peers().next().is_none() {
+            for addr in self.bootstrap.clone() {
+                info!("dialing bootstrap node {addr}");
+                if let Err(e) = self.swarm.dial(addr.clone()) {
+                    warn!("could not dial bootstrap node {addr}: {e}");
+                }
+            }
+        }
+        let mut peers : Vec<PeerId> = self.addr_book.best(TARGET_OUTBOUND_PEERS).into_iter().map(|(peer_id, _)| peer_id).collect();
+        peers.extend(self.swarm.behaviour_mut().routing_table_peers());
+        self.dial_peers(peers);
+        self.swarm.behaviour_mut().find_random_peers();
+    }
+
+    // Misbehaving adds to the misbehavior score of a peer, reaching the
+    // threshold disconnects and bans it
+    fn misbehaving(&mut self, peer_id : &PeerId, what : Misbehavior) {
+        if self.ban_list.misbehaving(peer_id, what, unix_time()) {
+            warn!("ban {peer_id} for {DEFAULT_BAN_SECS}s: {what:?}");
+            self.swarm.behaviour_mut().block_peer(*peer_id);
+        } else {
+            warn!("{peer_id} misbehaved: {what:?}, score {}", self.ban_list.score(peer_id));
+        }
+    }
+
+    fn lift_expired_bans(&mut self) {
+        for peer_id in self.ban_list.expire(unix_time()) {
+            info!("the ban of {peer_id} is over");
+            self.swarm.behaviour_mut().unblock_peer(peer_id);
+        }
+    }
+
+    // DialPeers dials the peers we are not connected to, as many as are
+    // missing to reach TARGET_OUTBOUND_PEERS
+    fn dial_peers(&mut self, peers : Vec<PeerId>) {
+        let missing = TARGET_OUTBOUND_PEERS.saturating_sub(self.outbound_peers.len());
+        let local_peer_id = *self.swarm.local_peer_id();
+        let now = unix_time();
+        let mut seen = HashSet::new();
+        let candidates : Vec<PeerId> = peers.into_iter()
+            .filter(|peer| *peer != local_peer_id && !self.swarm.is_connected(peer) && !self.ban_list.is_banned(peer, now) && seen.insert(*peer))
+            .take(missing)
+            .collect();
+        for peer in candidates {
+            // dialing a peer already being dialed does nothing
+            if let Err(e) = self.swarm.dial(peer) {
+                debug!("could not dial {peer}: {e}");
+            }
+        }
+    }
+
+    // DialDiscovered connects to the peers found by mDNS, their addresses
+    // are registered first so that kademlia and request-response can reach them
+    fn dial_discovered(&mut self, peers : Vec<(PeerId, Multiaddr)>) {
+        let mut discovered = Vec::new();
+        for (peer_id, addr) in peers {
+            info!("MdnsEvent:Discovered: {peer_id} | {addr}");
+            self.swarm.behaviour_mut().register_add_kad(&peer_id, addr.clone());
+            self.swarm.behaviour_mut().register_addr_rr(&peer_id, addr);
+            if !discovered.contains(&peer_id) {
+                discovered.push(peer_id);
+            }
+        }
+        for peer_id in discovered {
+            if self.swarm.is_connected(&peer_id) || self.ban_list.is_banned(&peer_id, unix_time()) {
+                continue;
+            }
+            if let Err(e) = self.swarm.dial(peer_id) {
+                warn!("could not dial {peer_id} found by mDNS: {e}");
+            }
+        }
+    }
     /*************************************************************************************/
     /*************************************************************************************/
-    async fn handle_events(&mut self) -> Result<(),Box<dyn std::error::Error>>{
-        match self.swarm.select_next_some().await {
+    fn handle_events(&mut self, event : SwarmEvent<AgentEvent>) -> Result<(),Box<dyn std::error::Error>>{
+        match event {
             SwarmEvent::NewListenAddr { listener_id, address } => info!("NewListenAddr: {listener_id:?} | {address:?}"),
             /********************************************************/
             SwarmEvent::ConnectionEstablished { 
@@ -376,7 +824,39 @@ impl Server {
                 num_established, 
                 concurrent_dial_errors, 
                 established_in 
-            } => info!("ConnectionEstablished: {peer_id} | {connection_id} | {endpoint:?} | {num_established} | {concurrent_dial_errors:?} | {established_in:?}"),
+            } => {
+                info!("ConnectionEstablished: {peer_id} | {connection_id} | {endpoint:?} | {num_established} | {concurrent_dial_errors:?} | {established_in:?}");
+                if endpoint.is_dialer() {
+                    self.outbound_peers.insert(peer_id);
+                }
+                // the dialer opens the version handshake, the peer is
+                // dropped if it is not done in time
+                if num_established.get() == 1 && !self.node_is_known(&peer_id) {
+                    self.handshakes.insert(peer_id, Instant::now());
+                    if endpoint.is_dialer() {
+                        if let Err(e) = self.send_version(&peer_id) {
+                            warn!("could not send version to {peer_id}: {e}");
+                        }
+                    }
+                }
+            },
+            SwarmEvent::OutgoingConnectionError { peer_id : Some(peer_id), error, .. } => {
+                debug!("could not connect to {peer_id}: {error}");
+                self.addr_book.failed(&peer_id);
+            },
+            // the last connection to a peer is gone, it is dialed again if
+            // the node now lacks outbound peers
+            SwarmEvent::ConnectionClosed { peer_id, num_established : 0, cause, .. } => {
+                info!("disconnected from {peer_id}: {cause:?}");
+                self.remove_node(&peer_id);
+                self.handshakes.remove(&peer_id);
+                self.ban_list.forget(&peer_id);
+                let due = self.last_reconnect.is_none_or(|t| t.elapsed() >= Duration::from_secs(RECONNECT_DELAY_SECS));
+                if self.outbound_peers.remove(&peer_id) && due {
+                    self.last_reconnect = Some(Instant::now());
+                    self.maintain_peers();
+                }
+            },
             /*
                 A new dialing attempt has been initiated by the NetworkBehaviour
                 implementation.A ConnectionEstablished event is reported if the 
@@ -392,7 +872,12 @@ impl Server {
                 IdentifyEvent::Pushed { connection_id : _, peer_id, info } => info!("IdentifyEvent:Pushed: {peer_id} | {info:?}"),
                 IdentifyEvent::Received { connection_id, peer_id, info }=> {
                     info!("IdentifyEvent:Received: {peer_id} | {info:?}");
-                    self.add_node(&peer_id);
+                    if info.protocol_version != params().kad_protocol {
+                        warn!("disconnect {peer_id}: it runs {} instead of {}", info.protocol_version, params().kad_protocol);
+                        let _ = self.swarm.disconnect_peer_id(peer_id);
+                        return Ok(());
+                    }
+                    self.addr_book.seen(&peer_id, info.listen_addrs.clone(), unix_time());
                     for addr in info.listen_addrs.clone(){
                         let agent_routing = self.swarm.behaviour_mut().register_add_kad(&peer_id, addr.clone());
                         match agent_routing {
@@ -416,10 +901,25 @@ impl Server {
                 RequestResponseEvent::Message { peer, connection_id, message }=> {
                     match message {
                         RequestResponseMessage::Request { request_id, request, channel } => {
-                            self.handle_message(request,&peer).unwrap();
+                            let response = match self.handle_request(request,&peer) {
+                                Ok(response) => response,
+                                Err(e) => {
+                                    warn!("request {request_id} from {peer} failed: {e}");
+                                    reject_msg("request", &request_id.to_string(), &e.to_string())
+                                }
+                            };
+                            if self.swarm.behaviour_mut().send_response(channel, response).is_err() {
+                                warn!("could not answer request {request_id} of {peer}: the connection is closed");
+                            }
                         },
                         RequestResponseMessage::Response { request_id, response } => {
-                            info!("RequestResponseEvent::Message::Response -> PeerID: {peer} | RequestID: {request_id} | Response: {response:?}")
+                            let request = self.pending_requests.remove(&request_id);
+                            if !request.is_some_and(|r| answers(&r.message, &response)) {
+                                warn!("{peer} answered request {request_id} with something else");
+                                self.misbehaving(&peer, Misbehavior::UnrequestedData);
+                            } else if let Err(e) = self.handle_response(response,&peer) {
+                                warn!("response {request_id} from {peer} failed: {e}");
+                            }
                         }
                     }
                 },
@@ -429,15 +929,30 @@ impl Server {
                 },
                 // A response to an inbound request has been sent.
                 RequestResponseEvent::ResponseSent { peer, connection_id,request_id } => {
-                    info!("RequestResponseEvent::ResponseSent -> PeerID: {peer} | RequestID: {request_id}")
+                    debug!("RequestResponseEvent::ResponseSent -> PeerID: {peer} | RequestID: {request_id}")
                 },
                 // An outbound request failed.
-                RequestResponseEvent::OutboundFailure { peer, connection_id,request_id, error } => {
-                    warn!("RequestResponseEvent::OutboundFailure -> PeerID: {peer} | RequestID: {request_id} | Error: {error}")
+                RequestResponseEvent::OutboundFailure { peer: _, connection_id,request_id, error } => {
+                    self.request_failed(request_id, &error);
                 },
                 _ => {}
             },
             /***********************************************************/
+            SwarmEvent::Behaviour(AgentEvent::Gossipsub(event)) => match event {
+                gossipsub::Event::Message { propagation_source, message_id, message } => {
+                    let acceptance = self.handle_gossip(&message.data, &propagation_source);
+                    self.swarm.behaviour_mut().report_validation(&message_id, &propagation_source, acceptance);
+                },
+                gossipsub::Event::Subscribed { peer_id, topic } => debug!("GossipsubEvent:Subscribed: {peer_id} | {topic}"),
+                gossipsub::Event::GossipsubNotSupported { peer_id } => warn!("{peer_id} does not support gossipsub"),
+                _ => {}
+            },
+            /***********************************************************/
+            SwarmEvent::Behaviour(AgentEvent::Mdns(event)) => match event {
+                mdns::Event::Discovered(peers) => self.dial_discovered(peers),
+                mdns::Event::Expired(peers) => debug!("MdnsEvent:Expired: {peers:?}"),
+            },
+            /***********************************************************/
             SwarmEvent::Behaviour(AgentEvent::Kad(event)) => match event {
                 kad::Event::ModeChanged { new_mode } => info!("KadEvent:ModeChanged: {new_mode}"),
                 kad::Event::RoutablePeer { peer, address } => info!("KadEvent:RoutablePeer: {peer} | {address}"),
@@ -457,7 +972,20 @@ impl Server {
                     stats, 
                     step } => {
 
-                    info!("KadEvent:OutboundQueryProgressed: ID: {id:?} | Result: {result:?} | Stats: {stats:?} | Step: {step:?}")
+                    debug!("KadEvent:OutboundQueryProgressed: ID: {id:?} | Result: {result:?} | Stats: {stats:?} | Step: {step:?}");
+                    // the peers of a random lookup : remember their addresses
+                    // and connect to them if we lack outbound peers
+                    if let kad::QueryResult::GetClosestPeers(Ok(found)) = result {
+                        let mut peers = Vec::new();
+                        for info in found.peers {
+                            for addr in info.addrs {
+                                self.swarm.behaviour_mut().register_add_kad(&info.peer_id, addr.clone());
+                                self.swarm.behaviour_mut().register_addr_rr(&info.peer_id, addr);
+                            }
+                            peers.push(info.peer_id);
+                        }
+                        self.dial_peers(peers);
+                    }
                 },
                 _ => {}
             }
@@ -467,95 +995,188 @@ impl Server {
         Ok(())
     }
     /****==================>  **********************/
-    pub fn handle_message(&mut self,message : Message, peer_id : &PeerId) ->  Result<(),Box<dyn std::error::Error>> {
-        println!("handle message !!");
-        match message {
-            Message::Tx(data) => self.handle_tx(data,peer_id)?,
-            Message::Version(data) => self.handle_version(data,peer_id)?,
+    // HandleRequest processes a request of a peer and returns the answer
+    // to send back on its response channel
+    pub fn handle_request(&mut self,message : Message, peer_id : &PeerId) ->  Result<Message,Box<dyn std::error::Error>> {
+        if !matches!(message, Message::Version(_)) && !self.node_is_known(peer_id) {
+            return Err(format_err!("no version handshake with {} yet", peer_id).into());
+        }
+        let response = match message {
+            Message::Tx(data) => self.handle_tx(data,peer_id,true)?,
+            Message::Version(data) => {
+                self.handle_version(data,peer_id)?;
+                Message::Version(self.version_msg()?)
+            }
             //Message::Addr(data) => self.handle_addr(data)?,
-            Message::Block(data) => self.handle_block(data,peer_id)?,
-            Message::GetBlock => self.handle_get_blocks(peer_id)?,
+            Message::Block(data) => self.handle_block(data,peer_id,false)?,
+            Message::GetBlock(data) => self.handle_get_blocks(data,peer_id)?,
             Message::GetData(data) => self.handle_get_data(data,peer_id)?,
+            Message::Inv(data) => {
+                self.handle_inv(data,peer_id)?;
+                Message::Ack
+            }
+            Message::Reject(data) => {
+                self.handle_reject(data,peer_id)?;
+                Message::Ack
+            }
+            Message::Ack | Message::NotFound(_) => {
+                self.misbehaving(peer_id, Misbehavior::MalformedMessage);
+                return Err(format_err!("{:?} is a response, not a request", message).into());
+            }
+        };
+        Ok(response)
+    }
+
+    // HandleGossip validates a transaction or a block before gossipsub
+    // forwards it : an invalid block is rejected, which lowers the score of
+    // the peer, a transaction our mempool refuses is only not forwarded
+    fn handle_gossip(&mut self, data : &[u8], source : &PeerId) -> MessageAcceptance {
+        let message : Message = match deserialize(data) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("undecodable gossip from {}: {}", source, e);
+                self.misbehaving(source, Misbehavior::MalformedMessage);
+                return MessageAcceptance::Reject;
+            }
+        };
+        if !self.node_is_known(source) {
+            debug!("ignore gossip from {}: no version handshake yet", source);
+            return MessageAcceptance::Ignore;
+        }
+        let result = match message {
+            Message::Tx(data) => self.handle_tx(data,source,false).map(|r| (r, MessageAcceptance::Ignore)),
+            Message::Block(data) => self.handle_block(data,source,false).map(|r| (r, MessageAcceptance::Reject)),
+            other => {
+                warn!("{} gossiped {:?}", source, other);
+                self.misbehaving(source, Misbehavior::MalformedMessage);
+                return MessageAcceptance::Reject;
+            }
+        };
+        match result {
+            Ok((Message::Reject(_), refused)) => refused,
+            Ok(_) => MessageAcceptance::Accept,
+            Err(e) => {
+                warn!("gossip from {} failed: {}", source, e);
+                MessageAcceptance::Ignore
+            }
+        }
+    }
+
+    // HandleResponse processes what a peer answered to one of our requests
+    fn handle_response(&mut self,message : Message, peer_id : &PeerId) ->  Result<(),Box<dyn std::error::Error>> {
+        match message {
+            Message::Version(data) => self.handle_version(data,peer_id)?,
             Message::Inv(data) => self.handle_inv(data,peer_id)?,
+            // nobody is left to tell about a refused answer, handle_* logged it
+            Message::Block(data) => { self.handle_block(data,peer_id,true)?; }
+            Message::Tx(data) => { self.handle_tx(data,peer_id,true)?; }
+            Message::Reject(data) => self.handle_reject(data,peer_id)?,
+            Message::NotFound(data) => {
+                warn!("{} does not have {} {}", peer_id, data.kind, data.id);
+                if data.kind == "block" {
+                    self.request_next_in_transit(peer_id)?;
+                }
+            }
+            Message::Ack => debug!("{} acknowledged a request", peer_id),
+            Message::GetData(_) | Message::GetBlock(_) => warn!("{} answered with a request: {:?}", peer_id, message),
         }
         Ok(())
     }
-    fn verify_tx(&self, tx: &Transaction) -> Result<bool,Box<dyn std::error::Error>> {
-        self.inner
-            .lock()
-            .unwrap()
-            .utxo
-            .blockchain
-            .verify_transaction(tx)
-    }
     /********************************/
-    fn handle_tx(&mut self, msg: Txmsg,peer_id : &PeerId) -> Result<(),Box<dyn std::error::Error>> {
+    fn handle_tx(&mut self, msg: Txmsg,peer_id : &PeerId,publish : bool) -> Result<Message,Box<dyn std::error::Error>> {
         println!("receive transaction {} , from :{}",peer_id, &msg.transaction.id);
-        self.insert_mempool(msg.transaction.clone());
+        let txid = msg.transaction.id.clone();
+        if self.inner.lock().unwrap().mempool.contains(&txid) {
+            return Ok(Message::Ack);
+        }
+        METRICS.tx_received();
+        if let Err(e) = self.accept_transaction(msg.transaction, publish) {
+            warn!("reject transaction {}: {}",txid,e);
+            METRICS.rejected("tx", e.as_ref());
+            if let Some(what) = reject_kind_of(e.as_ref()).and_then(|k| k.tx_misbehavior()) {
+                self.misbehaving(peer_id, what);
+            }
+            return Ok(reject_msg("tx", &txid, &e.to_string()));
+        }
+        Ok(Message::Ack)
+    }
 
-        let known_nodes = self.get_known_nodes();
-        for node in &known_nodes {
-            if node != self.swarm.local_peer_id() && node != peer_id {
-                self.send_inv(&node, "tx", vec![msg.transaction.id.clone()])?;
+    // AcceptTransaction admits a transaction from a peer or a local client
+    // to the mempool and, with `publish`, announces it to the network. The
+    // ones received over gossip are forwarded by gossipsub itself
+    fn accept_transaction(&mut self, tx: Transaction, publish: bool) -> Result<(),Box<dyn std::error::Error>> {
+        self.insert_mempool(tx.clone())?;
+        // the transaction is accepted from here on, what fails below is only logged
+        if publish {
+            let txid = tx.id.clone();
+            if let Err(e) = self.publish(params().tx_topic, &Message::Tx(Txmsg { transaction : tx })) {
+                warn!("could not announce transaction {}: {}", txid, e);
             }
         }
-        /*********/thread::sleep(Duration::from_secs(2));
+        self.expire_mempool();
         /********Mine the block if nb_transactions > limit *********************/
-        let mut mempool = self.get_mempool();
-        debug!("Current mempool: {:#?}", &mempool);
-        // sent while node was mining old ones 
-        let total_transactions_size: usize = mempool.values().map(|v| v.get_heap_size()).sum();
-        if mempool.len() >= 2 && !self.wallet_address.is_empty()  {
-            loop {
-                info!("Start mining a new block !!!");
-                let mut txs = Vec::new();
-                for (_, tx) in &mempool {
-                    if self.verify_tx(tx)? {
-                        txs.push(tx.clone());
-                    }
-                }
-                if txs.is_empty() {
-                    return Ok(());
-                }
-
-                let cbtx =
-                    Transaction::new_coinbase(self.wallet_address.clone(), String::new())?;
-                txs.push(cbtx);
-
-                for tx in &txs {
-                    mempool.remove(&tx.id);
-                }
-
-                let new_block = self.mine_block(txs)?;
-                self.utxo_reindex()?;
-
-                for node in &self.get_known_nodes() {
-                    if node != self.swarm.local_peer_id() {
-                        info!("send inv msg, new_mined_block_hash {}",new_block.get_hash());
-                        self.send_inv(&node, "block", vec![new_block.get_hash()])?;
-                    }
-                }
-                // to mine a new block based on transactions 
-                // sent while node was mining old ones 
-                if mempool.len() < 2 {
-                    break;
-                }
+        // a running job keeps its template, new transactions go into the next one
+        if !self.miner.is_mining() {
+            if let Err(e) = self.update_mining() {
+                error!("could not start mining: {}", e);
             }
-            self.clear_mempool();
         }
         Ok(())
     }
     /************************************************/
+    // HandleMinedBlock connects a block found by the miner and announces it
+    fn handle_mined_block(&mut self, block: Block) -> Result<(),Box<dyn std::error::Error>> {
+        // the tip moved while the job was running, the block would be an orphan
+        if block.get_prev_hash() != self.get_tip_hash() {
+            info!("drop stale mined block {}", block.get_hash());
+            return Ok(());
+        }
+        info!("mined new block {} at height {}", block.get_hash(), block.get_height());
+        self.policy.rotate_payout();
+        self.submit_block(block)
+    }
+    /************************************************/
+    // HandleVersion completes the handshake with a peer : an incompatible
+    // one is disconnected, the others become known, and as both ends see the
+    // height of the other one, the node behind a peer serving the whole
+    // chain asks it for the blocks
     fn handle_version(&mut self, msg: Versionmsg, peer_id : &PeerId) -> Result<(),Box<dyn std::error::Error>> {
         info!("receive version msg: {:#?}", msg);
-        let my_best_height = self.get_best_height()?;
-        if my_best_height < msg.best_height {
-            self.send_get_blocks(peer_id)?;
-        } else if my_best_height > msg.best_height {
-            self.send_version(peer_id)?;
+        if let Err(reason) = msg.check(params().genesis_hash, self.nonce) {
+            warn!("disconnect {peer_id}: {reason}");
+            self.handshakes.remove(peer_id);
+            self.addr_book.remove(peer_id);
+            let _ = self.swarm.disconnect_peer_id(*peer_id);
+            return Err(format_err!("incompatible version: {}", reason).into());
+        }
+        self.add_time_offset(peer_id, msg.timestamp);
+        self.handshakes.remove(peer_id);
+        if self.add_node(peer_id) {
+            info!("handshake with {peer_id} done: {} protocol {} services {:?}", msg.user_agent, msg.version, service_names(msg.services));
+            self.addr_book.connected(peer_id, Vec::new(), unix_time());
+        }
+        let syncs = msg.services & NODE_NETWORK != 0 && self.get_best_height()? < msg.best_height;
+        self.inner.lock().unwrap().peer_versions.insert(*peer_id, msg);
+        if syncs {
+            let locator = self.block_locator();
+            self.send_get_blocks(peer_id, locator)?;
         }
         Ok(())
     }
+
+    // DropSilentPeers disconnects the peers that did not complete the
+    // version handshake in time
+    fn drop_silent_peers(&mut self) {
+        let silent : Vec<PeerId> = self.handshakes.iter()
+            .filter(|(_, since)| since.elapsed() >= Duration::from_secs(HANDSHAKE_TIMEOUT_SECS))
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in silent {
+            warn!("disconnect {peer_id}: no version handshake after {HANDSHAKE_TIMEOUT_SECS}s");
+            self.handshakes.remove(&peer_id);
+            let _ = self.swarm.disconnect_peer_id(peer_id);
+        }
+    }
     /*************************************************/
     /*fn handle_addr(&self, msg: Vec<String>) -> Result<(),Box<dyn std::error::Error>> {
         info!("receive address msg: {:#?}", msg);
@@ -566,79 +1187,829 @@ impl Server {
         Ok(())
     }*/
     /*************************************************/
-    fn handle_block(&mut self, msg: Blockmsg,peer_id: &PeerId) -> Result<(),Box<dyn std::error::Error>> {
+    // HandleBlock adds a block of a peer to the block tree, `requested` tells
+    // whether it answers one of our requests
+    fn handle_block(&mut self, msg: Blockmsg,peer_id: &PeerId, requested: bool) -> Result<Message,Box<dyn std::error::Error>> {
         info!(
             "receive block msg {} from: {}",
             peer_id,
             msg.block.get_hash()
         );
-        self.add_block(msg.block);
+        let block = msg.block;
+        let block_hash = block.get_hash();
+        METRICS.block_received();
+        let change = match self.add_block(block) {
+            Ok(change) => change,
+            Err(e) => {
+                warn!("reject block from {}: {}",peer_id,e);
+                METRICS.rejected("block", e.as_ref());
+                if let Some(what) = reject_kind_of(e.as_ref()).and_then(|k| k.block_misbehavior()) {
+                    self.misbehaving(peer_id, what);
+                }
+                return Ok(reject_msg("block", &block_hash, &e.to_string()));
+            }
+        };
+        // an announced block should build on one we have, the peer is asked
+        // for the blocks we miss
+        if !requested && self.is_orphan(&block_hash)? {
+            self.misbehaving(peer_id, Misbehavior::UnrequestedOrphan);
+            let locator = self.block_locator();
+            self.send_get_blocks(peer_id, locator)?;
+        }
+        if !change.connected.is_empty() {
+            self.apply_tip_change(change)?;
+            // the template being mined no longer extends the tip
+            self.update_mining()?;
+        }
+        self.request_next_in_transit(peer_id)?;
+        Ok(Message::Ack)
+    }
 
+    // RequestNextInTransit asks the peer for the next block of an inventory
+    // being downloaded, or for the next inventory after a full one
+    fn request_next_in_transit(&mut self, peer_id: &PeerId) -> Result<(),Box<dyn std::error::Error>> {
         let mut in_transit = self.get_in_transit();
         if in_transit.len() > 0 {
-            let block_hash = &in_transit[0];
-            self.send_get_data(peer_id, "block", block_hash)?;
-            in_transit.remove(0);
+            let block_hash = in_transit.remove(0);
+            self.send_get_data(peer_id, "block", &block_hash)?;
             self.replace_in_transit(in_transit);
-        }else{
-            self.utxo_reindex().unwrap();
+            return Ok(());
+        }
+        let blocks_continue = self.inner.lock().unwrap().blocks_continue.take();
+        if let Some(last_hash) = blocks_continue {
+            let mut locator = vec![last_hash];
+            locator.extend(self.block_locator());
+            self.send_get_blocks(peer_id, locator)?;
         }
         Ok(())
     }
     /*************************************************/
-    fn handle_get_blocks(&mut self,peer_id :&PeerId) -> Result<(),Box<dyn std::error::Error>> {
-        info!("receive get blocks msg:");
-        let block_hashs = self.get_block_hashs();
-        self.send_inv(peer_id, "block", block_hashs)?;
+    fn handle_reject(&mut self, msg: Rejectmsg, peer_id: &PeerId) -> Result<(),Box<dyn std::error::Error>> {
+        warn!("{} rejected {} {}: {}", peer_id, msg.kind, msg.id, msg.reason);
         Ok(())
     }
     /*************************************************/
-    fn handle_get_data(&mut self, msg: GetDatamsg,peer_id :&PeerId) -> Result<(),Box<dyn std::error::Error>> {
-        info!("receive get data msg: {:#?}", msg);
-        if msg.kind == "block" {
-            let block = self.get_block(&msg.id)?;
-            self.send_block(peer_id ,&block)?;
-        } else if msg.kind == "tx" {
-            let tx = self.get_mempool_tx(&msg.id).unwrap();
-            self.send_tx(peer_id, &tx)?;
+    fn handle_get_blocks(&mut self, msg: GetBlocksmsg, peer_id :&PeerId) -> Result<Message,Box<dyn std::error::Error>> {
+        info!("receive get blocks msg from {}", peer_id);
+        if msg.locator.len() > MAX_LOCATOR_HASHES {
+            self.misbehaving(peer_id, Misbehavior::OversizedMessage);
+            return Err(format_err!("block locator of {} hashes, more than {}", msg.locator.len(), MAX_LOCATOR_HASHES).into());
         }
-        Ok(())
+        let items = self.inner.lock().unwrap().utxo.blockchain.blocks_after(&msg.locator, MAX_BLOCKS_PER_INV);
+        Ok(Message::Inv(Invmsg { kind : "block".to_string(), items }))
+    }
+    /*************************************************/
+    fn handle_get_data(&mut self, msg: GetDatamsg,peer_id :&PeerId) -> Result<Message,Box<dyn std::error::Error>> {
+        info!("receive get data msg from {}: {:#?}", peer_id, msg);
+        let found = match msg.kind.as_str() {
+            "block" => self.get_block(&msg.id).ok().map(|block| Message::Block(Blockmsg { block })),
+            "tx" => self.get_mempool_tx(&msg.id).map(|transaction| Message::Tx(Txmsg { transaction })),
+            _ => None,
+        };
+        Ok(found.unwrap_or(Message::NotFound(msg)))
     }
     /*************************************************/
     fn handle_inv(&mut self, msg: Invmsg,peer_id :&PeerId) -> Result<(),Box<dyn std::error::Error>> {
         info!("receive inv msg: {:#?}", msg);
+        let max_items = if msg.kind == "block" { MAX_BLOCKS_PER_INV } else { MAX_INV_ITEMS };
+        if msg.items.len() > max_items {
+            self.misbehaving(peer_id, Misbehavior::OversizedMessage);
+            return Err(format_err!("inventory of {} items, more than {}", msg.items.len(), max_items).into());
+        }
+        // an empty block inventory means the peer has nothing after our locator
+        if msg.kind != "block" && (msg.kind != "tx" || msg.items.is_empty()) {
+            self.misbehaving(peer_id, Misbehavior::MalformedMessage);
+            return Err(format_err!("bogus {} inventory of {} items", msg.kind, msg.items.len()).into());
+        }
         if msg.kind == "block" {
-            let block_hash = &msg.items[0];
-            self.send_get_data(peer_id, "block", block_hash)?;
-
+            // the blocks come lowest first, the known ones are skipped
             let mut new_in_transit = Vec::new();
             for b in &msg.items {
-                if b != block_hash {
+                if !self.inner.lock().unwrap().utxo.blockchain.has_block(b)? {
                     new_in_transit.push(b.clone());
                 }
             }
             self.replace_in_transit(new_in_transit);
+            self.inner.lock().unwrap().blocks_continue = match msg.items.last() {
+                Some(last_hash) if msg.items.len() == MAX_BLOCKS_PER_INV => Some(last_hash.clone()),
+                _ => None,
+            };
+            self.request_next_in_transit(peer_id)?;
         } else if msg.kind == "tx" {
-            let txid = &msg.items[0];
-            match self.get_mempool_tx(txid) {
-                Some(tx) => {
-                    if tx.id.is_empty() {
-                        self.send_get_data(peer_id, "tx", txid)?
-                    }
+            // every transaction the mempool lacks, each once
+            let mut seen = HashSet::new();
+            for txid in &msg.items {
+                if seen.insert(txid) && self.get_mempool_tx(txid).is_none() {
+                    self.send_get_data(peer_id, "tx", txid)?;
                 }
-                None => self.send_get_data(peer_id, "tx", txid)?,
             }
         }
         Ok(())
     }
     /********************************************************************/
-    /********used in handle block, handle tx******************************/
-    fn utxo_reindex(&self) -> Result<(),Box<dyn std::error::Error>> {
-        self.inner.lock().unwrap().utxo.reindex()
+    /********************************JSON-RPC****************************/
+    // StartRpc serves the JSON-RPC interface of the node on `addr`, and the
+    // REST explorer and the event stream next to it when `rest` is set, and
+    // the Prometheus metrics with `metrics`
+    pub async fn start_rpc(&mut self, addr: &str, auth: RpcAuth, rest: bool, metrics: bool) -> Result<(),Box<dyn std::error::Error>> {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        let public = rpc::PublicEndpoints { rest : rest.then(|| self.events.clone()), metrics };
+        if rest {
+            self.services |= NODE_LIGHT_SERVER;
+        }
+        tokio::spawn(rpc::serve(listener, self.rpc_sender.clone(), auth.clone(), public));
+        self.rpc_auth = Some(auth);
+        Ok(())
+    }
+
+    fn handle_rpc(&mut self, call: RpcCall) {
+        debug!("rpc call {} {}", call.method, call.params);
+        // answered once its blocks are mined
+        if call.method == "generate" {
+            return self.rpc_generate(call);
+        }
+        let result = match call.method.as_str() {
+            "getblocktemplate" => self.rpc_get_block_template(&call.params),
+            "submitblock" => self.rpc_submit_block(&call.params),
+            "setgenerate" => self.rpc_set_generate(&call.params),
+            "getmininginfo" => self.rpc_get_mining_info(),
+            "invalidateblock" => self.rpc_invalidate_block(&call.params),
+            "reconsiderblock" => self.rpc_reconsider_block(&call.params),
+            "getblockcount" => self.get_best_height().map(|h| serde_json::json!(h)).map_err(RpcError::from),
+            "getbestblockhash" => Ok(serde_json::json!(self.get_tip_hash())),
+            "getblock" => self.rpc_get_block(&call.params),
+            "getblockhash" => self.rpc_get_block_hash(&call.params),
+            "getrawtransaction" => self.rpc_get_raw_transaction(&call.params),
+            "sendrawtransaction" => self.rpc_send_raw_transaction(&call.params),
+            "sendtoaddress" => self.rpc_send_to_address(&call.params),
+            "bumpfee" => self.rpc_bump_fee(&call.params),
+            "getbalance" => self.rpc_get_balance(&call.params),
+            "getmempoolinfo" => self.rpc_get_mempool_info(),
+            "getpeerinfo" => self.rpc_get_peer_info(),
+            "listbanned" => self.rpc_list_banned(),
+            "setban" => self.rpc_set_ban(&call.params),
+            "clearbanned" => self.rpc_clear_banned(),
+            "rest.chaininfo" => self.rest_chain_info(),
+            "rest.tip" => self.rest_tip(),
+            "rest.block" => self.rest_block(&call.params),
+            "rest.blockbyheight" => self.rest_block_by_height(&call.params),
+            "rest.tx" => self.rest_tx(&call.params),
+            "rest.addresstxs" => self.rest_address_txs(&call.params),
+            "rest.addressutxos" => self.rest_address_utxos(&call.params),
+            "rest.mempool" => self.rest_mempool(),
+            "node.metrics" => self.scrape_metrics(),
+            "stop" => {
+                self.stopping = true;
+                Ok(serde_json::json!("node stopping"))
+            }
+            _ => Err(RpcError::new(rpc::METHOD_NOT_FOUND, &format!("unknown method {}", call.method))),
+        };
+        // the client may have hung up already
+        let _ = call.reply.send(result);
+    }
+
+    // getblocktemplate [address] : the block to mine on top of the tip, the
+    // coinbase pays `address` or the node wallet address
+    fn rpc_get_block_template(&self, params: &serde_json::Value) -> RpcResult {
+        let address = match rpc::param(params, 0) {
+            Some(_) => rpc::param_str(params, 0, "address")?,
+            None => match self.policy.payout_address() {
+                Some(address) => address.to_string(),
+                None => return Err(RpcError::new(rpc::INVALID_PARAMS, "no payout address given and the node has none configured")),
+            },
+        };
+        let (template, _) = self.new_block_template(&address)?;
+        let (coinbase, txs) = template.get_transaction().split_first()
+            .ok_or_else(|| RpcError::new(rpc::MISC_ERROR, "empty template"))?;
+        let mut transactions = Vec::new();
+        for tx in txs {
+            transactions.push(serde_json::json!({
+                "txid" : tx.id,
+                "data" : hex::encode(serialize(tx)?),
+                "weight" : tx.weight()?,
+            }));
+        }
+        Ok(serde_json::json!({
+            "height" : template.get_height(),
+            "previousblockhash" : template.get_prev_hash(),
+            "curtime" : template.get_timestamp(),
+            // a valid block hash starts with this many zero hex digits
+            "target" : crate::chainparams::params().target_hex,
+            "transactions" : transactions,
+            "coinbasevalue" : coinbase.value_out()?.as_units(),
+            // the whole template, ready for the proof of work
+            "block" : hex::encode(serialize(&template)?),
+        }))
+    }
+
+    // submitblock <hex> : validate a solved block, connect and relay it
+    fn rpc_submit_block(&mut self, params: &serde_json::Value) -> RpcResult {
+        let data = hex::decode(rpc::param_str(params, 0, "hexdata")?)
+            .map_err(|e| RpcError::new(rpc::INVALID_PARAMS, &e.to_string()))?;
+        let block : Block = deserialize(&data)
+            .map_err(|e| RpcError::new(rpc::INVALID_PARAMS, &format!("block decode failed: {}", e)))?;
+        let hash = block.get_hash();
+        self.submit_block(block).map_err(|e| RpcError::new(rpc::VERIFY_ERROR, &e.to_string()))?;
+        info!("accepted submitted block {}", hash);
+        Ok(serde_json::Value::String(hash))
+    }
+
+    // setgenerate <true|false> : turn the built-in miner on or off
+    fn rpc_set_generate(&mut self, params: &serde_json::Value) -> RpcResult {
+        let generate = rpc::param(params, 0).and_then(|v| v.as_bool())
+            .ok_or_else(|| RpcError::new(rpc::INVALID_PARAMS, "missing boolean parameter generate"))?;
+        if generate && self.policy.payout_addresses.is_empty() {
+            return Err(RpcError::new(rpc::INVALID_PARAMS, "the node has no payout address to mine for"));
+        }
+        info!("mining {}", if generate { "enabled" } else { "disabled" });
+        self.policy.enabled = generate;
+        self.update_mining()?;
+        Ok(serde_json::Value::Bool(generate))
+    }
+
+    fn rpc_get_mining_info(&self) -> RpcResult {
+        Ok(serde_json::json!({
+            "blocks" : self.get_best_height()?,
+            "pooledtx" : self.mempool_len(),
+            "generate" : self.policy.enabled,
+            "mining" : self.miner.is_mining(),
+            "mintxs" : self.policy.min_txs,
+            "minfees" : self.policy.min_fees.to_string(),
+            "maxblockinterval" : self.policy.max_block_interval,
+            "payoutaddresses" : self.policy.payout_addresses,
+        }))
+    }
+    // generate <n> [address] : regtest only, mine n blocks right away and
+    // return their hashes, the coinbases pay `address` or the node
+    fn rpc_generate(&mut self, call: RpcCall) {
+        if self.generate.is_some() {
+            let _ = call.reply.send(Err(RpcError::new(rpc::MISC_ERROR, "a generate call is already running")));
+            return;
+        }
+        match self.generate_params(&call.params) {
+            Ok((remaining, address)) => {
+                self.generate = Some(GenerateJob { address, remaining, hashes : Vec::new(), reply : call.reply });
+                self.generate_next();
+            }
+            Err(e) => {
+                let _ = call.reply.send(Err(e));
+            }
+        }
+    }
+
+    // GenerateParams reads the number of blocks and the address of a generate call
+    fn generate_params(&self, params: &serde_json::Value) -> Result<(u64, String), RpcError> {
+        if crate::chainparams::params().network != Network::Regtest {
+            return Err(RpcError::new(rpc::MISC_ERROR, "generate is only available on regtest"));
+        }
+        let blocks = rpc::param(params, 0).and_then(|v| v.as_u64())
+            .ok_or_else(|| RpcError::new(rpc::INVALID_PARAMS, "missing number of blocks"))?;
+        if blocks > MAX_GENERATE_BLOCKS {
+            return Err(RpcError::new(rpc::INVALID_PARAMS, &format!("at most {} blocks per call", MAX_GENERATE_BLOCKS)));
+        }
+        let address = match rpc::param(params, 1) {
+            Some(_) => rpc::param_str(params, 1, "address")?,
+            None => match self.policy.payout_address() {
+                Some(address) => address.to_string(),
+                None => return Err(RpcError::new(rpc::INVALID_PARAMS, "no address given and the node has none configured")),
+            },
+        };
+        Ok((blocks, address))
+    }
+
+    // GenerateNext hands the next block of the generate call to the
+    // generator threads, or answers the call once every block is connected
+    fn generate_next(&mut self) {
+        let address = match &self.generate {
+            Some(job) if job.remaining > 0 => job.address.clone(),
+            Some(_) => {
+                let hashes = std::mem::take(&mut self.generate.as_mut().unwrap().hashes);
+                info!("generated {} blocks", hashes.len());
+                self.finish_generate(Ok(serde_json::json!(hashes)));
+                return;
+            }
+            None => return,
+        };
+        // a block found by the background job would only turn stale
+        self.miner.cancel();
+        match self.new_block_template(&address) {
+            Ok((template, _)) => self.generator.start(template),
+            Err(e) => self.finish_generate(Err(RpcError::from(e))),
+        }
+    }
+
+    // FinishGenerate answers the generate call and lets the background job
+    // mine again
+    fn finish_generate(&mut self, result: RpcResult) {
+        if let Some(job) = self.generate.take() {
+            // the client may have hung up already
+            let _ = job.reply.send(result);
+        }
+        if let Err(e) = self.update_mining() {
+            error!("could not start mining: {}", e);
+        }
+    }
+
+    // HandleGeneratedBlock connects a block of the generate call, one built
+    // on a tip that moved meanwhile is mined again on the new one
+    fn handle_generated_block(&mut self, block: Block) {
+        if self.generate.is_none() {
+            return;
+        }
+        if block.get_prev_hash() != self.get_tip_hash() {
+            info!("drop stale generated block {}", block.get_hash());
+            return self.generate_next();
+        }
+        let hash = block.get_hash();
+        if let Err(e) = self.submit_block(block) {
+            return self.finish_generate(Err(RpcError::new(rpc::VERIFY_ERROR, &e.to_string())));
+        }
+        if let Some(job) = self.generate.as_mut() {
+            job.hashes.push(hash);
+            job.remaining -= 1;
+        }
+        self.generate_next();
+    }
+
+    // invalidateblock <hash> : treat a block and its descendants as invalid
+    fn rpc_invalidate_block(&mut self, params: &serde_json::Value) -> RpcResult {
+        let hash = rpc::param_str(params, 0, "blockhash")?;
+        let change = self.inner.lock().unwrap().utxo.blockchain.invalidate_block(&hash)?;
+        self.apply_tip_change(change)?;
+        self.update_mining()?;
+        Ok(serde_json::Value::Null)
+    }
+
+    // reconsiderblock <hash> : undo invalidateblock on a block and its family
+    fn rpc_reconsider_block(&mut self, params: &serde_json::Value) -> RpcResult {
+        let hash = rpc::param_str(params, 0, "blockhash")?;
+        let change = self.inner.lock().unwrap().utxo.blockchain.reconsider_block(&hash)?;
+        self.apply_tip_change(change)?;
+        self.update_mining()?;
+        Ok(serde_json::Value::Null)
+    }
+    // getblock <hash> [verbose] : the block as JSON, or hex encoded when
+    // verbose is false
+    fn rpc_get_block(&self, params: &serde_json::Value) -> RpcResult {
+        let hash = rpc::param_str(params, 0, "blockhash")?;
+        let block = self.get_block(&hash).map_err(|e| RpcError::new(rpc::INVALID_PARAMS, &e.to_string()))?;
+        if !rpc::param(params, 1).and_then(|v| v.as_bool()).unwrap_or(true) {
+            return Ok(serde_json::json!(hex::encode(serialize(&block)?)));
+        }
+        self.block_to_json(&block, false)
+    }
+
+    // BlockToJson describes a block with its transaction ids, or the whole
+    // transactions with `full_tx`
+    fn block_to_json(&self, block: &Block, full_tx: bool) -> RpcResult {
+        let hash = block.get_hash();
+        let best_height = self.get_best_height()?;
+        let in_active_chain = self.inner.lock().unwrap().utxo.blockchain
+            .get_block_hash(block.get_height()).is_ok_and(|h| h == hash);
+        let mut txs = Vec::new();
+        for tx in block.get_transaction() {
+            txs.push(if full_tx { tx_to_json(tx)? } else { serde_json::json!(tx.id) });
+        }
+        Ok(serde_json::json!({
+            "hash" : hash,
+            // -1 for a block outside the active chain
+            "confirmations" : if in_active_chain { best_height - block.get_height() + 1 } else { -1 },
+            "height" : block.get_height(),
+            "previousblockhash" : block.get_prev_hash(),
+            "time" : block.get_timestamp(),
+            "nonce" : block.get_nonce(),
+            "size" : block.get_size()?,
+            "weight" : block.get_weight()?,
+            "tx" : txs,
+        }))
+    }
+
+    // getblockhash <height> : hash of the active chain block at height
+    fn rpc_get_block_hash(&self, params: &serde_json::Value) -> RpcResult {
+        let height = rpc::param(params, 0).and_then(|v| v.as_i64())
+            .ok_or_else(|| RpcError::new(rpc::INVALID_PARAMS, "missing height"))?;
+        let hash = self.inner.lock().unwrap().utxo.blockchain.get_block_hash(height as i32)
+            .map_err(|e| RpcError::new(rpc::INVALID_PARAMS, &e.to_string()))?;
+        Ok(serde_json::json!(hash))
+    }
+
+    // getrawtransaction <txid> [verbose] : a mempool or confirmed transaction,
+    // hex encoded unless verbose is true
+    fn rpc_get_raw_transaction(&self, params: &serde_json::Value) -> RpcResult {
+        let txid = rpc::param_str(params, 0, "txid")?;
+        let verbose = rpc::param(params, 1).and_then(|v| v.as_bool()).unwrap_or(false);
+        let (tx, blockhash) = match self.get_mempool_tx(&txid) {
+            Some(tx) => (tx, None),
+            None => {
+                let (tx, hash) = self.inner.lock().unwrap().utxo.blockchain.find_transaction_block(&txid)
+                    .map_err(|_| RpcError::new(rpc::INVALID_PARAMS, &format!("no transaction {} in the mempool or the active chain", txid)))?;
+                (tx, Some(hash))
+            }
+        };
+        if !verbose {
+            return Ok(serde_json::json!(hex::encode(serialize(&tx)?)));
+        }
+        let mut value = tx_to_json(&tx)?;
+        // what a pending transaction pays, confirmed ones have a block instead
+        if let Some(entry) = self.inner.lock().unwrap().mempool.get_entry(&txid) {
+            value["fee"] = serde_json::json!(entry.fee.to_string());
+        }
+        value["blockhash"] = serde_json::json!(blockhash);
+        Ok(value)
+    }
+
+    // sendrawtransaction <hex> : admit a signed transaction and relay it
+    fn rpc_send_raw_transaction(&mut self, params: &serde_json::Value) -> RpcResult {
+        let data = hex::decode(rpc::param_str(params, 0, "hexstring")?)
+            .map_err(|e| RpcError::new(rpc::INVALID_PARAMS, &e.to_string()))?;
+        let tx : Transaction = deserialize(&data)
+            .map_err(|e| RpcError::new(rpc::INVALID_PARAMS, &format!("transaction decode failed: {}", e)))?;
+        let txid = tx.id.clone();
+        self.accept_transaction(tx, true).map_err(|e| RpcError::new(rpc::VERIFY_ERROR, &e.to_string()))?;
+        Ok(serde_json::json!(txid))
+    }
+
+    // sendtoaddress <from> <to> <amount> [fee] [replaceable] [locktime] :
+    // pay from a wallet address of the node and relay the transaction
+    fn rpc_send_to_address(&mut self, params: &serde_json::Value) -> RpcResult {
+        let from = rpc::param_str(params, 0, "from")?;
+        let to = rpc::param_str(params, 1, "to")?;
+        let amount = rpc::param_amount(params, 2, "amount")?;
+        let fee = match rpc::param(params, 3) {
+            Some(_) => rpc::param_amount(params, 3, "fee")?,
+            None => Amount::ZERO,
+        };
+        let replaceable = rpc::param(params, 4).and_then(|v| v.as_bool()).unwrap_or(false);
+        let lock_time = rpc::param(params, 5).and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+        let tx = {
+            let inner = self.inner.lock().unwrap();
+            Transaction::new_UTXO(&from, &to, amount, fee, replaceable, lock_time, &inner.utxo)?
+        };
+        Wallets::new()?.save_transaction(&tx)?;
+        let txid = tx.id.clone();
+        self.accept_transaction(tx, true).map_err(|e| RpcError::new(rpc::VERIFY_ERROR, &e.to_string()))?;
+        info!("sent {} from {} to {} in {}", amount, from, to, txid);
+        Ok(serde_json::json!(txid))
+    }
+
+    // bumpfee <txid> [fee] : replace a pending wallet transaction by one
+    // paying a higher fee
+    fn rpc_bump_fee(&mut self, params: &serde_json::Value) -> RpcResult {
+        let txid = rpc::param_str(params, 0, "txid")?;
+        let fee = match rpc::param(params, 1) {
+            Some(_) => Some(rpc::param_amount(params, 1, "fee")?),
+            None => None,
+        };
+        let wallets = Wallets::new()?;
+        let tx = wallets.get_transaction(&txid)?
+            .ok_or_else(|| RpcError::new(rpc::INVALID_PARAMS, &format!("transaction {} was not sent from this wallet", txid)))?;
+        let replacement = {
+            let inner = self.inner.lock().unwrap();
+            tx.bump_fee(fee, &wallets, &inner.mempool, &inner.utxo)?
+        };
+        wallets.save_transaction(&replacement)?;
+        let replacement_id = replacement.id.clone();
+        self.accept_transaction(replacement, true).map_err(|e| RpcError::new(rpc::VERIFY_ERROR, &e.to_string()))?;
+        Ok(serde_json::json!({ "txid" : replacement_id, "replaces" : txid }))
+    }
+
+    // getbalance <address> : confirmed balance of an address
+    fn rpc_get_balance(&self, params: &serde_json::Value) -> RpcResult {
+        let address = rpc::param_str(params, 0, "address")?;
+        let pub_key_hash = crate::chainparams::params().decode_address(&address)
+            .map_err(|e| RpcError::new(rpc::INVALID_PARAMS, &e.to_string()))?;
+        let utxos = self.inner.lock().unwrap().utxo.find_UTXO(&pub_key_hash)?;
+        let balance = Amount::checked_sum(utxos.iter().map(|(_,out)| out.value))
+            .ok_or_else(|| RpcError::new(rpc::MISC_ERROR, &format!("balance of {} out of range", address)))?;
+        Ok(serde_json::json!(balance.to_string()))
+    }
+
+    fn rpc_get_mempool_info(&self) -> RpcResult {
+        let inner = self.inner.lock().unwrap();
+        Ok(serde_json::json!({
+            "size" : inner.mempool.len(),
+            "weight" : inner.mempool.total_weight(),
+            "fees" : inner.mempool.total_fees().to_string(),
+            "maxweight" : MEMPOOL_MAX_WEIGHT,
+        }))
+    }
+
+    fn rpc_get_peer_info(&self) -> RpcResult {
+        let inner = self.inner.lock().unwrap();
+        let mut peers = Vec::new();
+        for peer in &inner.known_peers {
+            let record = self.addr_book.get(peer);
+            let version = inner.peer_versions.get(peer);
+            peers.push(serde_json::json!({
+                "id" : peer.to_string(),
+                "connected" : self.swarm.is_connected(peer),
+                "outbound" : self.outbound_peers.contains(peer),
+                "banscore" : self.ban_list.score(peer),
+                "version" : version.map(|v| v.version),
+                "subver" : version.map(|v| v.user_agent.clone()),
+                "services" : version.map(|v| service_names(v.services)),
+                "startingheight" : version.map(|v| v.best_height),
+                "timeoffset" : inner.time_offsets.get(peer),
+                "addrs" : record.map(|r| r.addrs.iter().map(|a| a.to_string()).collect::<Vec<_>>()),
+                "lastseen" : record.map(|r| r.last_seen),
+                "successes" : record.map(|r| r.successes),
+                "failures" : record.map(|r| r.failures),
+            }));
+        }
+        Ok(serde_json::json!(peers))
+    }
+
+    fn rpc_list_banned(&self) -> RpcResult {
+        let banned : Vec<serde_json::Value> = self.ban_list.banned().into_iter()
+            .map(|(peer_id, until)| serde_json::json!({ "id" : peer_id.to_string(), "banneduntil" : until }))
+            .collect();
+        Ok(serde_json::json!(banned))
+    }
+
+    // setban <peer id> add|remove [bantime] : ban a peer for bantime seconds
+    // [default: DEFAULT_BAN_SECS] or lift its ban
+    fn rpc_set_ban(&mut self, params: &serde_json::Value) -> RpcResult {
+        let peer = rpc::param_str(params, 0, "peerid")?;
+        let peer_id : PeerId = peer.parse()
+            .map_err(|_| RpcError::new(rpc::INVALID_PARAMS, &format!("invalid peer id {}", peer)))?;
+        match rpc::param_str(params, 1, "command")?.as_str() {
+            "add" => {
+                let bantime = match rpc::param(params, 2) {
+                    Some(v) => v.as_u64().ok_or_else(|| RpcError::new(rpc::INVALID_PARAMS, "bantime is a number of seconds"))?,
+                    None => DEFAULT_BAN_SECS,
+                };
+                info!("ban {peer_id} for {bantime}s on request");
+                self.ban_list.ban(&peer_id, unix_time() + bantime);
+                self.swarm.behaviour_mut().block_peer(peer_id);
+            }
+            "remove" => {
+                if !self.ban_list.unban(&peer_id) {
+                    return Err(RpcError::new(rpc::NOT_FOUND, &format!("{} is not banned", peer_id)));
+                }
+                self.swarm.behaviour_mut().unblock_peer(peer_id);
+            }
+            command => return Err(RpcError::new(rpc::INVALID_PARAMS, &format!("unknown command {}, expected add or remove", command))),
+        }
+        Ok(serde_json::Value::Null)
+    }
+
+    fn rpc_clear_banned(&mut self) -> RpcResult {
+        for peer_id in self.ban_list.clear() {
+            self.swarm.behaviour_mut().unblock_peer(peer_id);
+        }
+        Ok(serde_json::Value::Null)
+    }
+    /********************************************************************/
+    /*****************************REST explorer**************************/
+    fn rest_chain_info(&self) -> RpcResult {
+        let inner = self.inner.lock().unwrap();
+        let blockchain = &inner.utxo.blockchain;
+        Ok(serde_json::json!({
+            "network" : crate::chainparams::params().name,
+            "blocks" : blockchain.get_best_height()?,
+            "bestblockhash" : blockchain.current_hash,
+            "mediantime" : blockchain.median_time_past(&blockchain.current_hash)? / 1000,
+            "mempoolsize" : inner.mempool.len(),
+        }))
+    }
+
+    fn rest_tip(&self) -> RpcResult {
+        let tip = self.get_block(&self.get_tip_hash())?;
+        Ok(serde_json::json!({
+            "hash" : tip.get_hash(),
+            "height" : tip.get_height(),
+            "time" : tip.get_timestamp(),
+        }))
+    }
+
+    fn rest_block(&self, params: &serde_json::Value) -> RpcResult {
+        let hash = rpc::param_str(params, 0, "blockhash")?;
+        let block = self.get_block(&hash).map_err(|e| RpcError::new(rpc::NOT_FOUND, &e.to_string()))?;
+        self.block_to_json(&block, true)
+    }
+
+    fn rest_block_by_height(&self, params: &serde_json::Value) -> RpcResult {
+        let height = rpc::param(params, 0).and_then(|v| v.as_i64())
+            .ok_or_else(|| RpcError::new(rpc::INVALID_PARAMS, "missing height"))?;
+        let hash = self.inner.lock().unwrap().utxo.blockchain.get_block_hash(height as i32)
+            .map_err(|e| RpcError::new(rpc::NOT_FOUND, &e.to_string()))?;
+        self.block_to_json(&self.get_block(&hash)?, true)
+    }
+
+    fn rest_tx(&self, params: &serde_json::Value) -> RpcResult {
+        let txid = rpc::param_str(params, 0, "txid")?;
+        let best_height = self.get_best_height()?;
+        if let Some(entry) = self.inner.lock().unwrap().mempool.get_entry(&txid) {
+            return pending_tx_json(entry);
+        }
+        let (tx, hash) = self.inner.lock().unwrap().utxo.blockchain.find_transaction_block(&txid)
+            .map_err(|_| RpcError::new(rpc::NOT_FOUND, &format!("no transaction {} in the mempool or the active chain", txid)))?;
+        let height = self.get_block(&hash)?.get_height();
+        confirmed_tx_json(&tx, &hash, height, best_height)
     }
 
+    // the pending transactions of an address come first, then the confirmed
+    // ones from the newest
+    fn rest_address_txs(&self, params: &serde_json::Value) -> RpcResult {
+        let (address, pub_key_hash) = rest_address(params)?;
+        let best_height = self.get_best_height()?;
+        let inner = self.inner.lock().unwrap();
+        let mut pending : Vec<&MempoolEntry> = inner.mempool.entries().filter(|entry| {
+            entry.tx.vin.iter().any(|vin| vin.can_unlock_output_with(&pub_key_hash))
+                || entry.tx.vout.iter().any(|out| out.can_be_unlock_with(&pub_key_hash))
+        }).collect();
+        pending.sort_by(|a, b| b.time.cmp(&a.time).then_with(|| a.tx.id.cmp(&b.tx.id)));
+        let mut txs = Vec::new();
+        for entry in pending {
+            txs.push(pending_tx_json(entry)?);
+        }
+        for (tx, hash, height) in inner.utxo.blockchain.address_history(&pub_key_hash) {
+            txs.push(confirmed_tx_json(&tx, &hash, height, best_height)?);
+        }
+        Ok(serde_json::json!({ "address" : address, "txs" : txs }))
+    }
+
+    fn rest_address_utxos(&self, params: &serde_json::Value) -> RpcResult {
+        let (address, pub_key_hash) = rest_address(params)?;
+        let mut utxos = self.inner.lock().unwrap().utxo.find_UTXO(&pub_key_hash)?;
+        utxos.sort_by(|(a, _), (b, _)| a.txid.cmp(&b.txid).then(a.vout.cmp(&b.vout)));
+        let balance = Amount::checked_sum(utxos.iter().map(|(_,out)| out.value))
+            .ok_or_else(|| RpcError::new(rpc::MISC_ERROR, &format!("balance of {} out of range", address)))?;
+        let utxos : Vec<serde_json::Value> = utxos.iter().map(|(outpoint, out)| serde_json::json!({
+            "txid" : outpoint.txid,
+            "vout" : outpoint.vout,
+            "value" : out.value.to_string(),
+        })).collect();
+        Ok(serde_json::json!({ "address" : address, "balance" : balance.to_string(), "utxos" : utxos }))
+    }
+
+    fn rest_mempool(&self) -> RpcResult {
+        let inner = self.inner.lock().unwrap();
+        let mut entries : Vec<&MempoolEntry> = inner.mempool.entries().collect();
+        entries.sort_by(|a, b| a.time.cmp(&b.time).then_with(|| a.tx.id.cmp(&b.tx.id)));
+        let txs : Vec<serde_json::Value> = entries.iter().map(|entry| serde_json::json!({
+            "txid" : entry.tx.id,
+            "fee" : entry.fee.to_string(),
+            "weight" : entry.weight,
+            "time" : entry.time,
+        })).collect();
+        Ok(serde_json::json!({
+            "size" : inner.mempool.len(),
+            "weight" : inner.mempool.total_weight(),
+            "fees" : inner.mempool.total_fees().to_string(),
+            "txs" : txs,
+        }))
+    }
+    /********************************************************************/
+    /*********************************metrics****************************/
+    // ScrapeMetrics renders the gauges of the node followed by its counters
+    fn scrape_metrics(&self) -> RpcResult {
+        let mut out = String::new();
+        {
+            let inner = self.inner.lock().unwrap();
+            metrics::gauge(&mut out, "node_chain_height", "height of the active chain tip", inner.utxo.blockchain.get_best_height()? as f64);
+            metrics::gauge(&mut out, "node_mempool_transactions", "transactions in the mempool", inner.mempool.len() as f64);
+            metrics::gauge(&mut out, "node_mempool_weight", "weight of the mempool transactions", inner.mempool.total_weight() as f64);
+            let mut bytes = 0;
+            for entry in inner.mempool.entries() {
+                bytes += entry.tx.size()?;
+            }
+            metrics::gauge(&mut out, "node_mempool_bytes", "serialized size of the mempool transactions", bytes as f64);
+            metrics::gauge(&mut out, "node_mempool_fees", "fees paid by the mempool transactions, in coins", inner.mempool.total_fees().as_units() as f64 / COIN as f64);
+            metrics::gauge(&mut out, "node_peers_known", "peers the node knows of", inner.known_peers.len() as f64);
+        }
+        metrics::gauge(&mut out, "node_peers_connected", "peers with an open connection", self.swarm.connected_peers().count() as f64);
+        metrics::gauge(&mut out, "node_peers_outbound", "connected peers the node dialed", self.outbound_peers.len() as f64);
+        metrics::gauge(&mut out, "node_addrbook_peers", "peers remembered in the address book", self.addr_book.len() as f64);
+        metrics::gauge(&mut out, "node_mining_active", "1 while a proof of work job runs", if self.miner.is_mining() { 1.0 } else { 0.0 });
+        metrics::header(&mut out, "node_storage_bytes", "gauge", "size on disk of the sled databases");
+        metrics::sample(&mut out, "node_storage_bytes", &[("db", "blocks")], dir_size(Path::new(&params().data_path("blocks"))) as f64);
+        METRICS.render(&mut out);
+        Ok(serde_json::Value::String(out))
+    }
 }
 /**************************************************************************/
+// ServiceNames spells out the NODE_* bits of a version message
+fn service_names(services : u64) -> Vec<&'static str> {
+    [(NODE_NETWORK, "network"), (NODE_LIGHT_SERVER, "light_server"), (NODE_PRUNED, "pruned")].iter()
+        .filter(|(bit, _)| services & bit != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+// Answers tells whether a response is one a peer may give to our request
+fn answers(request: &Message, response: &Message) -> bool {
+    match (request, response) {
+        (_, Message::Reject(_)) => true,
+        (Message::Version(_), Message::Version(_)) => true,
+        (Message::GetBlock(_), Message::Inv(inv)) => inv.kind == "block",
+        (Message::GetData(get), Message::Block(msg)) => get.kind == "block" && msg.block.get_hash() == get.id,
+        (Message::GetData(get), Message::Tx(msg)) => get.kind == "tx" && msg.transaction.id == get.id,
+        (Message::GetData(get), Message::NotFound(missing)) => get.kind == missing.kind && get.id == missing.id,
+        (Message::Tx(_) | Message::Block(_) | Message::Inv(_), Message::Ack) => true,
+        _ => false,
+    }
+}
+
+// LoadNodeKey reads the libp2p keypair of the node from path, a new one is
+// generated and written there on the first start
+fn load_node_key(path : &str) -> Result<identity::Keypair, Box<dyn std::error::Error>> {
+    match std::fs::read(path) {
+        Ok(data) => return Ok(identity::Keypair::from_protobuf_encoding(&data)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let key = identity::Keypair::generate_ed25519();
+    std::fs::write(path, key.to_protobuf_encoding()?)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    info!("node key written to {}", path);
+    Ok(key)
+}
+
+fn reject_msg(kind: &str, id: &str, reason: &str) -> Message {
+    Message::Reject(Rejectmsg {
+        kind: kind.to_string(),
+        id: id.to_string(),
+        reason: reason.to_string(),
+    })
+}
+
+// TxToJson describes a transaction for the JSON-RPC clients
+fn tx_to_json(tx: &Transaction) -> Result<serde_json::Value, RpcError> {
+    let vin : Vec<serde_json::Value> = tx.vin.iter().map(|vin| {
+        if tx.is_coinbase() {
+            serde_json::json!({ "coinbase" : hex::encode(&vin.pub_key), "sequence" : vin.sequence })
+        } else {
+            serde_json::json!({ "txid" : vin.txid, "vout" : vin.vout, "sequence" : vin.sequence })
+        }
+    }).collect();
+    let vout : Vec<serde_json::Value> = tx.vout.iter().enumerate().map(|(n, out)| serde_json::json!({
+        "value" : out.value.to_string(),
+        "n" : n,
+        "pubkeyhash" : hex::encode(&out.pub_key_hash),
+    })).collect();
+    Ok(serde_json::json!({
+        "txid" : tx.id,
+        "hex" : hex::encode(serialize(tx)?),
+        "size" : tx.size()?,
+        "weight" : tx.weight()?,
+        "locktime" : tx.lock_time,
+        "vin" : vin,
+        "vout" : vout,
+    }))
+}
+
+// PendingTxJson describes a mempool transaction for the REST explorer
+fn pending_tx_json(entry: &MempoolEntry) -> RpcResult {
+    let mut value = tx_to_json(&entry.tx)?;
+    value["fee"] = serde_json::json!(entry.fee.to_string());
+    value["blockhash"] = serde_json::Value::Null;
+    value["height"] = serde_json::Value::Null;
+    value["confirmations"] = serde_json::json!(0);
+    Ok(value)
+}
+
+// ConfirmedTxJson describes an active chain transaction for the REST explorer
+fn confirmed_tx_json(tx: &Transaction, block_hash: &str, height: i32, best_height: i32) -> RpcResult {
+    let mut value = tx_to_json(tx)?;
+    value["blockhash"] = serde_json::json!(block_hash);
+    value["height"] = serde_json::json!(height);
+    value["confirmations"] = serde_json::json!(best_height - height + 1);
+    Ok(value)
+}
+
+// the address a REST call is about, with its public key hash
+fn rest_address(params: &serde_json::Value) -> Result<(String, Vec<u8>), RpcError> {
+    let address = rpc::param_str(params, 0, "address")?;
+    let pub_key_hash = crate::chainparams::params().decode_address(&address)
+        .map_err(|e| RpcError::new(rpc::INVALID_PARAMS, &e.to_string()))?;
+    Ok((address, pub_key_hash))
+}
+
+// DirSize adds up the size of the files below `path`
+fn dir_size(path: &Path) -> u64 {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries.flatten().map(|entry| match entry.metadata() {
+        Ok(meta) if meta.is_dir() => dir_size(&entry.path()),
+        Ok(meta) => meta.len(),
+        Err(_) => 0,
+    }).sum()
+}
+
+// seconds since UNIX epoch
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
 /*fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
     let mut data = [0; CMD_LEN];
     for (i, d) in cmd.as_bytes().iter().enumerate() {
@@ -4,7 +4,13 @@ use crypto::{digest::Digest, ed25519, sha2::Sha256};
 use failure::format_err;
 use sled::transaction;
 use crate::{blockchain::Blockchain, tx::{self, TXInput, TXOutput}, utxoset::UTXOSet, wallet::{self, hash_pub_key, Wallets}};
+use crate::constants::{BLOCK_SUBSIDY, DEFAULT_BUMP_FEE, LOCKTIME_THRESHOLD, MAX_REPLACEABLE_SEQUENCE, SEQUENCE_FINAL, WITNESS_SCALE_FACTOR};
+use crate::amount::Amount;
+use crate::mempool::Mempool;
+use crate::metrics::METRICS;
+use crate::validation::{reject, RejectKind};
 use log::{error, info};
+use std::time::Instant;
 
 #[derive(serde::Serialize, serde::Deserialize,Debug, Clone)]
 pub struct Transaction {
@@ -15,12 +21,18 @@ pub struct Transaction {
     pub vin : Vec<TXInput>,
     // A vector of TXOutput, representing outputs of 
     // the transaction (destinations for funds)
-    pub vout : Vec<TXOutput>
+    pub vout : Vec<TXOutput>,
+    // the transaction can not be mined before this block height, or this
+    // median-time-past in seconds from LOCKTIME_THRESHOLD up, unless all
+    // of its inputs are final
+    pub lock_time : u32,
 }
 impl Transaction {
 
-    // NEWTXOTransaction creates a new transaction
-    pub fn new_UTXO(from : &str, to : &str, amount : i32 , bc: &UTXOSet) -> Result<Transaction,Box<dyn std::error::Error>>{
+    // NEWTXOTransaction creates a new transaction paying `fee` to the miner,
+    // a replaceable transaction can later be fee bumped and a non zero
+    // `lock_time` keeps it out of the blocks until then
+    pub fn new_UTXO(from : &str, to : &str, amount : Amount , fee : Amount, replaceable : bool, lock_time : u32, bc: &UTXOSet) -> Result<Transaction,Box<dyn std::error::Error>>{
         let mut vin = Vec::new();
         
         let wallets = Wallets::new()?;
@@ -35,11 +47,21 @@ impl Transaction {
         let mut pub_key_hash = wallet.public_key.clone();
         hash_pub_key(&mut pub_key_hash);
 
-        let acc_v = bc.find_spendable_outputs(&pub_key_hash, amount);
-        if acc_v.0 <amount{
+        let total = amount.checked_add(fee)
+            .ok_or_else(|| format_err!("amount plus fee out of range"))?;
+        let acc_v = bc.find_spendable_outputs(&pub_key_hash, total)?;
+        if acc_v.0 <total{
             error!("Not enough balance");
             return Err(format_err!("Not enough balance: current balance {}",acc_v.0).into());
         }
+        // a lock time is only enforced when some input is not final
+        let sequence = if replaceable {
+            MAX_REPLACEABLE_SEQUENCE
+        } else if lock_time > 0 {
+            SEQUENCE_FINAL - 1
+        } else {
+            SEQUENCE_FINAL
+        };
         for tx in acc_v.1 {
             for out in tx.1{
                 let input = TXInput{
@@ -47,6 +69,7 @@ impl Transaction {
                     vout:out,
                     signature : Vec::new(),
                     pub_key : wallet.public_key.clone(),
+                    sequence,
                 };
                 vin.push(input);
             }
@@ -56,9 +79,11 @@ impl Transaction {
         
         //println!("the send amount : {}, is smaller than avaikable one : {}",amount,acc_v.0);
         
-        if acc_v.0 > amount{
+        if acc_v.0 > total{
+            let change = acc_v.0.checked_sub(total)
+                .ok_or_else(|| format_err!("Invalid change amount"))?;
             vout.push(TXOutput::new(
-                acc_v.0 - amount,
+                change,
                 from.to_string()
             )?);
         }
@@ -66,16 +91,26 @@ impl Transaction {
         let mut tx = Transaction {
             id : String::new(),
             vin ,
-            vout
+            vout,
+            lock_time,
         };
         tx.id=tx.hash()?;
         bc.blockchain.sign_transaction(&mut tx,&wallet.secret_key)?;
         Ok(tx)
     }
 
-    pub fn new_coinbase(to : String, mut data : String) -> Result<Transaction ,Box<dyn std::error::Error>> {
+    // NewCoinbase pays the block subsidy plus the fees of the block transactions to `to`
+    pub fn new_coinbase(to : String, height : i32, mut data : String, fees : Amount) -> Result<Transaction ,Box<dyn std::error::Error>> {
         info!("new coinbase Transaction to : {}",to);
         data+= &format!("Reward to : {}",to);
+        // the data starts with the block height, which keeps the coinbases of
+        // the blocks paying one address apart, and ends with the extra-nonce
+        // rolled by the miner
+        let mut coinbase_data = height.to_le_bytes().to_vec();
+        coinbase_data.extend_from_slice(data.as_bytes());
+        coinbase_data.extend_from_slice(&0u64.to_le_bytes());
+        let reward = Amount::from_units(BLOCK_SUBSIDY).checked_add(fees)
+            .ok_or_else(|| format_err!("coinbase reward out of range"))?;
         let mut tx = Transaction{
             id : String::new(),
             vin : vec![
@@ -83,18 +118,70 @@ impl Transaction {
                     txid: String::new(),
                     vout : -1,
                     signature : Vec::new(),
-                    pub_key : Vec::from(data.as_bytes())
+                    pub_key : coinbase_data,
+                    sequence : SEQUENCE_FINAL
                 }
             ],
             vout : vec![
-                TXOutput::new(100,to)?
-                ]
+                TXOutput::new(reward,to)?
+                ],
+            lock_time : 0,
         };
         tx.id = tx.hash()?;
         //tx.set_id()?;
         Ok(tx)
     }
     
+    // NewGenesisCoinbase is the coinbase of the genesis block : its output is
+    // locked to no public key hash, so nobody can spend it
+    pub fn new_genesis_coinbase(data : &str) -> Result<Transaction ,Box<dyn std::error::Error>> {
+        let mut coinbase_data = Vec::from(data.as_bytes());
+        coinbase_data.extend_from_slice(&0u64.to_le_bytes());
+        let mut tx = Transaction{
+            id : String::new(),
+            vin : vec![
+                TXInput{
+                    txid: String::new(),
+                    vout : -1,
+                    signature : Vec::new(),
+                    pub_key : coinbase_data,
+                    sequence : SEQUENCE_FINAL
+                }
+            ],
+            vout : vec![
+                TXOutput{
+                    value : Amount::from_units(BLOCK_SUBSIDY),
+                    pub_key_hash : Vec::new(),
+                }
+            ],
+            lock_time : 0,
+        };
+        tx.id = tx.hash()?;
+        Ok(tx)
+    }
+
+    // SetExtraNonce replaces the extra-nonce kept in the last bytes of the
+    // coinbase input data and recomputes the id
+    pub fn set_extra_nonce(&mut self, extra_nonce : u64) -> Result<(),Box<dyn std::error::Error>> {
+        if !self.is_coinbase() || self.vin[0].pub_key.len() < 8 {
+            return Err(format_err!("transaction {} has no extra-nonce", self.id).into());
+        }
+        let data = &mut self.vin[0].pub_key;
+        let len = data.len() - 8;
+        data[len..].copy_from_slice(&extra_nonce.to_le_bytes());
+        self.id = self.hash()?;
+        Ok(())
+    }
+
+    // CoinbaseHeight reads the block height a coinbase starts its data with
+    pub fn coinbase_height(&self) -> Option<i32> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        let bytes = self.vin[0].pub_key.get(..4)?;
+        Some(i32::from_le_bytes(bytes.try_into().ok()?))
+    }
+
     pub fn hash(& self)-> Result<String,Box<dyn std::error::Error>>{
         let mut copy = self.clone();
         copy.id = String::new();
@@ -104,6 +191,80 @@ impl Transaction {
         Ok(hasher.result_str())
     }
 
+    // ComputeId is the hash the id was set to before signing, that is
+    // the hash of the transaction with its signatures left empty
+    pub fn compute_id(&self) -> Result<String,Box<dyn std::error::Error>>{
+        let mut copy = self.clone();
+        for vin in &mut copy.vin{
+            vin.signature.clear();
+        }
+        copy.hash()
+    }
+
+    // Size returns the canonical serialized size of the transaction
+    pub fn size(&self) -> Result<usize,Box<dyn std::error::Error>>{
+        Ok(bincode::serialized_size(self)? as usize)
+    }
+
+    // WitnessSize returns the bytes taken by input signatures and public keys,
+    // the coinbase input only carries arbitrary data so it has no witness
+    pub fn witness_size(&self) -> usize {
+        if self.is_coinbase(){
+            return 0;
+        }
+        self.vin.iter().map(|vin| vin.signature.len() + vin.pub_key.len()).sum()
+    }
+
+    // Weight counts non witness bytes WITNESS_SCALE_FACTOR times and witness bytes once
+    pub fn weight(&self) -> Result<usize,Box<dyn std::error::Error>>{
+        let size = self.size()?;
+        let base_size = size - self.witness_size();
+        Ok(base_size * (WITNESS_SCALE_FACTOR - 1) + size)
+    }
+
+    // ValueOut sums the outputs, failing when the total overflows or leaves the money range
+    pub fn value_out(&self) -> Result<Amount,Box<dyn std::error::Error>>{
+        Amount::checked_sum(self.vout.iter().map(|out| out.value))
+            .ok_or_else(|| reject(RejectKind::Invalid, format!("transaction {}: outputs total out of range",self.id)))
+    }
+
+    // CheckAmounts makes sure the spent outputs cover the created ones and returns the fee
+    pub fn check_amounts(&self, prev_txs : &HashMap<String,Transaction>) -> Result<Amount,Box<dyn std::error::Error>>{
+        if self.is_coinbase(){
+            return self.check_spent_amounts(&[]);
+        }
+        self.check_spent_amounts(&self.spent_outputs(prev_txs)?)
+    }
+
+    // CheckSpentAmounts is CheckAmounts given the outputs spent by the
+    // inputs, in the order of the inputs
+    pub fn check_spent_amounts(&self, spent : &[TXOutput]) -> Result<Amount,Box<dyn std::error::Error>>{
+        let value_out = self.value_out()?;
+        if self.is_coinbase(){
+            return Ok(Amount::ZERO);
+        }
+        if spent.len() != self.vin.len(){
+            return Err(format_err!("transaction {}: {} spent outputs for {} inputs",self.id,spent.len(),self.vin.len()).into());
+        }
+        let value_in = Amount::checked_sum(spent.iter().map(|out| out.value))
+            .ok_or_else(|| reject(RejectKind::Invalid, format!("transaction {}: inputs total out of range",self.id)))?;
+        value_in.checked_sub(value_out)
+            .ok_or_else(|| reject(RejectKind::Invalid, format!("transaction {}: spends {} but only has {}",self.id,value_out,value_in)))
+    }
+
+    // SpentOutputs looks up in prev_txs the output spent by each input
+    fn spent_outputs(&self, prev_txs : &HashMap<String,Transaction>) -> Result<Vec<TXOutput>,Box<dyn std::error::Error>>{
+        let mut spent = Vec::new();
+        for vin in &self.vin{
+            let prev_tx = prev_txs.get(&vin.txid)
+                .ok_or_else(|| reject(RejectKind::MissingInputs, format!("transaction {}: unknown input {}",self.id,vin.txid)))?;
+            let out = usize::try_from(vin.vout).ok().and_then(|i| prev_tx.vout.get(i))
+                .ok_or_else(|| reject(RejectKind::MissingInputs, format!("transaction {}: input {}:{} does not exist",self.id,vin.txid,vin.vout)))?;
+            spent.push(out.clone());
+        }
+        Ok(spent)
+    }
+
     fn set_id(&mut self)-> Result<(),Box<dyn std::error::Error>>{
         let mut hasher = Sha256::new();
         let data = bincode::serialize(self)?;
@@ -111,6 +272,90 @@ impl Transaction {
         self.id = hasher.result_str();
         Ok(())
     }
+    // IsFinal tells whether the transaction may go in a block at `height`
+    // whose previous blocks have a median time past of `time` seconds
+    pub fn is_final(&self, height : i32, time : u64) -> bool {
+        if self.lock_time == 0 {
+            return true;
+        }
+        let locked_until = if self.lock_time < LOCKTIME_THRESHOLD { height as i64 } else { time as i64 };
+        if (self.lock_time as i64) < locked_until {
+            return true;
+        }
+        self.vin.iter().all(|vin| vin.sequence == SEQUENCE_FINAL)
+    }
+
+    // SignalsReplaceable tells if the sender accepts this transaction being
+    // replaced in the mempool by a conflicting one paying a higher fee
+    pub fn signals_replaceable(&self) -> bool {
+        self.vin.iter().any(|vin| vin.sequence <= MAX_REPLACEABLE_SEQUENCE)
+    }
+
+    /// BumpFee rebuilds the transaction paying `new_fee` instead of the current fee :
+    /// the difference is taken from the change output, the inputs stay the same so
+    /// the result conflicts with (and replaces) the original, then it is signed again.
+    /// The inputs may spend pending transactions, they are looked up in the mempool first
+    pub fn bump_fee(&self, new_fee : Option<Amount>, wallets : &Wallets, mempool : &Mempool, bc : &UTXOSet) -> Result<Transaction,Box<dyn std::error::Error>>{
+        let mut prev_txs = HashMap::new();
+        for vin in &self.vin{
+            if prev_txs.contains_key(&vin.txid){
+                continue;
+            }
+            let prev_tx = match mempool.get(&vin.txid){
+                Some(tx) => tx.clone(),
+                None => bc.blockchain.find_transaction(&vin.txid)?,
+            };
+            prev_txs.insert(vin.txid.clone(),prev_tx);
+        }
+        let wallet = wallets.get_wallet_by_pub_key(&self.vin[0].pub_key)
+            .ok_or_else(|| format_err!("transaction {} was not signed by a wallet of this node",self.id))?;
+        let mut pub_key_hash = wallet.public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+
+        let mut tx = self.with_fee(new_fee, &prev_txs, &pub_key_hash)?;
+        tx.sign(&wallet.secret_key, prev_txs)?;
+        Ok(tx)
+    }
+
+    // WithFee returns the unsigned copy of the transaction paying `new_fee`, or
+    // DEFAULT_BUMP_FEE more than now, out of the change paid back to pub_key_hash
+    fn with_fee(&self, new_fee : Option<Amount>, prev_txs : &HashMap<String,Transaction>, pub_key_hash : &[u8]) -> Result<Transaction,Box<dyn std::error::Error>>{
+        if !self.signals_replaceable(){
+            return Err(format_err!("transaction {} does not signal replaceability",self.id).into());
+        }
+        let old_fee = self.check_amounts(prev_txs)?;
+        let new_fee = match new_fee{
+            Some(fee) => fee,
+            None => old_fee.checked_add(Amount::from_units(DEFAULT_BUMP_FEE))
+                .ok_or_else(|| format_err!("fee out of range"))?
+        };
+        let delta = new_fee.checked_sub(old_fee)
+            .filter(|d| *d > Amount::ZERO)
+            .ok_or_else(|| format_err!("new fee {} must be higher than the current fee {}",new_fee,old_fee))?;
+
+        let mut tx = self.clone();
+        let index = self.change_output(pub_key_hash)
+            .ok_or_else(|| format_err!("transaction {} has no change output to take the fee from",self.id))?;
+        let change = &mut tx.vout[index];
+        change.value = change.value.checked_sub(delta)
+            .ok_or_else(|| format_err!("change {} can not cover a fee increase of {}",change.value,delta))?;
+        if change.value == Amount::ZERO {
+            tx.vout.remove(index);
+        }
+        for vin in &mut tx.vin{
+            vin.signature.clear();
+        }
+        tx.id = tx.hash()?;
+        Ok(tx)
+    }
+
+    // ChangeOutput is the index of the output paying back pub_key_hash : new_UTXO
+    // puts the payment first and the change after it, so the payment is never
+    // taken for the change, even when the wallet pays itself
+    fn change_output(&self, pub_key_hash : &[u8]) -> Option<usize> {
+        self.vout.iter().skip(1).position(|out| out.can_be_unlock_with(pub_key_hash)).map(|i| i + 1)
+    }
+
     // IsCoiBase check whether the transaction is coinbase
     pub fn is_coinbase(&self) -> bool {
         self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
@@ -174,31 +419,36 @@ impl Transaction {
         Ok(true)
     }
     */
-    pub fn verify(&self, prev_TXs : HashMap<String,Transaction>)-> Result<bool,Box<dyn std::error::Error>>{
+    // VerifySpent checks the input signatures against the outputs they spend,
+    // given in the order of the inputs
+    pub fn verify_spent(&self, spent : &[TXOutput])-> Result<bool,Box<dyn std::error::Error>>{
         if self.is_coinbase(){
             return Ok(true);
         }
-        for vin in &self.vin{
-            if prev_TXs.get(&vin.txid).unwrap().id.is_empty(){
-                return Err(format_err!("").into());
-            }
+        if spent.len() != self.vin.len(){
+            return Err(format_err!("transaction {}: {} spent outputs for {} inputs",self.id,spent.len(),self.vin.len()).into());
         }
         let mut tx_copy = self.trim_copy();
 
-        for in_id in 0..self.vin.len() {
-            let prev_Tx = prev_TXs.get(&self.vin[in_id].txid).unwrap();
+        for (in_id, out) in spent.iter().enumerate() {
+            let pub_key_hash = &out.pub_key_hash;
+            // the key signing the input must be the one the output is locked to
+            if !self.vin[in_id].can_unlock_output_with(pub_key_hash) {
+                return Ok(false);
+            }
             tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_Tx.vout[self.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
+            tx_copy.vin[in_id].pub_key = pub_key_hash.clone();
             tx_copy.id = tx_copy.hash()?;
             tx_copy.vin[in_id].pub_key = Vec::new();
 
-            if !ed25519::verify(
+            let started = Instant::now();
+            let valid = ed25519::verify(
                 &tx_copy.id.as_bytes(),
                 &self.vin[in_id].pub_key,
                 &self.vin[in_id].signature,
-            ) {
+            );
+            METRICS.signature_verified("ed25519", started.elapsed());
+            if !valid {
                 return Ok(false);
             }
         }
@@ -215,7 +465,8 @@ impl Transaction {
                     txid : v.txid.clone(),
                     vout : v.vout.clone(),
                     signature : Vec::new(),
-                    pub_key : Vec::new()
+                    pub_key : Vec::new(),
+                    sequence : v.sequence
                 }
             )
         }
@@ -230,9 +481,69 @@ impl Transaction {
             id:self.id.clone(),
             vin,
             vout,
+            lock_time : self.lock_time,
         }
     }
         
 }
 
 // represent transaction input
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(units : u64, pub_key_hash : &[u8]) -> TXOutput {
+        TXOutput { value : Amount::from_units(units), pub_key_hash : pub_key_hash.to_vec() }
+    }
+
+    // payment spends a 100_000 output of prev, paying `paid` back to the sender
+    // and 10_000 in fees, the rest is change
+    fn payment(prev : &Transaction, paid : u64, pub_key : &[u8], pub_key_hash : &[u8]) -> Transaction {
+        let mut tx = Transaction {
+            id : String::new(),
+            vin : vec![TXInput { txid : prev.id.clone(), vout : 0, signature : Vec::new(), pub_key : pub_key.to_vec(), sequence : MAX_REPLACEABLE_SEQUENCE }],
+            vout : vec![output(paid, pub_key_hash), output(90_000 - paid, pub_key_hash)],
+            lock_time : 0,
+        };
+        tx.id = tx.hash().unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_bump_fee_takes_the_change(){
+        let (secret_key, public_key) = ed25519::keypair(&[1; 32]);
+        let mut pub_key_hash = public_key.to_vec();
+        hash_pub_key(&mut pub_key_hash);
+        let mut prev = Transaction::new_genesis_coinbase("prev").unwrap();
+        prev.vout = vec![output(100_000, &pub_key_hash)];
+        prev.id = prev.hash().unwrap();
+        let prev_txs = HashMap::from([(prev.id.clone(), prev.clone())]);
+
+        // the wallet pays itself, the payment comes first and keeps its value
+        let tx = payment(&prev, 60_000, &public_key, &pub_key_hash);
+        let mut bumped = tx.with_fee(Some(Amount::from_units(15_000)), &prev_txs, &pub_key_hash).unwrap();
+        assert_eq!(bumped.vout[0].value, Amount::from_units(60_000));
+        assert_eq!(bumped.vout[1].value, Amount::from_units(25_000));
+        assert_eq!(bumped.vin[0].outpoint(), tx.vin[0].outpoint());
+        bumped.sign(&secret_key, prev_txs.clone()).unwrap();
+        assert!(bumped.verify_spent(&prev.vout[..1]).unwrap());
+        assert_eq!(bumped.id, bumped.compute_id().unwrap());
+
+        // the default bump, and a change spent entirely on the fee
+        let bumped = tx.with_fee(None, &prev_txs, &pub_key_hash).unwrap();
+        assert_eq!(bumped.check_amounts(&prev_txs).unwrap(), Amount::from_units(10_000 + DEFAULT_BUMP_FEE));
+        let bumped = tx.with_fee(Some(Amount::from_units(40_000)), &prev_txs, &pub_key_hash).unwrap();
+        assert_eq!(bumped.vout.len(), 1);
+        assert!(tx.with_fee(Some(Amount::from_units(40_001)), &prev_txs, &pub_key_hash).is_err());
+        assert!(tx.with_fee(Some(Amount::from_units(10_000)), &prev_txs, &pub_key_hash).is_err());
+
+        // without change, or without opting in, there is nothing to bump
+        let mut single = tx.clone();
+        single.vout.truncate(1);
+        assert!(single.with_fee(None, &prev_txs, &pub_key_hash).is_err());
+        let mut final_tx = tx.clone();
+        final_tx.vin[0].sequence = SEQUENCE_FINAL;
+        assert!(final_tx.with_fee(None, &prev_txs, &pub_key_hash).is_err());
+    }
+}
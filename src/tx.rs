@@ -1,6 +1,9 @@
-use bitcoincash_addr::Address;
+use std::collections::BTreeMap;
+use std::fmt;
+use crate::chainparams::params;
 use log::debug;
 
+use crate::amount::Amount;
 use crate::wallet::hash_pub_key;
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
@@ -13,20 +16,38 @@ pub struct TXInput {
     // signature
     //pub script_sig : String
     pub signature : Vec<u8>,
-    pub pub_key : Vec<u8>
+    pub pub_key : Vec<u8>,
+    // an input with a sequence up to MAX_REPLACEABLE_SEQUENCE lets
+    // the transaction be replaced in the mempool by a higher fee one
+    pub sequence : u32
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct TXOutput {
     // amount of funds this output represents
-    pub value : i32,
+    pub value : Amount,
     // an address that specifies who will recieve this money
     pub pub_key_hash : Vec<u8>
 }
 
+// OutPoint references one output of a previous transaction,
+// two inputs with the same outpoint spend the same coins
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    pub txid : String,
+    pub vout : i32
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.txid, self.vout)
+    }
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct TXOutputs {
-    pub outputs : Vec<TXOutput>
+    // unspent outputs keyed by their index (vout) in the transaction
+    pub outputs : BTreeMap<i32,TXOutput>
 }
 
 
@@ -38,6 +59,13 @@ impl TXInput {
         pubkeyhash == unlocking_data
     }
 
+    pub fn outpoint(&self) -> OutPoint {
+        OutPoint {
+            txid : self.txid.clone(),
+            vout : self.vout
+        }
+    }
+
 }
 
 impl TXOutput {
@@ -47,13 +75,13 @@ impl TXOutput {
     }
 
     fn lock(&mut self, address : &str) -> Result<(),Box<dyn std::error::Error>>{
-        let pub_key_hash = Address::decode(address).unwrap().body;
+        let pub_key_hash = params().decode_address(address)?;
         debug!("lock: {}",address);
         self.pub_key_hash = pub_key_hash ;
         Ok(())
     }
 
-    pub fn new(value : i32, address : String) -> Result<Self,Box<dyn std::error::Error>>{
+    pub fn new(value : Amount, address : String) -> Result<Self,Box<dyn std::error::Error>>{
         let mut txo = TXOutput{
             value,
             pub_key_hash : Vec::new(),
@@ -1,9 +1,11 @@
 use std::collections::HashMap;
 
-use log::info;
 use serde_json::error;
 
-use crate::{block::Block, blockchain::Blockchain, tx::TXOutputs};
+use failure::format_err;
+use crate::{amount::Amount, blockchain::Blockchain, tx::{OutPoint, TXOutput, TXOutputs}};
+// total found and, per transaction id, the output indexes to spend
+pub type SpendableOutputs = (Amount,HashMap<String, Vec<i32>>);
 // it will works on unspent transactions outputs 
 // to speed transactions when the blockchain becomes 
 // bigger
@@ -22,58 +24,13 @@ impl UTXOSet {
     */
     /// Reindex rebuilds the UTXO set
     pub fn reindex(&self) -> Result<(),Box<dyn std::error::Error>>{
-        if let Err(e) = std::fs::remove_dir_all("data/utxos"){
-            info!("no utxos index db exist to be deleted !!")
-        };
-        let db = sled::open("data/utxos")?;
-        let utxos = self.blockchain.find_UTXO();
-        for (txid,outs) in utxos {
-            db.insert(txid.as_bytes(),bincode::serialize(&outs)?)?;
-        }
-        Ok(())
-    }
-    
-    // Update the UTXO with transactions from the block 
-    pub fn update(&self, block : &Block) -> Result<(),Box<dyn std::error::Error>>{
-        let db = sled::open("data/utxos")?;
-        for tx in block.get_transaction(){
-            if !tx.is_coinbase(){
-                for vin in &tx.vin{
-                    let mut update_outputs = TXOutputs{
-                        outputs : Vec::new(),
-                    };
-                    let outs : TXOutputs = bincode::deserialize(&db.get(&vin.txid)?.unwrap())?;
-                    for out_idx in 0..outs.outputs.len(){
-                        if out_idx != vin.vout as usize{
-                            update_outputs.outputs.push(outs.outputs[out_idx].clone());
-                        }
-                    }
-                    // all outputs of transaction txid have been consumed
-                    if update_outputs.outputs.is_empty(){
-                        db.remove(&vin.txid)?;
-                    }else{
-                        db.insert(vin.txid.as_bytes(), bincode::serialize(&update_outputs)?)? ;
-                    }
-                }
-            }
-            let mut new_outputs = TXOutputs{
-                outputs : Vec::new(),
-            };
-
-            for out in &tx.vout{
-                new_outputs.outputs.push(out.clone());
-            }
-            
-            db.insert(tx.id.as_bytes(), bincode::serialize(&new_outputs)?)?;
-        }
-        Ok(())
+        self.blockchain.rebuild_utxos()
     }
     
     // return the number of transactions in the UTXO set
     pub fn count_transactions(&self) -> Result<i32,Box<dyn std::error::Error>> {
         let mut counter : i32 = 0 ;
-        let db = sled::open("data/utxos")?;
-        for kv in db.iter(){
+        for kv in self.blockchain.utxo_tree()?.iter(){
             kv?;
             counter +=1;
         }
@@ -82,49 +39,56 @@ impl UTXOSet {
     }
     
     /// FindUnspentTransactions returns a list of transactions containing unspent outputs
-    pub fn find_spendable_outputs(&self, address:&[u8],amount: i32)->(i32,HashMap<String, Vec<i32>>){
+    pub fn find_spendable_outputs(&self, address:&[u8],amount: Amount)->Result<SpendableOutputs,Box<dyn std::error::Error>>{
         let mut unspent_outputs : HashMap<String, Vec<i32>> = HashMap::new();
-        let mut accumulated : i32 = 0 ;
-        let db = sled::open("data/utxos").unwrap();
-        for kv in db.iter(){
+        let mut accumulated = Amount::ZERO ;
+        for kv in self.blockchain.utxo_tree()?.iter(){
             let (k,v) = kv.unwrap();
             let txid = String::from_utf8(k.to_vec()).unwrap();
             let outs : TXOutputs = bincode::deserialize(&v.to_vec()).unwrap();
             
-            for out_idx in 0..outs.outputs.len(){
-                if outs.outputs[out_idx].can_be_unlock_with(address) && accumulated < amount {
-                    accumulated+=outs.outputs[out_idx].value;
+            for (out_idx, out) in &outs.outputs{
+                if out.can_be_unlock_with(address) && accumulated < amount {
+                    accumulated = accumulated.checked_add(out.value)
+                        .ok_or_else(|| format_err!("spendable outputs total out of range"))?;
                     match unspent_outputs.get_mut(&txid){
-                        Some(v) => v.push(out_idx as i32),
+                        Some(v) => v.push(*out_idx),
                         None=> {
-                            unspent_outputs.insert(txid.clone(), vec![out_idx as i32]);
+                            unspent_outputs.insert(txid.clone(), vec![*out_idx]);
                         }
                     }
                 }
             } 
         }
-        (accumulated,unspent_outputs)
+        Ok((accumulated,unspent_outputs))
     }
     /// Update updates the UTXO set with transactions from the Block
     ///
     /// The Block is considered to be the tip of a blockchain
     // it will be used to find the balance of a specific user 
-    pub fn find_UTXO(&self,pub_key_hash : &[u8]) -> Result<TXOutputs,Box<dyn std::error::Error>>{
-        let mut utxos = TXOutputs{
-            outputs : Vec::new()
-        };
-        let db = sled::open("data/utxos")?;
-        for kv in db.iter(){
-            let (_,v) = kv?;
+    pub fn find_UTXO(&self,pub_key_hash : &[u8]) -> Result<Vec<(OutPoint,TXOutput)>,Box<dyn std::error::Error>>{
+        let mut utxos = Vec::new();
+        for kv in self.blockchain.utxo_tree()?.iter(){
+            let (k,v) = kv?;
+            let txid = String::from_utf8(k.to_vec())?;
             let outs : TXOutputs = bincode::deserialize(&v.to_vec())?;
             
-            for out in outs.outputs{
+            for (vout,out) in outs.outputs{
                 if out.can_be_unlock_with(pub_key_hash){
-                    utxos.outputs.push(out.clone());
+                    utxos.push((OutPoint{ txid : txid.clone(), vout }, out));
                 }
             }
         }
         Ok(utxos)
     }
 
+    /// GetOutput returns the output referenced by outpoint if it is still unspent
+    pub fn get_output(&self, outpoint : &OutPoint) -> Result<Option<TXOutput>,Box<dyn std::error::Error>>{
+        let outs : TXOutputs = match self.blockchain.utxo_tree()?.get(&outpoint.txid)? {
+            Some(v) => bincode::deserialize(&v)?,
+            None => return Ok(None)
+        };
+        Ok(outs.outputs.get(&outpoint.vout).cloned())
+    }
+
 }
\ No newline at end of file
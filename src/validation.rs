@@ -0,0 +1,121 @@
+use std::fmt;
+
+use crate::banlist::Misbehavior;
+
+// RejectKind is the rule a refused block or transaction broke
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RejectKind {
+    // the transaction is in the mempool already
+    Duplicate,
+    InvalidSignature,
+    // an input spends an unknown or spent output
+    MissingInputs,
+    // two spends of the same output, or a conflict that can not be replaced
+    DoubleSpend,
+    // a lock time not reached yet
+    NotFinal,
+    InvalidPow,
+    // a block timestamp not after the median time past
+    Time,
+    // a block timestamp too far ahead of the network adjusted time
+    TimeTooNew,
+    // a fee too low for the replace-by-fee rules or for a full mempool
+    Fee,
+    Weight,
+    // any other broken rule : amounts, coinbase, height, ids
+    Invalid,
+}
+
+impl RejectKind {
+    // Label is the reason of the node_rejected_total metric, a few labels
+    // keep the number of series small
+    pub fn label(&self) -> &'static str {
+        match self {
+            RejectKind::Duplicate => "duplicate",
+            RejectKind::InvalidSignature => "invalid_signature",
+            RejectKind::MissingInputs => "missing_inputs",
+            RejectKind::DoubleSpend => "double_spend",
+            RejectKind::NotFinal => "not_final",
+            RejectKind::InvalidPow => "invalid_pow",
+            RejectKind::Time | RejectKind::TimeTooNew => "time",
+            RejectKind::Fee => "fee",
+            RejectKind::Weight => "weight",
+            RejectKind::Invalid => "other",
+        }
+    }
+
+    // BlockMisbehavior is what relaying a block refused for this costs the
+    // peer : a block from the future may only be early, the other rules are
+    // consensus rules
+    pub fn block_misbehavior(&self) -> Option<Misbehavior> {
+        match self {
+            RejectKind::TimeTooNew | RejectKind::Duplicate => None,
+            _ => Some(Misbehavior::InvalidBlock),
+        }
+    }
+
+    // TxMisbehavior is what relaying a transaction refused for this costs the
+    // peer : a missing input or a low fee may be a race or our policy, the
+    // other rules are broken by the sender
+    pub fn tx_misbehavior(&self) -> Option<Misbehavior> {
+        match self {
+            RejectKind::InvalidSignature | RejectKind::Weight | RejectKind::Invalid => Some(Misbehavior::InvalidTx),
+            _ => None,
+        }
+    }
+}
+
+// Rejection is the error of a block or transaction refused by a validation
+// rule, the callers find its kind back by downcasting the boxed error
+#[derive(Debug)]
+pub struct Rejection {
+    pub kind : RejectKind,
+    pub message : String,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+// Reject builds the error of a refused block or transaction
+pub fn reject(kind : RejectKind, message : String) -> Box<dyn std::error::Error> {
+    Box::new(Rejection { kind, message })
+}
+
+// RejectKindOf returns the kind of a rejection, None for the other errors
+pub fn reject_kind_of(error : &(dyn std::error::Error + 'static)) -> Option<RejectKind> {
+    error.downcast_ref::<Rejection>().map(|r| r.kind)
+}
+
+// Within prefixes the message of a rejection with what it was found in,
+// the other errors pass unchanged
+pub fn within(what : &str, error : Box<dyn std::error::Error>) -> Box<dyn std::error::Error> {
+    match error.downcast::<Rejection>() {
+        Ok(r) => reject(r.kind, format!("{}: {}", what, r.message)),
+        Err(error) => error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejection() {
+        let error = within("block ab", reject(RejectKind::TimeTooNew, "timestamp 5 is too far in the future".to_string()));
+        assert_eq!(error.to_string(), "block ab: timestamp 5 is too far in the future");
+        assert_eq!(reject_kind_of(error.as_ref()), Some(RejectKind::TimeTooNew));
+        assert_eq!(RejectKind::TimeTooNew.label(), "time");
+        assert_eq!(RejectKind::TimeTooNew.block_misbehavior(), None);
+        assert_eq!(RejectKind::MissingInputs.block_misbehavior(), Some(Misbehavior::InvalidBlock));
+        assert_eq!(RejectKind::MissingInputs.tx_misbehavior(), None);
+        assert_eq!(RejectKind::InvalidSignature.tx_misbehavior(), Some(Misbehavior::InvalidTx));
+
+        let other : Box<dyn std::error::Error> = "disk full".into();
+        assert_eq!(reject_kind_of(within("block ab", other).as_ref()), None);
+    }
+}
@@ -11,6 +11,8 @@ use serde::{Serialize, Deserialize};
 use crypto::ed25519 ;
 use rand::RngCore ;
 use rand::rngs::OsRng ;
+use crate::transaction::Transaction;
+use crate::chainparams::params;
 
 
 #[derive(Serialize,Deserialize, Debug,Clone,PartialEq)]
@@ -41,6 +43,7 @@ impl Wallet {
             //  0 o 1 i to avoid confusion
             scheme : Scheme::Base58,
             hash_type : HashType::Script,
+            network : params().address_network.clone(),
             ..Default::default()
         };
         address.encode().unwrap()
@@ -66,7 +69,7 @@ impl Wallets {
         let mut wlt = Wallets {
             wallets : HashMap::<String,Wallet>::new(),
         };
-        let db = sled::open("data/wallets")?;
+        let db = sled::open(params().data_path("wallets"))?;
         for item in db.into_iter(){
             let i =item?;
             let address = String::from_utf8(i.0.to_vec())?;
@@ -97,8 +100,31 @@ impl Wallets {
         self.wallets.get(address)
     }
 
+    pub fn get_wallet_by_pub_key(&self, pub_key : &[u8]) -> Option<&Wallet>{
+        self.wallets.values().find(|w| w.public_key == pub_key)
+    }
+
+    // transactions sent from the wallets are kept in their own tree,
+    // so that they can be found again to bump their fee
+    pub fn save_transaction(&self, tx : &Transaction) -> Result<(),Box<dyn std::error::Error>>{
+        let db = sled::open(params().data_path("wallets"))?;
+        let txs = db.open_tree("transactions")?;
+        txs.insert(tx.id.as_bytes(), bincode::serialize(tx)?)?;
+        db.flush()?;
+        Ok(())
+    }
+
+    pub fn get_transaction(&self, txid : &str) -> Result<Option<Transaction>,Box<dyn std::error::Error>>{
+        let db = sled::open(params().data_path("wallets"))?;
+        let txs = db.open_tree("transactions")?;
+        match txs.get(txid)? {
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None)
+        }
+    }
+
     pub fn save_all(&self) -> Result<(),Box<dyn std::error::Error>>{
-        let db = sled::open("data/wallets")?;
+        let db = sled::open(params().data_path("wallets"))?;
         for(address, wallet) in &self.wallets {
             let data = bincode::serialize(wallet)?;
             db.insert(address,data)?;